@@ -1,3 +1,4 @@
 fn main() {
+    #[cfg(feature = "tray")]
     tauri_build::build();
 }
@@ -0,0 +1,377 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// What the mute button did, recognized from its down and up edges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Gesture {
+    /// short press, not followed by another one within double press time
+    Click,
+    /// two short presses
+    DoubleClick,
+    /// still held after long press time, fired while held
+    LongPress,
+    /// held for hold time, fired while held and followed by `Release`
+    Hold,
+    Release,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GestureTimings {
+    /// `None` makes presses clicks however long they are held, when nothing listens to holds
+    pub hold: Option<Duration>,
+    pub long_press: Duration,
+    /// 0 makes `Click` fire right on release, without waiting for the second press
+    pub double_press: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Down {
+        since: Duration,
+        // second press of a possible double click
+        second: bool,
+        held: bool,
+        long: bool,
+    },
+    /// short press released, waiting whether the second one comes
+    Released {
+        at: Duration,
+    },
+}
+
+/// Turns button edges into gestures. Time is taken on the button's clock, timed gestures are
+/// fired by `tick` once `deadline` passes.
+#[derive(Debug)]
+pub struct GestureRecognizer {
+    timings: GestureTimings,
+    state: State,
+}
+
+impl GestureRecognizer {
+    pub fn new(timings: GestureTimings) -> Self {
+        Self {
+            timings,
+            state: State::Idle,
+        }
+    }
+
+    /// Takes effect from the next press
+    pub fn set_timings(&mut self, timings: GestureTimings) {
+        self.timings = timings;
+    }
+
+    pub fn down(&mut self, at: Duration) -> Vec<Gesture> {
+        let mut gestures = self.tick(at);
+        let second = match self.state {
+            State::Idle => false,
+            State::Released { .. } => true,
+            // up edge was lost, the press is still going on
+            State::Down { .. } => return gestures,
+        };
+        self.state = State::Down {
+            since: at,
+            second,
+            held: false,
+            long: false,
+        };
+        // one more tick, hold time may be 0
+        gestures.extend(self.tick(at));
+        gestures
+    }
+
+    pub fn up(&mut self, at: Duration) -> Vec<Gesture> {
+        // gestures which should have fired while it was held
+        let mut gestures = self.tick(at);
+        let State::Down { second, held, .. } = self.state else {
+            return gestures;
+        };
+        self.state = State::Idle;
+        if held {
+            gestures.push(Gesture::Release);
+        } else if second {
+            gestures.push(Gesture::DoubleClick);
+        } else if self.timings.double_press.is_zero() {
+            gestures.push(Gesture::Click);
+        } else {
+            self.state = State::Released { at };
+        }
+        gestures
+    }
+
+    /// Forgets the press going on, e.g. when the button is gone. A held press still ends with
+    /// `Release`, so push-to-talk doesn't stay on.
+    pub fn cancel(&mut self) -> Vec<Gesture> {
+        let held = matches!(self.state, State::Down { held: true, .. });
+        self.state = State::Idle;
+        if held {
+            vec![Gesture::Release]
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// When `tick` has something to fire
+    pub fn deadline(&self) -> Option<Duration> {
+        match self.state {
+            State::Idle => None,
+            State::Down {
+                since, held, long, ..
+            } => match (held, long) {
+                (false, _) => self.timings.hold.map(|hold| since + hold),
+                (true, false) => Some(since + self.timings.long_press),
+                (true, true) => None,
+            },
+            State::Released { at } => Some(at + self.timings.double_press),
+        }
+    }
+
+    pub fn tick(&mut self, now: Duration) -> Vec<Gesture> {
+        let mut gestures = Vec::new();
+        match &mut self.state {
+            State::Idle => {}
+            State::Down {
+                since,
+                second,
+                held,
+                long,
+            } => {
+                let pressed_for = now.saturating_sub(*since);
+                if !*held && self.timings.hold.is_some_and(|hold| pressed_for >= hold) {
+                    // the short press before this one was a click on its own
+                    if *second {
+                        gestures.push(Gesture::Click);
+                        *second = false;
+                    }
+                    *held = true;
+                    gestures.push(Gesture::Hold);
+                }
+                if *held && !*long && pressed_for >= self.timings.long_press {
+                    *long = true;
+                    gestures.push(Gesture::LongPress);
+                }
+            }
+            State::Released { at } => {
+                if now.saturating_sub(*at) >= self.timings.double_press {
+                    self.state = State::Idle;
+                    gestures.push(Gesture::Click);
+                }
+            }
+        }
+        gestures
+    }
+}
+
+/// Recognizer of one button, with button timestamps mapped to local time. The clocks are
+/// aligned on every edge, so they can't drift apart.
+#[derive(Debug)]
+pub struct ButtonGestures {
+    recognizer: GestureRecognizer,
+    // button timestamp of the last edge and when it was received
+    clock: Option<(Duration, Instant)>,
+}
+
+impl ButtonGestures {
+    pub fn new(timings: GestureTimings) -> Self {
+        Self {
+            recognizer: GestureRecognizer::new(timings),
+            clock: None,
+        }
+    }
+
+    pub fn edge(
+        &mut self,
+        pressed: bool,
+        timestamp: Duration,
+        timings: GestureTimings,
+    ) -> Vec<Gesture> {
+        self.clock = Some((timestamp, Instant::now()));
+        if pressed {
+            self.recognizer.set_timings(timings);
+            self.recognizer.down(timestamp)
+        } else {
+            self.recognizer.up(timestamp)
+        }
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        let (timestamp, received) = self.clock?;
+        let deadline = self.recognizer.deadline()?;
+        Some(received + deadline.saturating_sub(timestamp))
+    }
+
+    pub fn cancel(&mut self) -> Vec<Gesture> {
+        self.recognizer.cancel()
+    }
+
+    pub fn tick(&mut self) -> Vec<Gesture> {
+        let Some((timestamp, received)) = self.clock else {
+            return Vec::new();
+        };
+        self.recognizer.tick(timestamp + received.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMINGS: GestureTimings = GestureTimings {
+        hold: Some(Duration::from_millis(300)),
+        long_press: Duration::from_millis(800),
+        double_press: Duration::from_millis(250),
+    };
+
+    #[derive(Debug, Clone, Copy)]
+    enum Input {
+        Down(u64),
+        Up(u64),
+        Tick(u64),
+    }
+    use Input::*;
+
+    // gestures fired by every input, ticks are also done at each deadline passed in between
+    fn recognize(timings: GestureTimings, inputs: &[Input]) -> Vec<(u64, Gesture)> {
+        let mut recognizer = GestureRecognizer::new(timings);
+        let mut gestures = Vec::new();
+        for input in inputs {
+            let (Down(at) | Up(at) | Tick(at)) = *input;
+            let at = Duration::from_millis(at);
+            while let Some(deadline) = recognizer.deadline().filter(|d| *d < at) {
+                let fired = recognizer.tick(deadline);
+                gestures.extend(fired.into_iter().map(|g| (deadline.as_millis() as u64, g)));
+            }
+            let fired = match input {
+                Down(_) => recognizer.down(at),
+                Up(_) => recognizer.up(at),
+                Tick(_) => recognizer.tick(at),
+            };
+            gestures.extend(fired.into_iter().map(|g| (at.as_millis() as u64, g)));
+        }
+        gestures
+    }
+
+    #[test]
+    fn short_press_is_click_after_double_press_time() {
+        assert_eq!(
+            recognize(TIMINGS, &[Down(0), Up(100), Tick(1000)]),
+            vec![(350, Gesture::Click)]
+        );
+    }
+
+    #[test]
+    fn click_fires_on_release_without_double_press() {
+        let timings = GestureTimings {
+            double_press: Duration::ZERO,
+            ..TIMINGS
+        };
+        assert_eq!(
+            recognize(timings, &[Down(0), Up(100)]),
+            vec![(100, Gesture::Click)]
+        );
+    }
+
+    #[test]
+    fn two_quick_presses_are_double_click() {
+        assert_eq!(
+            recognize(TIMINGS, &[Down(0), Up(100), Down(200), Up(300), Tick(1000)]),
+            vec![(300, Gesture::DoubleClick)]
+        );
+    }
+
+    #[test]
+    fn slow_second_press_is_another_click() {
+        assert_eq!(
+            recognize(TIMINGS, &[Down(0), Up(100), Down(500), Up(600), Tick(1000)]),
+            vec![(350, Gesture::Click), (850, Gesture::Click)]
+        );
+    }
+
+    #[test]
+    fn held_button_is_hold_then_long_press_then_release() {
+        assert_eq!(
+            recognize(TIMINGS, &[Down(0), Up(1000)]),
+            vec![
+                (300, Gesture::Hold),
+                (800, Gesture::LongPress),
+                (1000, Gesture::Release)
+            ]
+        );
+    }
+
+    #[test]
+    fn long_press_is_click_without_hold() {
+        let timings = GestureTimings {
+            hold: None,
+            ..TIMINGS
+        };
+        assert_eq!(
+            recognize(timings, &[Down(0), Up(1000), Tick(2000)]),
+            vec![(1250, Gesture::Click)]
+        );
+        assert_eq!(
+            recognize(timings, &[Down(0), Up(100), Down(200), Up(1000)]),
+            vec![(1000, Gesture::DoubleClick)]
+        );
+    }
+
+    #[test]
+    fn hold_released_before_long_press() {
+        assert_eq!(
+            recognize(TIMINGS, &[Down(0), Up(500), Tick(2000)]),
+            vec![(300, Gesture::Hold), (500, Gesture::Release)]
+        );
+    }
+
+    #[test]
+    fn click_followed_by_hold() {
+        assert_eq!(
+            recognize(TIMINGS, &[Down(0), Up(100), Down(200), Up(600)]),
+            vec![
+                (500, Gesture::Click),
+                (500, Gesture::Hold),
+                (600, Gesture::Release)
+            ]
+        );
+    }
+
+    #[test]
+    fn missed_ticks_are_caught_up_on_release() {
+        let mut recognizer = GestureRecognizer::new(TIMINGS);
+        assert!(recognizer.down(Duration::ZERO).is_empty());
+        assert_eq!(
+            recognizer.up(Duration::from_millis(900)),
+            vec![Gesture::Hold, Gesture::LongPress, Gesture::Release]
+        );
+        assert_eq!(recognizer.deadline(), None);
+    }
+
+    #[test]
+    fn cancelled_hold_is_released() {
+        let mut recognizer = GestureRecognizer::new(TIMINGS);
+        recognizer.down(Duration::ZERO);
+        assert_eq!(
+            recognizer.tick(Duration::from_millis(300)),
+            vec![Gesture::Hold]
+        );
+        assert_eq!(recognizer.cancel(), vec![Gesture::Release]);
+        assert_eq!(recognizer.deadline(), None);
+
+        // short press and pending click are just dropped
+        recognizer.down(Duration::from_millis(1000));
+        assert!(recognizer.cancel().is_empty());
+        recognizer.down(Duration::from_millis(2000));
+        recognizer.up(Duration::from_millis(2100));
+        assert!(recognizer.cancel().is_empty());
+        assert_eq!(recognizer.tick(Duration::from_millis(5000)), vec![]);
+    }
+
+    #[test]
+    fn lost_edges_are_ignored() {
+        assert_eq!(
+            recognize(TIMINGS, &[Up(0), Down(100), Down(150), Up(200), Tick(1000)]),
+            vec![(450, Gesture::Click)]
+        );
+    }
+}
@@ -0,0 +1,229 @@
+pub mod gestures;
+
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+
+use self::gestures::Gesture;
+
+/// Input of the button an action can be bound to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    Clockwise,
+    CounterClockwise,
+    /// press count reported by the button, or click of firmware reporting button edges
+    Press,
+    DoublePress,
+    LongPress,
+    Hold,
+}
+
+impl Input {
+    /// Direction of the encoder turn
+    pub fn turn(impulses: i32) -> Self {
+        if impulses >= 0 {
+            Input::Clockwise
+        } else {
+            Input::CounterClockwise
+        }
+    }
+
+    /// `None` for `Release`, which ends `Hold` instead of being bound on its own
+    pub fn gesture(gesture: Gesture) -> Option<Self> {
+        match gesture {
+            Gesture::Click => Some(Input::Press),
+            Gesture::DoubleClick => Some(Input::DoublePress),
+            Gesture::LongPress => Some(Input::LongPress),
+            Gesture::Hold => Some(Input::Hold),
+            Gesture::Release => None,
+        }
+    }
+}
+
+/// What an input does. Continuous actions (`output_volume`, `mic_gain`, `brightness`) go up on
+/// clockwise turn and down on counter-clockwise one, the other ones fire once per report
+/// from the button.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    OutputVolume,
+    MicGain,
+    MicMuteToggle,
+    MediaPlayPause,
+    MediaNext,
+    MediaPrevious,
+    Brightness,
+    /// unmutes microphone while the button is held, `hold` only
+    PushToTalk,
+    /// run with `sh -c` (`cmd /C` on Windows)
+    Command(String),
+}
+
+impl Action {
+    /// Actions which need a turn direction, only the knob can be bound to them
+    pub fn is_knob(&self) -> bool {
+        matches!(
+            self,
+            Action::OutputVolume | Action::MicGain | Action::Brightness
+        )
+    }
+}
+
+/// Runs action which doesn't touch the sound controller. Programs are spawned in the background,
+/// their failure is only logged.
+pub fn run_external(
+    action: &Action,
+    address: BDAddr,
+    impulses: i32,
+    impulses_per_rotation: u32,
+) -> anyhow::Result<()> {
+    let mut command = match action {
+        Action::Command(command) => shell(command),
+        Action::MediaPlayPause => media("play-pause")?,
+        Action::MediaNext => media("next")?,
+        Action::MediaPrevious => media("previous")?,
+        Action::Brightness => {
+            // one full rotation sweeps the whole range, like volume does
+            let percent = impulses.unsigned_abs() * 100 / impulses_per_rotation;
+            let sign = if impulses >= 0 { '+' } else { '-' };
+            brightness(percent.max(1), sign)?
+        }
+        Action::OutputVolume | Action::MicGain | Action::MicMuteToggle | Action::PushToTalk => {
+            anyhow::bail!("{action:?} is done by sound controller")
+        }
+    };
+    command
+        .env("H_BUTTON_ADDRESS", address.to_string())
+        .env("H_BUTTON_IMPULSES", impulses.to_string());
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("failed to run {action:?}: {e}"))?;
+    let action = action.clone();
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if status.success() => trace!("{action:?} done"),
+            Ok(status) => warn!("{action:?} failed with {status}"),
+            Err(e) => warn!("{action:?} failed: {e:?}"),
+        }
+    });
+    Ok(())
+}
+
+fn shell(command: &str) -> tokio::process::Command {
+    #[cfg(windows)]
+    let (shell, flag) = ("cmd", "/C");
+    #[cfg(not(windows))]
+    let (shell, flag) = ("sh", "-c");
+    let mut shell = tokio::process::Command::new(shell);
+    shell.arg(flag).arg(command);
+    shell
+}
+
+// players are controlled over MPRIS, which `playerctl` talks to
+#[cfg(target_os = "linux")]
+fn media(command: &str) -> anyhow::Result<tokio::process::Command> {
+    let mut playerctl = tokio::process::Command::new("playerctl");
+    playerctl.arg(command);
+    Ok(playerctl)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn media(_command: &str) -> anyhow::Result<tokio::process::Command> {
+    anyhow::bail!("media actions are supported on Linux only, bind a command instead")
+}
+
+#[cfg(target_os = "linux")]
+fn brightness(percent: u32, sign: char) -> anyhow::Result<tokio::process::Command> {
+    let mut brightnessctl = tokio::process::Command::new("brightnessctl");
+    brightnessctl.arg("set").arg(format!("{percent}%{sign}"));
+    Ok(brightnessctl)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn brightness(_percent: u32, _sign: char) -> anyhow::Result<tokio::process::Command> {
+    anyhow::bail!("brightness action is supported on Linux only, bind a command instead")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, DeviceRole, DeviceRoleConfig};
+
+    const BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+
+    #[test]
+    fn bindings_are_read_from_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[bindings]]
+            clockwise = "media_next"
+            counter_clockwise = "media_previous"
+            press = { command = "notify-send pressed" }
+
+            [[bindings]]
+            address = "24:0A:C4:00:00:02"
+            clockwise = "brightness"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        let action = |input| config.action(&BUTTON.into(), input);
+        assert_eq!(action(Input::Clockwise), Some(Action::MediaNext));
+        assert_eq!(action(Input::CounterClockwise), Some(Action::MediaPrevious));
+        assert_eq!(
+            action(Input::Press),
+            Some(Action::Command("notify-send pressed".to_string()))
+        );
+        let other = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x02].into();
+        assert_eq!(
+            config.action(&other, Input::Clockwise),
+            Some(Action::Brightness)
+        );
+        // inputs it doesn't bind come from the binding without address
+        assert_eq!(
+            config.action(&other, Input::CounterClockwise),
+            Some(Action::MediaPrevious)
+        );
+    }
+
+    #[test]
+    fn unbound_inputs_follow_role() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.action(&BUTTON.into(), Input::Clockwise),
+            Some(Action::OutputVolume)
+        );
+        assert_eq!(
+            config.action(&BUTTON.into(), Input::Press),
+            Some(Action::MicMuteToggle)
+        );
+        // gestures do nothing unless bound
+        assert_eq!(config.action(&BUTTON.into(), Input::DoublePress), None);
+        assert_eq!(config.action(&BUTTON.into(), Input::Hold), None);
+
+        config.device.roles = vec![DeviceRoleConfig {
+            address: BUTTON.into(),
+            role: DeviceRole::MicGain,
+        }];
+        assert_eq!(
+            config.action(&BUTTON.into(), Input::CounterClockwise),
+            Some(Action::MicGain)
+        );
+    }
+
+    #[test]
+    fn invalid_bindings_are_rejected() {
+        let invalid = |bindings| {
+            toml::from_str::<Config>(bindings)
+                .unwrap()
+                .validate()
+                .is_err()
+        };
+
+        assert!(invalid("[[bindings]]\npress = \"output_volume\""));
+        assert!(invalid("[[bindings]]\nclockwise = { command = \" \" }"));
+        assert!(invalid("[[bindings]]\n[[bindings]]"));
+        assert!(invalid("[[bindings]]\nlong_press = \"push_to_talk\""));
+        assert!(invalid("[gestures]\nhold_ms = 1000\nlong_press_ms = 500"));
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::{BTreeMap, HashSet};
+
+use btleplug::api::BDAddr;
+
+/// Last battery level of every connected button
+#[derive(Debug, Default)]
+pub struct BatteryLevels {
+    levels: BTreeMap<BDAddr, u8>,
+    // already notified about low battery, until it's charged above the threshold again
+    low: HashSet<BDAddr>,
+}
+
+impl BatteryLevels {
+    /// Stores new level, returns true when it has just dropped below `threshold`
+    pub fn update(&mut self, address: BDAddr, level: u8, threshold: u8) -> bool {
+        self.levels.insert(address, level);
+        if level >= threshold {
+            self.low.remove(&address);
+            return false;
+        }
+        self.low.insert(address)
+    }
+
+    /// Forgets disconnected button
+    pub fn remove(&mut self, address: BDAddr) {
+        self.levels.remove(&address);
+        self.low.remove(&address);
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, address: BDAddr) -> Option<u8> {
+        self.levels.get(&address).copied()
+    }
+
+    /// e.g. `Battery: 80%`, address is added for each button when more are connected
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))] // shown in tray only
+    pub fn summary(&self) -> String {
+        match self.levels.len() {
+            0 => "Battery: unknown".to_string(),
+            1 => format!("Battery: {}%", self.levels.values().next().unwrap()),
+            _ => {
+                let levels: Vec<String> = self
+                    .levels
+                    .iter()
+                    .map(|(address, level)| format!("{address} {level}%"))
+                    .collect();
+                format!("Battery: {}", levels.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    events::{Event, EventBus},
+    sound::sound_controller::MicrophoneStatus,
+};
+
+/// Publishes `MicStateChanged` once per change, whether it was made by an action or noticed by
+/// the backend watcher, so leds aren't written twice
+#[derive(Clone)]
+pub struct MicrophoneReporter {
+    events: EventBus,
+    last: Arc<Mutex<Option<MicrophoneStatus>>>,
+}
+
+impl MicrophoneReporter {
+    pub fn new(events: EventBus) -> Self {
+        Self {
+            events,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Publishes `status` unless it's the one reported last
+    pub fn report(&self, status: MicrophoneStatus) {
+        let mut last = self.last.lock().unwrap();
+        if *last == Some(status) {
+            trace!("Microphone status {status:?} already reported");
+            return;
+        }
+        *last = Some(status);
+        self.events.publish(Event::MicStateChanged(status));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btleplug::api::BDAddr;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn same_status_is_reported_once() {
+        let events = EventBus::new();
+        let mut receiver = events.subscribe();
+        let reporter = MicrophoneReporter::new(events.clone());
+
+        reporter.report(MicrophoneStatus::Unmuted);
+        // e.g. watcher noticing the change an action has already reported
+        reporter.clone().report(MicrophoneStatus::Unmuted);
+        reporter.report(MicrophoneStatus::Muted);
+        let end = Event::DeviceDisconnected {
+            address: BDAddr::default(),
+        };
+        events.publish(end.clone());
+
+        for expected in [
+            Event::MicStateChanged(MicrophoneStatus::Unmuted),
+            Event::MicStateChanged(MicrophoneStatus::Muted),
+            end,
+        ] {
+            assert_eq!(receiver.recv().await, Some(expected));
+        }
+    }
+}
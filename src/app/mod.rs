@@ -1,176 +1,510 @@
-mod poller;
-
 use std::{
+    collections::{BTreeMap, HashMap},
     error::Error,
-    sync::{Arc, Mutex},
+    sync::{mpsc, Arc, Mutex},
+    time::Duration,
 };
 
+use btleplug::api::BDAddr;
 use serde::{Deserialize, Serialize};
-use tauri::async_runtime::block_on;
-use uuid::Uuid;
+use tokio::{
+    runtime::Handle,
+    sync::{mpsc::unbounded_channel, watch},
+    time::Instant,
+};
 
+#[cfg(feature = "tray")]
+use crate::tray::{tray_menu::tray_init, *};
 use crate::{
-    ble::{self, *},
-    sound::sound_controller::*,
-    tray::{tray_menu::tray_init, *},
+    actions::{self, gestures::ButtonGestures, Action, Input},
+    ble::{
+        self,
+        connection::{ConnectionState, StateFile},
+        device_info::DeviceInfo,
+        *,
+    },
+    config::{ConfigHandle, EncoderConfig},
+    events::{self, Event, EventBus, EventReceiver},
+    sound::{
+        backend::{Direction, OnMicrophoneChangeCallback},
+        sound_controller::*,
+        volume_curve::VolumeKnob,
+    },
 };
 
-const PERIPHERAL_NAME_MATCH_FILTER: &str = "H-Button";
-const NOTIFY_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xa3c87500_8ed3_4bdf_8a39_a01bebede295);
-const LED_STATUS_CHARACTERISTIC_UUID: Uuid =
-    Uuid::from_u128(0x3c9a3f00_8ed3_4bdf_8a39_a01bebede295);
+mod battery;
+mod microphone;
+#[cfg(test)]
+mod tests;
+
+use self::{battery::BatteryLevels, microphone::MicrophoneReporter};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub enum BluetoothMessage {
     HidStatus(HidStatus), // from server (esp32) to client (windows, mac os, linux)
     SetMicMuteIndicator(LedStatus), // from client to server
+    // from server, milliseconds since firmware start when the mute button went down or up
+    ButtonDown(u32),
+    ButtonUp(u32),
 }
 
 pub struct App {
-    sound_controller: Arc<Mutex<SoundController>>,
-    current_hid_status: Arc<Mutex<HidStatus>>, // TODO: could be also atomics
+    config: ConfigHandle,
+    events: EventBus,
+    // until it's moved into sound thread by `spawn_tasks`
+    sound_controller: Option<SoundController>,
+    battery_levels: Arc<Mutex<BatteryLevels>>,
+    // what connected buttons told about themselves, shown in tray "About device"
+    device_infos: Arc<Mutex<BTreeMap<BDAddr, DeviceInfo>>>,
 }
 
 impl App {
-    pub fn new() -> Self {
+    pub fn new(config: ConfigHandle) -> anyhow::Result<Self> {
+        let sound_controller = SoundController::new(config.clone())?;
+        Ok(Self::with_sound_controller(config, sound_controller))
+    }
+
+    pub fn with_sound_controller(config: ConfigHandle, sound_controller: SoundController) -> Self {
         Self {
-            sound_controller: Arc::new(Mutex::new(SoundController::new())),
-            current_hid_status: Arc::new(Mutex::new(HidStatus::default())),
+            config,
+            events: EventBus::new(),
+            sound_controller: Some(sound_controller),
+            battery_levels: Arc::new(Mutex::new(BatteryLevels::default())),
+            device_infos: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
-    fn test_tray() {
-        std::thread::spawn(move || loop {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            change_icon(TrayIcon::Muted);
-            change_title_test1();
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            change_icon(TrayIcon::Unmuted);
-            change_title_test2();
-            std::thread::sleep(std::time::Duration::from_secs(1));
-        });
+
+    async fn wait_for_shutdown_signal() {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut sigterm = signal(SignalKind::terminate()).expect("failed to register SIGTERM");
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = sigterm.recv() => {},
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = tokio::signal::ctrl_c().await;
+        }
     }
 
-    // move to sound controller ?
-    fn calculate_volume(initial_position: i32, encoder_position: i32, current_volume: i64) -> i64 {
-        let impulses_per_rotation = 240;
-        let volume_range: i64 = 65536;
-        let impulses = encoder_position - initial_position;
+    fn mic_mute_indicator(microphone_status: MicrophoneStatus) -> LedStatus {
+        match microphone_status {
+            MicrophoneStatus::Muted => LedStatus::On,
+            MicrophoneStatus::Unmuted => LedStatus::Off,
+        }
+    }
 
-        let volume_change =
-            (volume_range as f64 / impulses_per_rotation as f64 * impulses as f64) as i64;
+    fn microphone_status(sound_controller: &mut SoundController) -> MicrophoneStatus {
+        sound_controller
+            .get_microphone_status()
+            .unwrap_or_else(|e| {
+                error!("Failed to read microphone status: {e:?}");
+                MicrophoneStatus::Unmuted
+            })
+    }
 
-        (current_volume + volume_change).clamp(0, volume_range)
+    /// Does what `input` of the button is bound to, `turn` is impulses of encoder turn and when
+    /// it was reported, `None` for button input
+    fn perform(
+        sound_controller: &mut SoundController,
+        config: &ConfigHandle,
+        microphone: &MicrophoneReporter,
+        knobs: &mut HashMap<BDAddr, VolumeKnob>,
+        address: BDAddr,
+        input: Input,
+        turn: Option<(i32, Instant)>,
+    ) {
+        let config = config.get();
+        let Some(action) = config.action(&address, input) else {
+            trace!("{input:?} of {address} is not bound");
+            return;
+        };
+        debug!("{input:?} of {address} does {action:?}");
+        let impulses = turn.map_or(0, |(impulses, _)| impulses);
+        let mut turn_volume = |direction| match turn {
+            Some((impulses, at)) => Self::turn_volume(
+                sound_controller,
+                &config.encoder,
+                knobs.entry(address).or_default(),
+                direction,
+                impulses,
+                at,
+            ),
+            // volume bound to a button gesture has nothing to turn
+            None => Ok(()),
+        };
+        let result = match &action {
+            Action::OutputVolume => turn_volume(Direction::Playback),
+            Action::MicGain => turn_volume(Direction::Capture),
+            Action::MicMuteToggle => sound_controller
+                .toggle_microphone_mute()
+                .and_then(|()| Self::report_microphone_status(sound_controller, microphone)),
+            // muted again on release, see `release`
+            Action::PushToTalk => sound_controller
+                .unmute_mic()
+                .and_then(|()| Self::report_microphone_status(sound_controller, microphone)),
+            external => actions::run_external(
+                external,
+                address,
+                impulses,
+                config.encoder.impulses_per_rotation,
+            ),
+        };
+        if let Err(e) = result {
+            error!("Failed to do {action:?}: {e:?}");
+        }
     }
 
-    async fn do_something() {}
-
-    pub async fn run(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
-        // callback that takes raw bytes and always returns raw bytes
-        // raw bytes are sent to relevant bluetooth peripheral
-        let sound_controller = self.sound_controller.clone();
-        let current_hs = self.current_hid_status.clone();
-        let on_connect_cb = Arc::new(Mutex::new(move |msg: &[u8]| -> Vec<u8> {
-            println!("Initial value: {:?}", core::str::from_utf8(msg));
-            let pb = serde_json::from_slice(msg);
-            let initial_hid_status = match pb {
-                Ok(p) => match p {
-                    BluetoothMessage::HidStatus(hs) => Ok(hs),
-                    _ => {
-                        panic!("Unexpected message type")
-                    }
-                },
-                Err(e) => {
-                    println!("Error: {:?}", e);
-                    Err(e)
+    /// Knob works on `encoder.volume_range` steps, which are mapped onto the element's own range
+    fn turn_volume(
+        sound_controller: &mut SoundController,
+        encoder: &EncoderConfig,
+        knob: &mut VolumeKnob,
+        direction: Direction,
+        impulses: i32,
+        at: Instant,
+    ) -> anyhow::Result<()> {
+        let range = encoder.volume_range as f64;
+        let read = |sound_controller: &mut SoundController| -> anyhow::Result<i64> {
+            Ok((sound_controller.volume_percent(direction)? / 100.0 * range).round() as i64)
+        };
+        let current = read(sound_controller)?;
+        let volume = knob.turn(encoder, impulses, at, current);
+        sound_controller.set_volume_percent(direction, volume as f64 / range * 100.0)?;
+        // element steps may be coarser than knob ones, small turns add up until the next step
+        knob.settled(read(sound_controller)?);
+        Ok(())
+    }
+
+    fn report_microphone_status(
+        sound_controller: &mut SoundController,
+        microphone: &MicrophoneReporter,
+    ) -> anyhow::Result<()> {
+        microphone.report(sound_controller.get_microphone_status()?);
+        Ok(())
+    }
+
+    /// Ends `Hold` gesture, push to talk mutes the microphone again
+    fn release(
+        sound_controller: &mut SoundController,
+        config: &ConfigHandle,
+        microphone: &MicrophoneReporter,
+        address: BDAddr,
+    ) {
+        if config.get().action(&address, Input::Hold) != Some(Action::PushToTalk) {
+            return;
+        }
+        debug!("Push to talk of {address} released");
+        let result = sound_controller
+            .mute_mic()
+            .and_then(|()| Self::report_microphone_status(sound_controller, microphone));
+        if let Err(e) = result {
+            error!("Failed to mute microphone after push to talk: {e:?}");
+        }
+    }
+
+    /// Hands events over to the sound thread, ends it by dropping `sound` when the bus is gone
+    async fn sound_task(sound: mpsc::Sender<Event>, mut receiver: EventReceiver) {
+        while let Some(event) = receiver.recv().await {
+            if sound.send(event).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Owns the sound controller: performs actions bound to button input and keeps leds showing
+    /// microphone status. ALSA and pulse calls block, so it runs on its own thread instead of
+    /// holding up the runtime, which handles bluetooth notifications too.
+    fn sound_thread(
+        mut sound_controller: SoundController,
+        config: ConfigHandle,
+        events: EventBus,
+        microphone: MicrophoneReporter,
+        receiver: mpsc::Receiver<Event>,
+    ) {
+        let mut knobs = HashMap::new();
+        for event in receiver {
+            match event {
+                Event::DeviceConnected { address, .. } => {
+                    let microphone_status = Self::microphone_status(&mut sound_controller);
+                    debug!("Microphone of newly connected {address}: {microphone_status:?}");
+                    events.publish(Event::LedCommand {
+                        address: Some(address),
+                        led_status: Self::mic_mute_indicator(microphone_status),
+                    });
                 }
+                Event::DeviceDisconnected { address } => {
+                    knobs.remove(&address);
+                }
+                Event::EncoderDelta {
+                    address,
+                    impulses,
+                    at,
+                } => Self::perform(
+                    &mut sound_controller,
+                    &config,
+                    &microphone,
+                    &mut knobs,
+                    address,
+                    Input::turn(impulses),
+                    Some((impulses, at)),
+                ),
+                Event::MutePressed { address } => Self::perform(
+                    &mut sound_controller,
+                    &config,
+                    &microphone,
+                    &mut knobs,
+                    address,
+                    Input::Press,
+                    None,
+                ),
+                Event::Gesture { address, gesture } => match Input::gesture(gesture) {
+                    Some(input) => Self::perform(
+                        &mut sound_controller,
+                        &config,
+                        &microphone,
+                        &mut knobs,
+                        address,
+                        input,
+                        None,
+                    ),
+                    None => Self::release(&mut sound_controller, &config, &microphone, address),
+                },
+                Event::MicStateChanged(microphone_status) => events.publish(Event::LedCommand {
+                    address: None,
+                    led_status: Self::mic_mute_indicator(microphone_status),
+                }),
+                _ => {}
             }
-            .unwrap();
-            let microphone_status = sound_controller.lock().unwrap().get_microphone_status();
-            println!(
-                "Initial hid status: {:?}, microphone: {:?}",
-                initial_hid_status, microphone_status
-            );
-
-            *current_hs.lock().unwrap() = initial_hid_status;
-
-            let msg = match microphone_status {
-                MicrophoneStatus::Muted => BluetoothMessage::SetMicMuteIndicator(LedStatus::On),
-                MicrophoneStatus::Unmuted => BluetoothMessage::SetMicMuteIndicator(LedStatus::Off),
+        }
+    }
+
+    /// Recognizes gestures from button edges, timed ones are fired while nothing is received
+    async fn gesture_task(config: ConfigHandle, events: EventBus, mut receiver: EventReceiver) {
+        let mut buttons: HashMap<BDAddr, ButtonGestures> = HashMap::new();
+        loop {
+            let deadline = buttons.values().filter_map(ButtonGestures::deadline).min();
+            let event = tokio::select! {
+                event = receiver.recv() => match event {
+                    Some(event) => Some(event),
+                    None => return,
+                },
+                _ = async {
+                    match deadline {
+                        Some(deadline) => tokio::time::sleep_until(deadline).await,
+                        None => std::future::pending().await,
+                    }
+                } => None,
             };
-            serde_json::to_string(&msg).unwrap().as_bytes().to_vec()
-        }));
-
-        // callback that takes raw bytes and maybe returns raw bytes
-        // raw bytes (if returned) are sent to relevant bluetooth peripheral
-        let sound_controller = self.sound_controller.clone();
-        let current_hs = self.current_hid_status.clone();
-        let on_notification_cb = Arc::new(Mutex::new(move |msg: &[u8]| -> Option<Vec<u8>> {
-            println!("Notification: {:?}", core::str::from_utf8(msg));
-            let msg = serde_json::from_slice(msg).unwrap();
-            let mut sound_controller = sound_controller.lock().unwrap();
-            let mut current_hs = current_hs.lock().unwrap();
-            match msg {
-                BluetoothMessage::HidStatus(hs) => {
-                    if hs.encoder_position != current_hs.encoder_position {
-                        let volume = Self::calculate_volume(
-                            current_hs.encoder_position,
-                            hs.encoder_position,
-                            sound_controller.get_current_volume(),
-                        );
-                        sound_controller.set_volume(volume);
-                        current_hs.encoder_position = hs.encoder_position;
+            let mut gestures = Vec::new();
+            match event {
+                Some(Event::ButtonEdge {
+                    address,
+                    pressed,
+                    timestamp,
+                }) => {
+                    let config = config.get();
+                    let mut timings = config.gestures.timings();
+                    // clicks don't wait for a second press nobody listens to
+                    if config.action(&address, Input::DoublePress).is_none() {
+                        timings.double_press = Duration::ZERO;
+                    }
+                    // nor are long presses of mute button made into holds nobody listens to
+                    let unbound = |input| config.action(&address, input).is_none();
+                    if unbound(Input::Hold) && unbound(Input::LongPress) {
+                        timings.hold = None;
+                    }
+                    let button = buttons
+                        .entry(address)
+                        .or_insert_with(|| ButtonGestures::new(timings));
+                    let fired = button.edge(pressed, timestamp, timings);
+                    gestures.extend(fired.into_iter().map(|gesture| (address, gesture)));
+                }
+                Some(Event::DeviceDisconnected { address }) => {
+                    // button held down when it went away must not leave push-to-talk on
+                    if let Some(mut button) = buttons.remove(&address) {
+                        let fired = button.cancel();
+                        gestures.extend(fired.into_iter().map(|gesture| (address, gesture)));
+                    }
+                }
+                Some(_) => {}
+                None => {
+                    for (address, button) in &mut buttons {
+                        let fired = button.tick();
+                        gestures.extend(fired.into_iter().map(|gesture| (*address, gesture)));
+                    }
+                }
+            }
+            for (address, gesture) in gestures {
+                events.publish(Event::Gesture { address, gesture });
+            }
+        }
+    }
+
+    /// Keeps what tray and `status` command show about connected buttons up to date
+    async fn state_task(
+        battery_levels: Arc<Mutex<BatteryLevels>>,
+        device_infos: Arc<Mutex<BTreeMap<BDAddr, DeviceInfo>>>,
+        config: ConfigHandle,
+        mut receiver: EventReceiver,
+    ) {
+        while let Some(event) = receiver.recv().await {
+            match event {
+                Event::DeviceConnected {
+                    address,
+                    device_info,
+                } => {
+                    // refused firmware doesn't get here, so it's the warning policy
+                    #[cfg(feature = "tray")]
+                    if device_info.protocol_version < config.get().device.min_protocol_version {
+                        set_device_warning(Some(&format!(
+                            "Outdated firmware on {address}, please update it"
+                        )));
                     }
-                    if hs.mic_mute_button_press_count != current_hs.mic_mute_button_press_count {
-                        sound_controller.toggle_microphone_mute();
-                        let msg = match sound_controller.get_microphone_status() {
-                            MicrophoneStatus::Muted => {
-                                BluetoothMessage::SetMicMuteIndicator(LedStatus::On)
-                            }
-                            MicrophoneStatus::Unmuted => {
-                                BluetoothMessage::SetMicMuteIndicator(LedStatus::Off)
-                            }
-                        };
-                        current_hs.mic_mute_button_press_count = hs.mic_mute_button_press_count;
-                        let msg = serde_json::to_vec(&msg).unwrap();
-                        return Some(msg);
+                    device_infos.lock().unwrap().insert(address, device_info);
+                }
+                // levels and info of disconnected buttons are stale
+                Event::DeviceDisconnected { address } => {
+                    device_infos.lock().unwrap().remove(&address);
+                    let mut battery_levels = battery_levels.lock().unwrap();
+                    battery_levels.remove(address);
+                    #[cfg(feature = "tray")]
+                    set_battery_summary(&battery_levels.summary());
+                }
+                Event::BatteryLevel { address, level } => {
+                    info!("Battery level of {address}: {level}%");
+                    let threshold = config.get().device.low_battery_threshold;
+                    let mut battery_levels = battery_levels.lock().unwrap();
+                    if battery_levels.update(address, level, threshold) {
+                        warn!("Battery of {address} is low: {level}%");
+                        #[cfg(feature = "tray")]
+                        notify_low_battery(address, level);
                     }
-                    None
+                    #[cfg(feature = "tray")]
+                    set_battery_summary(&battery_levels.summary());
                 }
-                _ => panic!("Unexpected message type"),
+                #[cfg(feature = "tray")]
+                Event::DeviceWarning { warning, .. } => set_device_warning(warning.as_deref()),
+                #[cfg(feature = "tray")]
+                Event::MicStateChanged(microphone_status) => match microphone_status {
+                    MicrophoneStatus::Muted => change_icon(TrayIcon::Muted),
+                    MicrophoneStatus::Unmuted => change_icon(TrayIcon::Unmuted),
+                },
+                _ => {}
+            }
+        }
+    }
+
+    /// Spawns the tasks subsystems talk through, sound controller is moved into its own one
+    fn spawn_tasks(&mut self) {
+        tokio::spawn(events::log_events(self.events.subscribe()));
+        // subscribed here, so events published right after this returns reach the tasks
+        tokio::spawn(Self::state_task(
+            self.battery_levels.clone(),
+            self.device_infos.clone(),
+            self.config.clone(),
+            self.events.subscribe(),
+        ));
+        tokio::spawn(Self::gesture_task(
+            self.config.clone(),
+            self.events.clone(),
+            self.events.subscribe(),
+        ));
+        let Some(mut sound_controller) = self.sound_controller.take() else {
+            return;
+        };
+        // microphone may be (un)muted by other app too, led and tray icon follow it
+        let microphone = MicrophoneReporter::new(self.events.clone());
+        let reporter = microphone.clone();
+        let on_change_cb: OnMicrophoneChangeCallback =
+            Arc::new(move |microphone_status: MicrophoneStatus| reporter.report(microphone_status));
+        if let Err(e) = sound_controller.subscribe(on_change_cb) {
+            warn!("Microphone changes made by other apps won't be tracked: {e:?}");
+        }
+        let (sound, sound_events) = mpsc::channel();
+        let config = self.config.clone();
+        let events = self.events.clone();
+        // external actions spawn their programs on the runtime
+        let runtime = Handle::current();
+        let spawned = std::thread::Builder::new()
+            .name("sound".to_string())
+            .spawn(move || {
+                let _runtime = runtime.enter();
+                Self::sound_thread(sound_controller, config, events, microphone, sound_events)
+            });
+        if let Err(e) = spawned {
+            error!("Failed to start sound thread, button won't control sound: {e:?}");
+            return;
+        }
+        tokio::spawn(Self::sound_task(sound, self.events.subscribe()));
+    }
+
+    pub async fn run(&mut self, headless: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // kept alive until the driver exits
+        let _config_watcher = self.config.watch().unwrap_or_else(|e| {
+            warn!("Config file changes won't be reloaded: {e:?}");
+            None
+        });
+
+        self.spawn_tasks();
+
+        let (ble_commands, ble_commands_rx) = unbounded_channel();
+
+        // connection state is published to tray and to the file read by `status` command
+        let (connection_state, mut connection_state_rx) = watch::channel(ConnectionState::Scanning);
+        let state_file = StateFile::open();
+        state_file.write(&connection_state_rx.borrow());
+        tokio::spawn(async move {
+            while connection_state_rx.changed().await.is_ok() {
+                let state = connection_state_rx.borrow_and_update().clone();
+                info!("Connection state: {state}");
+                StateFile::open().write(&state);
+                #[cfg(feature = "tray")]
+                set_connection_state(&state);
             }
-        }));
-
-        let on_change_cb = Arc::new(
-            // todo: change led status
-            |microphone_status: MicrophoneStatus| match microphone_status {
-                MicrophoneStatus::Muted => change_icon(TrayIcon::Muted),
-                MicrophoneStatus::Unmuted => change_icon(TrayIcon::Unmuted),
-            },
-        );
-
-        // let poller = poller::Poller::new(
-        //     self.sound_controller.clone(),
-        //     std::time::Duration::from_millis(100),
-        //     on_change_cb,
-        // );
-        // poller.start();
+        });
 
         // bluetooth related code needs to be running in different OS thread
-        let handle = std::thread::spawn(move || {
-            block_on(ble::run(
-                PERIPHERAL_NAME_MATCH_FILTER,
-                on_connect_cb,
-                on_notification_cb,
-            ));
+        let runtime = Handle::current();
+        let config = self.config.clone();
+        let events = self.events.clone();
+        let bluetooth = tokio::task::spawn_blocking(move || {
+            runtime.block_on(ble::run(config, events, ble_commands_rx, connection_state));
         });
-        // Self::test_tray();
-        tray_init();
-
-        // block on the handle thread so app doesn't exit
-        handle.join().unwrap();
-        // init sound
-        // init tray
-        // init bluetooth(on_connect_cb, on_notif_cb)
+
+        // bluetooth turns led off and disconnects before the driver exits
+        let stopped = async move {
+            if let Err(e) = bluetooth.await {
+                error!("Bluetooth thread failed: {e:?}");
+            }
+            state_file.remove();
+        };
+
+        // signals stop bluetooth the same way in both modes, as does tray "quit"
+        let shutdown = ble_commands.clone();
+        tokio::spawn(async move {
+            Self::wait_for_shutdown_signal().await;
+            info!("Shutdown requested");
+            let _ = shutdown.send(BleCommand::Shutdown);
+        });
+
+        #[cfg(feature = "tray")]
+        if !headless {
+            // closing tray ends the driver, so it waits until bluetooth is done
+            tokio::spawn(async move {
+                stopped.await;
+                tray_quit();
+            });
+            tray_init(self.config.clone(), ble_commands, self.device_infos.clone());
+            return Ok(());
+        }
+        #[cfg(not(feature = "tray"))]
+        let _ = headless;
+
+        stopped.await;
         Ok(())
     }
 }
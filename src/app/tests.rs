@@ -0,0 +1,702 @@
+use std::time::Duration;
+
+use btleplug::api::{Peripheral as _, WriteType};
+
+use super::*;
+use crate::actions::gestures::Gesture;
+use crate::ble::device_info::OutdatedFirmware;
+use crate::ble::protocol::{Encoding, PROTOCOL_VERSION};
+use crate::ble::simulator::{SimulatedEvent, SimulatedPeripheral};
+use crate::ble::NotificationsManager;
+use crate::config::{
+    BindingConfig, Config, DeviceRole, DeviceRoleConfig, EncoderConfig, GestureConfig,
+    OldFirmwarePolicy,
+};
+use crate::sound::backend::{Channel, SoundBackend};
+use crate::sound::mock::{MockElement, MockSoundBackend};
+use crate::sound::volume_curve::VolumeCurve;
+
+const BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+const OTHER_BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x02];
+
+// raw volume after turning by `n` tenths of a rotation (24 impulses) from 0
+fn tenths(n: i64) -> i64 {
+    (n as f64 * 65536.0 / 10.0).round() as i64
+}
+
+fn app(roles: Vec<DeviceRoleConfig>) -> (App, MockSoundBackend) {
+    let mut config = Config::default();
+    config.device.roles = roles;
+    app_with_config(config)
+}
+
+/// App with its tasks running, sound goes to the returned backend. Knob turns map linearly onto
+/// volume, curves and acceleration are tested in `volume_curve`.
+fn app_with_config(mut config: Config) -> (App, MockSoundBackend) {
+    config.encoder = EncoderConfig {
+        curve: VolumeCurve::Linear,
+        acceleration: 1.0,
+        ..config.encoder
+    };
+    let sound = MockSoundBackend::new();
+    let mut app = App::with_sound_controller(
+        ConfigHandle::new(None, config),
+        SoundController::with_backend(Box::new(sound.clone())),
+    );
+    app.spawn_tasks();
+    (app, sound)
+}
+
+fn button(app: &App, address: [u8; 6]) -> SimulatedPeripheral {
+    SimulatedPeripheral::new(address.into(), app.config.get().device)
+}
+
+/// Connects and waits for the initial led write, which answers `Event::DeviceConnected`
+async fn connect(
+    app: &App,
+    button: &SimulatedPeripheral,
+) -> NotificationsManager<SimulatedPeripheral> {
+    let notifications_manager = try_connect(app, button).await.unwrap();
+    eventually(|| !button.led_writes().is_empty()).await;
+    notifications_manager
+}
+
+async fn try_connect(
+    app: &App,
+    button: &SimulatedPeripheral,
+) -> anyhow::Result<NotificationsManager<SimulatedPeripheral>> {
+    button.connect().await.unwrap();
+    let mut notifications_manager =
+        NotificationsManager::new(button.clone(), app.config.get().device, app.events.clone())
+            .await;
+    notifications_manager.start().await?;
+    Ok(notifications_manager)
+}
+
+// notifications are handled by a spawned task, so results show up a bit later
+async fn eventually(mut condition: impl FnMut() -> bool) {
+    for _ in 0..100 {
+        if condition() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met within 1s");
+}
+
+// everything published until the bus is quiet for a while
+async fn published(events: &mut EventReceiver) -> Vec<Event> {
+    let mut published = Vec::new();
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(50), events.recv()).await
+    {
+        published.push(event);
+    }
+    published
+}
+
+// raw volume of the loudest channel
+fn volume(sound: &MockSoundBackend, direction: Direction) -> i64 {
+    let channels = sound.clone().channel_volumes(direction).unwrap();
+    channels.iter().map(|(_, volume)| *volume).max().unwrap()
+}
+
+fn output_volume(sound: &MockSoundBackend) -> i64 {
+    volume(sound, Direction::Playback)
+}
+
+fn capture_volume(sound: &MockSoundBackend) -> i64 {
+    volume(sound, Direction::Capture)
+}
+
+fn microphone_status(sound: &MockSoundBackend) -> MicrophoneStatus {
+    sound.clone().get_microphone_status().unwrap()
+}
+
+#[tokio::test]
+async fn connect_sets_led_from_microphone_status() {
+    let (app, sound) = app(Vec::new());
+    sound.clone().mute_mic().unwrap();
+    let button = button(&app, BUTTON);
+
+    let _notifications = connect(&app, &button).await;
+
+    assert_eq!(button.led_states(), vec![LedStatus::On]);
+    assert_eq!(button.hid_status().led_status, LedStatus::On);
+}
+
+#[tokio::test]
+async fn encoder_turn_changes_output_volume() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+
+    button.play(&[SimulatedEvent::TurnEncoder(-48)]);
+    eventually(|| output_volume(&sound) == 0).await;
+    assert_eq!(capture_volume(&sound), 0);
+}
+
+#[tokio::test]
+async fn encoder_turn_follows_element_range_and_balance() {
+    let (app, sound) = app(Vec::new());
+    let mut element = MockElement::new((0, 87), &[Channel::Left, Channel::Right], None);
+    element.channels = vec![(Channel::Left, 40), (Channel::Right, 20)];
+    sound.set_element(Direction::Playback, element);
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    // a tenth of the rotation is a tenth of the element range, right side stays at half
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+    eventually(|| output_volume(&sound) == 49).await;
+    assert_eq!(
+        sound.element(Direction::Playback).channels,
+        vec![(Channel::Left, 49), (Channel::Right, 25)]
+    );
+}
+
+#[tokio::test]
+async fn mic_gain_role_changes_capture_volume() {
+    let (app, sound) = app(vec![DeviceRoleConfig {
+        address: BUTTON.into(),
+        role: DeviceRole::MicGain,
+    }]);
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+
+    eventually(|| capture_volume(&sound) == tenths(1)).await;
+    assert_eq!(output_volume(&sound), 0);
+}
+
+#[tokio::test]
+async fn mute_press_toggles_microphone_and_led() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.play(&[SimulatedEvent::PressMute]);
+    eventually(|| button.led_states().len() == 2).await;
+    assert_eq!(microphone_status(&sound), MicrophoneStatus::Muted);
+
+    button.play(&[SimulatedEvent::PressMute]);
+    eventually(|| button.led_states().len() == 3).await;
+    assert_eq!(microphone_status(&sound), MicrophoneStatus::Unmuted);
+
+    assert_eq!(
+        button.led_states(),
+        vec![LedStatus::Off, LedStatus::On, LedStatus::Off]
+    );
+}
+
+#[tokio::test]
+async fn reboot_resets_encoder_baseline() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+
+    // position starts from 0 again, which must not be taken as turning the knob back
+    button.play(&[SimulatedEvent::Reboot]);
+    assert!(!button.is_connected().await.unwrap());
+    let _notifications = connect(&app, &button).await;
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+
+    eventually(|| output_volume(&sound) == tenths(2)).await;
+}
+
+#[tokio::test]
+async fn buttons_keep_separate_baselines() {
+    let (app, sound) = app(vec![DeviceRoleConfig {
+        address: OTHER_BUTTON.into(),
+        role: DeviceRole::MicGain,
+    }]);
+    let volume_button = button(&app, BUTTON);
+    let gain_button = button(&app, OTHER_BUTTON);
+    let _volume_notifications = connect(&app, &volume_button).await;
+    let _gain_notifications = connect(&app, &gain_button).await;
+
+    volume_button.play(&[
+        SimulatedEvent::TurnEncoder(24),
+        SimulatedEvent::TurnEncoder(24),
+    ]);
+    gain_button.play(&[SimulatedEvent::TurnEncoder(24)]);
+
+    eventually(|| output_volume(&sound) == tenths(2)).await;
+    eventually(|| capture_volume(&sound) == tenths(1)).await;
+}
+
+#[tokio::test]
+async fn binary_encoding_is_negotiated_with_new_firmware() {
+    let (app, sound) = app(Vec::new());
+    let button = SimulatedPeripheral::with_protocol(
+        BUTTON.into(),
+        app.config.get().device,
+        PROTOCOL_VERSION,
+    );
+    let _notifications = connect(&app, &button).await;
+
+    assert_eq!(button.encoding(), Encoding::Binary(PROTOCOL_VERSION));
+    assert_eq!(button.led_writes(), vec![vec![0x12, 0x01]]);
+
+    button.play(&[SimulatedEvent::TurnEncoder(24), SimulatedEvent::PressMute]);
+    eventually(|| button.led_states().len() == 2).await;
+    assert_eq!(output_volume(&sound), tenths(1));
+    assert_eq!(button.led_writes()[1], vec![0x12, 0x00]);
+}
+
+#[tokio::test]
+async fn old_firmware_falls_back_to_json() {
+    let (app, _) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    assert_eq!(button.encoding(), Encoding::Json);
+    assert_eq!(
+        button.led_writes(),
+        vec![br#"{"SetMicMuteIndicator":"Off"}"#.to_vec()]
+    );
+}
+
+#[tokio::test]
+async fn bad_and_unknown_frames_are_ignored() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+    let mut events = app.events.subscribe();
+
+    button.notify(b"\x00garbage".to_vec());
+    button.notify(br#"{"HidStatus":{"encoder_position":"#.to_vec());
+    // sent by newer firmware, not counted as error
+    button.notify(br#"{"BatteryLevel":80}"#.to_vec());
+    button.notify(b"{".to_vec());
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+    let warnings: Vec<Option<String>> = published(&mut events)
+        .await
+        .into_iter()
+        .filter_map(|event| match event {
+            Event::DeviceWarning { warning, .. } => Some(warning),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        warnings,
+        vec![
+            Some("Device protocol error: 3 bad messages from 24:0A:C4:00:00:01".to_string()),
+            None
+        ]
+    );
+}
+
+#[tokio::test]
+async fn notifications_of_other_characteristics_are_ignored() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+    let mut events = app.events.subscribe();
+
+    let device_config = app.config.get().device;
+    for uuid in [
+        device_config.led_status_characteristic_uuid,
+        device_config.protocol_characteristic_uuid,
+        device_config.led_status_characteristic_uuid,
+    ] {
+        button.notify_characteristic(uuid, vec![0x01]);
+    }
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+    let warnings = published(&mut events)
+        .await
+        .into_iter()
+        .filter(|event| matches!(event, Event::DeviceWarning { .. }))
+        .count();
+    assert_eq!(warnings, 0);
+}
+
+fn battery_level(app: &App, address: [u8; 6]) -> Option<u8> {
+    app.battery_levels.lock().unwrap().get(address.into())
+}
+
+#[tokio::test]
+async fn battery_level_is_read_and_tracked() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    button.set_battery_level(80);
+    let _notifications = connect(&app, &button).await;
+    eventually(|| battery_level(&app, BUTTON) == Some(80)).await;
+
+    button.set_battery_level(75);
+    eventually(|| battery_level(&app, BUTTON) == Some(75)).await;
+
+    // battery notifications don't disturb the button itself
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+}
+
+#[tokio::test]
+async fn button_without_battery_service_connects() {
+    let (app, _) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    assert_eq!(battery_level(&app, BUTTON), None);
+    assert_eq!(button.led_states(), vec![LedStatus::Off]);
+}
+
+#[test]
+fn low_battery_is_reported_once_until_charged() {
+    let mut battery_levels = BatteryLevels::default();
+
+    assert!(!battery_levels.update(BUTTON.into(), 50, 20));
+    assert!(battery_levels.update(BUTTON.into(), 19, 20));
+    assert!(!battery_levels.update(BUTTON.into(), 10, 20));
+    assert!(!battery_levels.update(OTHER_BUTTON.into(), 90, 20));
+    assert_eq!(
+        battery_levels.summary(),
+        "Battery: 24:0A:C4:00:00:01 10%, 24:0A:C4:00:00:02 90%"
+    );
+
+    assert!(!battery_levels.update(BUTTON.into(), 100, 20));
+    assert!(battery_levels.update(BUTTON.into(), 15, 20));
+    // 0 disables the warning
+    assert!(!battery_levels.update(BUTTON.into(), 0, 0));
+
+    battery_levels.remove(BUTTON.into());
+    assert_eq!(battery_levels.summary(), "Battery: 90%");
+}
+
+#[tokio::test]
+async fn device_information_is_read_on_connect() {
+    let (app, _) = app(Vec::new());
+    let button = SimulatedPeripheral::with_protocol(
+        BUTTON.into(),
+        app.config.get().device,
+        PROTOCOL_VERSION,
+    );
+    let _notifications = connect(&app, &button).await;
+    eventually(|| {
+        app.device_infos
+            .lock()
+            .unwrap()
+            .contains_key(&BUTTON.into())
+    })
+    .await;
+
+    let device_info = app.device_infos.lock().unwrap()[&BUTTON.into()].clone();
+    assert_eq!(device_info.manufacturer.as_deref(), Some("H-Button"));
+    assert_eq!(device_info.model.as_deref(), Some("HB-SIM"));
+    assert_eq!(device_info.serial.as_deref(), Some("240AC4000001"));
+    assert_eq!(device_info.hardware_revision.as_deref(), Some("rev-sim"));
+    assert_eq!(device_info.firmware_revision.as_deref(), Some("2.1.0"));
+    assert_eq!(device_info.protocol_version, PROTOCOL_VERSION);
+}
+
+fn min_protocol_config(old_firmware: OldFirmwarePolicy) -> Config {
+    let mut config = Config::default();
+    config.device.min_protocol_version = 1;
+    config.device.old_firmware = old_firmware;
+    config
+}
+
+#[tokio::test]
+async fn old_firmware_is_refused() {
+    let (app, _) = app_with_config(min_protocol_config(OldFirmwarePolicy::Refuse));
+    let button = button(&app, BUTTON);
+
+    let error = try_connect(&app, &button).await.err().unwrap();
+
+    let outdated = error.downcast_ref::<OutdatedFirmware>().unwrap();
+    assert_eq!(outdated.protocol_version, 0);
+    assert!(button.led_writes().is_empty());
+    assert!(app.device_infos.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn old_firmware_is_accepted_with_warning() {
+    let (app, _) = app_with_config(min_protocol_config(OldFirmwarePolicy::Warn));
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    assert_eq!(button.encoding(), Encoding::Json);
+    eventually(|| {
+        app.device_infos
+            .lock()
+            .unwrap()
+            .contains_key(&BUTTON.into())
+    })
+    .await;
+    assert_eq!(
+        app.device_infos.lock().unwrap()[&BUTTON.into()].protocol_version,
+        0
+    );
+}
+
+#[tokio::test]
+async fn led_writes_are_acknowledged() {
+    let (app, _) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.play(&[SimulatedEvent::PressMute]);
+    eventually(|| button.led_states().len() == 2).await;
+
+    assert_eq!(
+        button.led_write_types(),
+        vec![WriteType::WithResponse, WriteType::WithResponse]
+    );
+}
+
+#[tokio::test]
+async fn failed_led_write_is_retried() {
+    let (app, _) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.fail_led_writes(2);
+    button.play(&[SimulatedEvent::PressMute]);
+
+    eventually(|| button.hid_status().led_status == LedStatus::On).await;
+    assert_eq!(button.led_write_types().len(), 4);
+}
+
+#[tokio::test]
+async fn lost_led_write_is_reconciled() {
+    let mut config = Config::default();
+    config.device.led_reconcile_ms = 100;
+    let (app, sound) = app_with_config(config);
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.lose_led_writes(1);
+    button.play(&[SimulatedEvent::PressMute]);
+    eventually(|| button.led_write_types().len() == 2).await;
+    assert_eq!(button.hid_status().led_status, LedStatus::Off);
+
+    // corrected after status is read back
+    eventually(|| button.hid_status().led_status == LedStatus::On).await;
+    assert_eq!(microphone_status(&sound), MicrophoneStatus::Muted);
+}
+
+#[tokio::test]
+async fn microphone_change_reaches_every_button() {
+    let (app, _) = app(Vec::new());
+    let first = button(&app, BUTTON);
+    let second = button(&app, OTHER_BUTTON);
+    let _first_notifications = connect(&app, &first).await;
+    let _second_notifications = connect(&app, &second).await;
+
+    // as published by sound backend, when other app mutes the microphone
+    app.events
+        .publish(Event::MicStateChanged(MicrophoneStatus::Muted));
+
+    eventually(|| first.hid_status().led_status == LedStatus::On).await;
+    eventually(|| second.hid_status().led_status == LedStatus::On).await;
+}
+
+#[tokio::test]
+async fn disconnected_button_is_forgotten() {
+    let (app, _) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    button.set_battery_level(80);
+    let notifications = connect(&app, &button).await;
+    eventually(|| battery_level(&app, BUTTON) == Some(80)).await;
+
+    notifications.stop().await;
+
+    eventually(|| battery_level(&app, BUTTON).is_none()).await;
+    assert!(app.device_infos.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn reconnects_dont_leak_notification_tasks() {
+    let (app, _) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    button.set_battery_level(80);
+    // sound, state and logging tasks
+    let subscribers = app.events.subscriber_count();
+
+    for _ in 0..5 {
+        let notifications = connect(&app, &button).await;
+        assert_eq!(app.events.subscriber_count(), subscribers + 1);
+        assert_eq!(button.notification_streams(), 1);
+
+        notifications.stop().await;
+        assert_eq!(app.events.subscriber_count(), subscribers);
+        assert_eq!(button.notification_streams(), 0);
+        assert!(!button.is_subscribed());
+        button.disconnect().await.unwrap();
+    }
+}
+
+fn app_with_bindings(bindings: Vec<BindingConfig>) -> (App, MockSoundBackend) {
+    app_with_config(Config {
+        bindings,
+        ..Default::default()
+    })
+}
+
+#[tokio::test]
+async fn bindings_take_precedence_over_roles() {
+    let (app, sound) = app_with_bindings(vec![
+        BindingConfig {
+            address: None,
+            clockwise: Some(Action::MicGain),
+            counter_clockwise: Some(Action::MicGain),
+            ..Default::default()
+        },
+        BindingConfig {
+            address: Some(OTHER_BUTTON.into()),
+            clockwise: Some(Action::OutputVolume),
+            ..Default::default()
+        },
+    ]);
+    let gain_button = button(&app, BUTTON);
+    let volume_button = button(&app, OTHER_BUTTON);
+    let _gain_notifications = connect(&app, &gain_button).await;
+    let _volume_notifications = connect(&app, &volume_button).await;
+
+    gain_button.play(&[
+        SimulatedEvent::TurnEncoder(24),
+        SimulatedEvent::TurnEncoder(24),
+    ]);
+    eventually(|| capture_volume(&sound) == tenths(2)).await;
+    gain_button.play(&[SimulatedEvent::TurnEncoder(-24)]);
+    eventually(|| capture_volume(&sound) == tenths(1)).await;
+
+    // counter-clockwise turn of this one falls back to binding without address
+    volume_button.play(&[SimulatedEvent::TurnEncoder(24)]);
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+    volume_button.play(&[SimulatedEvent::TurnEncoder(-24)]);
+    eventually(|| capture_volume(&sound) == 0).await;
+    assert_eq!(output_volume(&sound), tenths(1));
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn press_runs_bound_command() {
+    let output = std::env::temp_dir().join(format!("h-button-press-{}", std::process::id()));
+    let (app, sound) = app_with_bindings(vec![BindingConfig {
+        address: Some(BUTTON.into()),
+        press: Some(Action::Command(format!(
+            "echo $H_BUTTON_ADDRESS > {}",
+            output.display()
+        ))),
+        ..Default::default()
+    }]);
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    button.play(&[SimulatedEvent::PressMute]);
+
+    eventually(|| std::fs::read_to_string(&output).is_ok_and(|s| s == "24:0A:C4:00:00:01\n")).await;
+    std::fs::remove_file(&output).unwrap();
+    assert_eq!(microphone_status(&sound), MicrophoneStatus::Unmuted);
+    assert_eq!(button.led_states(), vec![LedStatus::Off]);
+
+    // knob still works afterwards
+    button.play(&[SimulatedEvent::TurnEncoder(24)]);
+    eventually(|| output_volume(&sound) == tenths(1)).await;
+}
+
+#[tokio::test]
+async fn edge_click_toggles_mute_once() {
+    let (app, sound) = app(Vec::new());
+    let mut events = app.events.subscribe();
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+    published(&mut events).await;
+
+    // firmware reporting edges counts the press in `HidStatus` too
+    button.play(&[
+        SimulatedEvent::ButtonDown(1000),
+        SimulatedEvent::ButtonUp(1100),
+    ]);
+
+    let published = published(&mut events).await;
+    assert!(!published.contains(&Event::MutePressed {
+        address: BUTTON.into()
+    }));
+    assert_eq!(
+        published
+            .iter()
+            .filter(|event| matches!(event, Event::Gesture { .. }))
+            .collect::<Vec<_>>(),
+        vec![&Event::Gesture {
+            address: BUTTON.into(),
+            gesture: Gesture::Click
+        }]
+    );
+    assert_eq!(microphone_status(&sound), MicrophoneStatus::Muted);
+}
+
+#[tokio::test]
+async fn slow_edge_click_toggles_mute_without_hold_binding() {
+    let (app, sound) = app(Vec::new());
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    // longer than default hold time, nothing is bound to hold though
+    button.play(&[
+        SimulatedEvent::ButtonDown(1000),
+        SimulatedEvent::ButtonUp(1600),
+    ]);
+    eventually(|| microphone_status(&sound) == MicrophoneStatus::Muted).await;
+}
+
+#[tokio::test]
+async fn push_to_talk_unmutes_while_held() {
+    let (app, sound) = app_with_config(Config {
+        gestures: GestureConfig {
+            hold_ms: 50,
+            ..Default::default()
+        },
+        bindings: vec![BindingConfig {
+            hold: Some(Action::PushToTalk),
+            ..Default::default()
+        }],
+        ..Default::default()
+    });
+    sound.clone().mute_mic().unwrap();
+    let button = button(&app, BUTTON);
+    let _notifications = connect(&app, &button).await;
+
+    // hold fires on timeout, while the button is still down
+    button.play(&[SimulatedEvent::ButtonDown(1000)]);
+    eventually(|| microphone_status(&sound) == MicrophoneStatus::Unmuted).await;
+    eventually(|| button.hid_status().led_status == LedStatus::Off).await;
+
+    button.play(&[SimulatedEvent::ButtonUp(3000)]);
+    eventually(|| microphone_status(&sound) == MicrophoneStatus::Muted).await;
+    eventually(|| button.hid_status().led_status == LedStatus::On).await;
+}
+
+#[tokio::test]
+async fn push_to_talk_mutes_when_held_button_disconnects() {
+    let (app, sound) = app_with_config(Config {
+        gestures: GestureConfig {
+            hold_ms: 50,
+            ..Default::default()
+        },
+        bindings: vec![BindingConfig {
+            hold: Some(Action::PushToTalk),
+            ..Default::default()
+        }],
+        ..Default::default()
+    });
+    sound.clone().mute_mic().unwrap();
+    let button = button(&app, BUTTON);
+    let notifications = connect(&app, &button).await;
+
+    button.play(&[SimulatedEvent::ButtonDown(1000)]);
+    eventually(|| microphone_status(&sound) == MicrophoneStatus::Unmuted).await;
+
+    // up edge never comes
+    notifications.stop().await;
+    eventually(|| microphone_status(&sound) == MicrophoneStatus::Muted).await;
+}
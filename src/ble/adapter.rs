@@ -0,0 +1,171 @@
+use std::fmt;
+
+use btleplug::api::{BDAddr, Central as _, Manager as _};
+use btleplug::platform::{Adapter, Manager};
+
+/// Adapter requested in config (or any adapter) is not present right now
+#[derive(Debug)]
+pub struct NoAdapter(pub Option<String>);
+
+impl fmt::Display for NoAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Some(selector) => write!(f, "bluetooth adapter {selector} not found"),
+            None => write!(f, "no bluetooth adapter found"),
+        }
+    }
+}
+
+impl std::error::Error for NoAdapter {}
+
+/// Adapter present in the system, as listed by `adapters` command
+#[derive(Debug)]
+pub struct AdapterDescription {
+    /// interface name, e.g. `hci0`
+    pub name: String,
+    pub address: Option<BDAddr>,
+    pub info: String,
+}
+
+/// Adapter with given interface name (`hci1`) or address, first one when `selector` is `None`
+pub(crate) async fn select_adapter(
+    manager: &Manager,
+    selector: Option<&str>,
+) -> anyhow::Result<Adapter> {
+    let adapters = manager.adapters().await?;
+    let Some(selector) = selector else {
+        return adapters
+            .into_iter()
+            .next()
+            .ok_or_else(|| NoAdapter(None).into());
+    };
+
+    // D-Bus is only asked when selecting by address
+    let bluez = match selector.parse::<BDAddr>() {
+        Ok(_) => bluez_adapters().await?,
+        Err(_) => Vec::new(),
+    };
+    if let Some(name) = selected_name(selector, &bluez) {
+        for adapter in adapters {
+            if adapter_name(&adapter).await? == name {
+                return Ok(adapter);
+            }
+        }
+    }
+    Err(NoAdapter(Some(selector.to_string())).into())
+}
+
+pub async fn list_adapters() -> anyhow::Result<Vec<AdapterDescription>> {
+    let manager = Manager::new().await?;
+    let mut descriptions = Vec::new();
+    for adapter in manager.adapters().await? {
+        let info = adapter.adapter_info().await?;
+        let name = adapter_name(&adapter).await?;
+        descriptions.push(AdapterDescription {
+            address: adapter_address_by_name(&name).await?,
+            name,
+            info,
+        });
+    }
+    Ok(descriptions)
+}
+
+// btleplug formats adapter info as `hci0 (usb:v1D6Bp0246d0540)`
+async fn adapter_name(adapter: &Adapter) -> anyhow::Result<String> {
+    let info = adapter.adapter_info().await?;
+    Ok(info
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string())
+}
+
+// btleplug doesn't expose adapter addresses, so they are looked up in BlueZ directly
+#[cfg(target_os = "linux")]
+async fn bluez_adapters() -> anyhow::Result<Vec<(String, BDAddr)>> {
+    use once_cell::sync::OnceCell;
+
+    // single D-Bus connection kept for the whole process, it's not closed when dropped
+    static SESSION: OnceCell<bluez_async::BluetoothSession> = OnceCell::new();
+    let session = match SESSION.get() {
+        Some(session) => session,
+        None => {
+            let (dbus_handle, session) = bluez_async::BluetoothSession::new().await?;
+            tokio::spawn(async move {
+                if let Err(e) = dbus_handle.await {
+                    error!("Lost D-Bus connection: {e:?}");
+                }
+            });
+            SESSION.get_or_init(|| session)
+        }
+    };
+
+    let mut adapters = Vec::new();
+    for info in session.get_adapters().await? {
+        let address = info.mac_address.to_string().parse()?;
+        adapters.push((info.id.to_string(), address));
+    }
+    Ok(adapters)
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn bluez_adapters() -> anyhow::Result<Vec<(String, BDAddr)>> {
+    Ok(Vec::new())
+}
+
+/// Interface name of adapter picked by `selector`, looked up in `bluez` adapters when it's address
+fn selected_name(selector: &str, bluez: &[(String, BDAddr)]) -> Option<String> {
+    match selector.parse::<BDAddr>() {
+        Ok(address) => bluez
+            .iter()
+            .find(|(_, a)| *a == address)
+            .map(|(name, _)| name.clone()),
+        Err(_) => Some(selector.to_string()),
+    }
+}
+
+async fn adapter_address_by_name(name: &str) -> anyhow::Result<Option<BDAddr>> {
+    let adapters = bluez_adapters().await?;
+    Ok(adapters
+        .into_iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, address)| address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bluez() -> Vec<(String, BDAddr)> {
+        vec![
+            (
+                "hci0".to_string(),
+                [0x00, 0x1a, 0x7d, 0xda, 0x71, 0x10].into(),
+            ),
+            (
+                "hci1".to_string(),
+                [0x5c, 0xf3, 0x70, 0x00, 0x00, 0x01].into(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn adapter_selected_by_name() {
+        assert_eq!(selected_name("hci1", &bluez()), Some("hci1".to_string()));
+        // names are matched against btleplug adapters, not the ones known to BlueZ
+        assert_eq!(selected_name("hci2", &[]), Some("hci2".to_string()));
+    }
+
+    #[test]
+    fn adapter_selected_by_address() {
+        assert_eq!(
+            selected_name("5C:F3:70:00:00:01", &bluez()),
+            Some("hci1".to_string())
+        );
+        assert_eq!(
+            selected_name("00:1a:7d:da:71:10", &bluez()),
+            Some("hci0".to_string())
+        );
+        assert_eq!(selected_name("5C:F3:70:00:00:02", &bluez()), None);
+    }
+}
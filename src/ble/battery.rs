@@ -0,0 +1,45 @@
+use anyhow::Context;
+use btleplug::api::{bleuuid::uuid_from_u16, CharPropFlags, Peripheral as PeripheralTrait};
+use uuid::Uuid;
+
+use super::find_characteristic;
+
+/// Standard Battery Service, buttons running on battery expose it
+pub const BATTERY_SERVICE_UUID: Uuid = uuid_from_u16(0x180F);
+/// Battery Level characteristic, single byte with percent of charge
+pub const BATTERY_LEVEL_UUID: Uuid = uuid_from_u16(0x2A19);
+
+/// Percent of charge from Battery Level value, `None` when it's malformed
+pub fn level(value: &[u8]) -> Option<u8> {
+    match value {
+        [level] if *level <= 100 => Some(*level),
+        _ => None,
+    }
+}
+
+/// Reads Battery Level and subscribes to its notifications when the button supports them.
+/// Returns `None` for buttons without Battery Service.
+pub async fn subscribe<P: PeripheralTrait>(peripheral: &P) -> anyhow::Result<Option<u8>> {
+    let Ok(characteristic) =
+        find_characteristic(peripheral, BATTERY_LEVEL_UUID, CharPropFlags::READ)
+    else {
+        return Ok(None);
+    };
+    if characteristic.service_uuid != BATTERY_SERVICE_UUID {
+        return Ok(None);
+    }
+    let value = peripheral
+        .read(&characteristic)
+        .await
+        .context("failed to read battery level")?;
+    // notifications are optional in Battery Service, the initial level is all we get then
+    if characteristic.properties.contains(CharPropFlags::NOTIFY) {
+        peripheral
+            .subscribe(&characteristic)
+            .await
+            .context("failed to subscribe to battery level")?;
+    }
+    level(&value)
+        .map(Some)
+        .ok_or_else(|| anyhow::anyhow!("invalid battery level {value:?}"))
+}
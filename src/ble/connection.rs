@@ -0,0 +1,234 @@
+use std::{fmt, fs, path::PathBuf, time::Duration};
+
+use btleplug::api::BDAddr;
+use rand::Rng;
+use tokio::time::Instant;
+
+const STATE_FILE_NAME: &str = "connection";
+
+/// What bluetooth thread is doing right now, shown in tray and by `status` command
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// adapter selected in config is not plugged in
+    NoAdapter,
+    Scanning,
+    Connecting(BDAddr),
+    Connected(Vec<BDAddr>),
+    /// firmware update in progress, notifications are stopped until it's done
+    Updating {
+        address: BDAddr,
+        percent: u8,
+    },
+    /// waiting before next attempt, after connection or adapter failure
+    Backoff {
+        attempt: u32,
+        retry_in: Duration,
+    },
+}
+
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionState::NoAdapter => write!(f, "Waiting for bluetooth adapter"),
+            ConnectionState::Scanning => write!(f, "Scanning"),
+            ConnectionState::Connecting(address) => write!(f, "Connecting to {address}"),
+            ConnectionState::Connected(addresses) => {
+                let addresses: Vec<String> = addresses.iter().map(|a| a.to_string()).collect();
+                write!(f, "Connected to {}", addresses.join(", "))
+            }
+            ConnectionState::Updating { address, percent } => {
+                write!(f, "Updating firmware of {address}: {percent}%")
+            }
+            ConnectionState::Backoff { attempt, retry_in } => write!(
+                f,
+                "Retrying in {}s (attempt {attempt})",
+                retry_in.as_secs().max(1)
+            ),
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so several drivers don't retry in lockstep
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    attempt: u32,
+    initial: Duration,
+    max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            attempt: 0,
+            initial,
+            max,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Delay before the next attempt, somewhere between half and full of the exponential one
+    pub fn next_delay(&mut self) -> Duration {
+        let exponential = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(self.attempt))
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        exponential.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+/// Pending reconnect of a single peripheral
+#[derive(Debug)]
+pub(crate) struct Reconnect {
+    pub at: Instant,
+    pub backoff: Backoff,
+}
+
+/// Connection state of running driver, published for `status` command run in other process
+pub struct StateFile {
+    path: Option<PathBuf>,
+}
+
+impl StateFile {
+    /// `$XDG_RUNTIME_DIR/h-button-driver/connection` on Linux
+    /// `$XDG_STATE_HOME` is used when runtime directory is not set, e.g. outside of a login
+    /// session, nothing is written when neither is known
+    pub fn open() -> Self {
+        Self::in_dirs(dirs::runtime_dir(), dirs::state_dir())
+    }
+
+    fn in_dirs(runtime_dir: Option<PathBuf>, state_dir: Option<PathBuf>) -> Self {
+        let path = runtime_dir
+            .or(state_dir)
+            .map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(STATE_FILE_NAME));
+        Self { path }
+    }
+
+    pub fn write(&self, state: &ConnectionState) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let content = format!("{}\n{state}\n", std::process::id());
+        if let Err(e) = fs::write(path, content) {
+            warn!("Failed to write {}: {e:?}", path.display());
+        }
+    }
+
+    /// State written by driver which is still running, if any
+    pub fn read(&self) -> Option<String> {
+        let content = fs::read_to_string(self.path.as_ref()?).ok()?;
+        let (pid, state) = content.split_once('\n')?;
+        // file is left behind when driver is killed
+        let pid: u32 = pid.parse().ok()?;
+        if cfg!(target_os = "linux") && !PathBuf::from(format!("/proc/{pid}")).exists() {
+            return None;
+        }
+        Some(state.trim().to_string())
+    }
+
+    pub fn remove(&self) {
+        if let Some(path) = &self.path {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_jittered(delay: Duration, exponential: Duration) {
+        assert!(
+            delay >= exponential / 2 && delay <= exponential,
+            "{delay:?} not within half and full of {exponential:?}"
+        );
+    }
+
+    #[test]
+    fn backoff_grows_exponentially_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(10));
+        for (attempt, exponential) in [1, 2, 4, 8, 10, 10, 10].into_iter().enumerate() {
+            assert_eq!(backoff.attempt(), attempt as u32);
+            assert_jittered(backoff.next_delay(), Duration::from_secs(exponential));
+        }
+        // doesn't overflow, however long the button stays away
+        for _ in 0..100 {
+            assert_jittered(backoff.next_delay(), Duration::from_secs(10));
+        }
+    }
+
+    #[test]
+    fn backoff_jitter_spreads_delays() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<Duration> = (0..100)
+            .map(|_| {
+                backoff.reset();
+                backoff.next_delay()
+            })
+            .collect();
+        for delay in &delays {
+            assert_jittered(*delay, Duration::from_secs(1));
+        }
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[test]
+    fn backoff_reset_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        for _ in 0..5 {
+            backoff.next_delay();
+        }
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        assert_jittered(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("h-button-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn state_file_is_in_runtime_dir() {
+        let state_file = StateFile::in_dirs(Some(temp_dir("runtime")), Some(temp_dir("state")));
+        assert!(state_file.path.unwrap().starts_with(temp_dir("runtime")));
+    }
+
+    #[test]
+    fn state_file_falls_back_to_state_dir() {
+        let dir = temp_dir("state-fallback");
+        // runtime directory is not set
+        let state_file = StateFile::in_dirs(None, Some(dir.clone()));
+        assert_eq!(state_file.read(), None);
+
+        let state = ConnectionState::Connected(vec![[0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01].into()]);
+        state_file.write(&state);
+        assert_eq!(state_file.read(), Some(state.to_string()));
+        state_file.remove();
+        assert_eq!(state_file.read(), None);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn state_file_without_directory_is_not_written() {
+        let state_file = StateFile::in_dirs(None, None);
+        state_file.write(&ConnectionState::Scanning);
+        assert_eq!(state_file.read(), None);
+        state_file.remove();
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::HashMap;
+
+use btleplug::api::BDAddr;
+
+use super::protocol::DecodeError;
+
+/// Bad frames in a row after which the button is reported as misbehaving
+const REPEATED_BAD_FRAMES: u32 = 3;
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Counts {
+    total: u64,
+    // since the last good frame
+    consecutive: u32,
+}
+
+/// Counts frames from buttons that couldn't be decoded
+#[derive(Debug, Default)]
+pub struct DecodeErrors {
+    counts: HashMap<BDAddr, Counts>,
+}
+
+impl DecodeErrors {
+    /// Records bad frame, returns warning to show when they keep coming
+    pub fn bad_frame(&mut self, address: BDAddr, error: &DecodeError) -> Option<String> {
+        let counts = self.counts.entry(address).or_default();
+        counts.total += 1;
+        counts.consecutive += 1;
+        warn!(
+            "Bad frame from {address} ({} so far): {error}",
+            counts.total
+        );
+        (counts.consecutive >= REPEATED_BAD_FRAMES).then(|| {
+            format!(
+                "Device protocol error: {} bad messages from {address}",
+                counts.consecutive
+            )
+        })
+    }
+
+    /// Records good frame, returns true when previously reported warning is over
+    pub fn good_frame(&mut self, address: BDAddr) -> bool {
+        let Some(counts) = self.counts.get_mut(&address) else {
+            return false;
+        };
+        let was_reported = counts.consecutive >= REPEATED_BAD_FRAMES;
+        counts.consecutive = 0;
+        was_reported
+    }
+
+    #[cfg(test)]
+    pub fn total(&self, address: BDAddr) -> u64 {
+        self.counts.get(&address).map_or(0, |counts| counts.total)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::protocol::Encoding;
+
+    const BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+    const OTHER_BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x02];
+
+    #[test]
+    fn repeated_bad_frames_are_reported_until_good_frame() {
+        let mut decode_errors = DecodeErrors::default();
+        let error = || Encoding::Json.decode(b"{").unwrap_err();
+
+        assert_eq!(decode_errors.bad_frame(BUTTON.into(), &error()), None);
+        assert_eq!(decode_errors.bad_frame(BUTTON.into(), &error()), None);
+        assert!(decode_errors.bad_frame(BUTTON.into(), &error()).is_some());
+        assert_eq!(decode_errors.bad_frame(OTHER_BUTTON.into(), &error()), None);
+
+        assert!(decode_errors.good_frame(BUTTON.into()));
+        assert!(!decode_errors.good_frame(BUTTON.into()));
+        assert_eq!(decode_errors.total(BUTTON.into()), 3);
+    }
+}
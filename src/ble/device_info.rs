@@ -0,0 +1,97 @@
+use std::fmt;
+
+use btleplug::api::{bleuuid::uuid_from_u16, BDAddr, CharPropFlags, Peripheral as PeripheralTrait};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::find_characteristic;
+
+/// Standard Device Information Service
+pub const DEVICE_INFORMATION_SERVICE_UUID: Uuid = uuid_from_u16(0x180A);
+pub const MANUFACTURER_NAME_UUID: Uuid = uuid_from_u16(0x2A29);
+pub const MODEL_NUMBER_UUID: Uuid = uuid_from_u16(0x2A24);
+pub const SERIAL_NUMBER_UUID: Uuid = uuid_from_u16(0x2A25);
+pub const HARDWARE_REVISION_UUID: Uuid = uuid_from_u16(0x2A27);
+pub const FIRMWARE_REVISION_UUID: Uuid = uuid_from_u16(0x2A26);
+
+/// What the button tells about itself, fields it doesn't expose are `None`
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub firmware_revision: Option<String>,
+    /// highest binary protocol version of the firmware, 0 when it speaks JSON only
+    pub protocol_version: u8,
+}
+
+impl DeviceInfo {
+    /// Reads Device Information Service, missing or unreadable characteristics are left `None`
+    pub async fn read<P: PeripheralTrait>(peripheral: &P, protocol_version: u8) -> Self {
+        Self {
+            manufacturer: read_string(peripheral, MANUFACTURER_NAME_UUID).await,
+            model: read_string(peripheral, MODEL_NUMBER_UUID).await,
+            serial: read_string(peripheral, SERIAL_NUMBER_UUID).await,
+            hardware_revision: read_string(peripheral, HARDWARE_REVISION_UUID).await,
+            firmware_revision: read_string(peripheral, FIRMWARE_REVISION_UUID).await,
+            protocol_version,
+        }
+    }
+}
+
+async fn read_string<P: PeripheralTrait>(peripheral: &P, uuid: Uuid) -> Option<String> {
+    let characteristic = find_characteristic(peripheral, uuid, CharPropFlags::READ).ok()?;
+    if characteristic.service_uuid != DEVICE_INFORMATION_SERVICE_UUID {
+        return None;
+    }
+    match peripheral.read(&characteristic).await {
+        // strings are UTF-8, some firmwares pad them with NULs
+        Ok(value) => Some(
+            String::from_utf8_lossy(&value)
+                .trim_end_matches('\0')
+                .trim()
+                .to_string(),
+        ),
+        Err(e) => {
+            warn!("Failed to read device information {uuid}: {e:?}");
+            None
+        }
+    }
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let or_unknown = |value: &Option<String>| value.clone().unwrap_or("unknown".to_string());
+        writeln!(f, "Manufacturer: {}", or_unknown(&self.manufacturer))?;
+        writeln!(f, "Model: {}", or_unknown(&self.model))?;
+        writeln!(f, "Serial: {}", or_unknown(&self.serial))?;
+        writeln!(f, "Hardware: {}", or_unknown(&self.hardware_revision))?;
+        writeln!(f, "Firmware: {}", or_unknown(&self.firmware_revision))?;
+        match self.protocol_version {
+            0 => write!(f, "Protocol: JSON only"),
+            version => write!(f, "Protocol: binary v{version}"),
+        }
+    }
+}
+
+/// Firmware older than `device.min_protocol_version`, connection is refused with it when
+/// `device.old_firmware` is `refuse`
+#[derive(Debug)]
+pub struct OutdatedFirmware {
+    pub address: BDAddr,
+    pub protocol_version: u8,
+    pub min_protocol_version: u8,
+}
+
+impl fmt::Display for OutdatedFirmware {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "firmware of {} speaks protocol {}, at least {} is required, please update it",
+            self.address, self.protocol_version, self.min_protocol_version
+        )
+    }
+}
+
+impl std::error::Error for OutdatedFirmware {}
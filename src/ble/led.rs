@@ -0,0 +1,126 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use btleplug::api::{CharPropFlags, Characteristic, Peripheral as PeripheralTrait, WriteType};
+use uuid::Uuid;
+
+use super::protocol::Encoding;
+use super::LedStatus;
+use crate::app::BluetoothMessage;
+
+const LED_WRITE_ATTEMPTS: u32 = 3;
+// multiplied by the attempt number
+const LED_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// LED status characteristic and the write type to use with it: acknowledged write
+/// when the firmware supports it, as a lost write leaves the mute indicator wrong
+pub(crate) fn led_characteristic<P: PeripheralTrait>(
+    peripheral: &P,
+    uuid: Uuid,
+) -> anyhow::Result<(Characteristic, WriteType)> {
+    let characteristic = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| {
+            c.uuid == uuid
+                && c.properties
+                    .intersects(CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE)
+        })
+        .ok_or_else(|| anyhow::anyhow!("writable led status characteristic {uuid} not found"))?;
+    let write_type = if characteristic.properties.contains(CharPropFlags::WRITE) {
+        WriteType::WithResponse
+    } else {
+        WriteType::WithoutResponse
+    };
+    Ok((characteristic, write_type))
+}
+
+/// Delivers `SetMicMuteIndicator` to one button: writes are retried, and the last requested
+/// state is compared with `HidStatus.led_status` by `reconcile`
+#[derive(Clone)]
+pub(crate) struct Led<T: PeripheralTrait> {
+    peripheral: T,
+    characteristic: Characteristic,
+    write_type: WriteType,
+    encoding: Encoding,
+    // how often `reconcile` should be called, from `device.led_reconcile_ms`
+    reconcile_interval: Duration,
+    // last state sent, what the button should show
+    desired: Arc<Mutex<Option<LedStatus>>>,
+}
+
+impl<T: PeripheralTrait> Led<T> {
+    pub fn new(
+        peripheral: T,
+        uuid: Uuid,
+        encoding: Encoding,
+        reconcile_interval: Duration,
+    ) -> anyhow::Result<Self> {
+        let (characteristic, write_type) = led_characteristic(&peripheral, uuid)?;
+        Ok(Self {
+            peripheral,
+            characteristic,
+            write_type,
+            encoding,
+            reconcile_interval,
+            desired: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    pub fn reconcile_interval(&self) -> Duration {
+        self.reconcile_interval
+    }
+
+    pub async fn send(&self, msg: &BluetoothMessage) -> anyhow::Result<()> {
+        if let BluetoothMessage::SetMicMuteIndicator(led_status) = msg {
+            // kept even when all attempts fail, so `reconcile` fixes it later
+            *self.desired.lock().unwrap() = Some(*led_status);
+        }
+        trace!("Sending bluetooth msg: {msg:?}");
+        let data = self.encoding.encode(msg);
+        let mut attempt = 1;
+        loop {
+            match self
+                .peripheral
+                .write(&self.characteristic, &data, self.write_type)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < LED_WRITE_ATTEMPTS => {
+                    warn!("Led write failed, retrying ({attempt}/{LED_WRITE_ATTEMPTS}): {e}");
+                    tokio::time::sleep(LED_RETRY_DELAY * attempt).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(anyhow::anyhow!(e).context("failed to write led status")),
+            }
+        }
+    }
+
+    /// Reads `HidStatus` from `status_characteristic` and sends the desired state again
+    /// when the button shows something else
+    pub async fn reconcile(&self, status_characteristic: &Characteristic) -> anyhow::Result<()> {
+        let Some(desired) = *self.desired.lock().unwrap() else {
+            return Ok(());
+        };
+        let data = self
+            .peripheral
+            .read(status_characteristic)
+            .await
+            .context("failed to read led status back")?;
+        let Ok(BluetoothMessage::HidStatus(hid_status)) = self.encoding.decode(&data) else {
+            trace!("Led status not checked, status read back is not HidStatus");
+            return Ok(());
+        };
+        if hid_status.led_status == desired {
+            return Ok(());
+        }
+        warn!(
+            "Button {} shows led {:?}, expected {desired:?}, correcting",
+            self.peripheral.address(),
+            hid_status.led_status
+        );
+        self.send(&BluetoothMessage::SetMicMuteIndicator(desired))
+            .await
+    }
+}
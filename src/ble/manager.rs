@@ -1,146 +1,660 @@
+use anyhow::Context;
 use btleplug::api::{
-    Central, CentralEvent, CharPropFlags, Manager as _, Peripheral as _, ScanFilter, WriteType,
+    BDAddr, Central, CentralEvent, Peripheral as _, PeripheralProperties, ScanFilter,
 };
-use btleplug::platform::{Adapter, Manager, Peripheral, PeripheralId};
+use btleplug::platform::{Adapter, Manager, PeripheralId};
 use futures::stream::StreamExt;
-use std::error::Error;
-use tokio::sync::oneshot::Receiver;
-use uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+use super::adapter::select_adapter;
+use super::connection::{Backoff, ConnectionState, Reconnect};
+use super::device_info::OutdatedFirmware;
 
 use super::notifications::NotificationsManager;
-use super::{OnConnectCallback, OnNotificationCallback};
-
-pub struct BtlteManager {
-    _manager: Manager,
-    adapter: Adapter,
-    notifications_manager: Option<NotificationsManager<Peripheral>>,
-    connected_peripheral: Option<PeripheralId>,
-    peripheral_name_filter: &'static str,
+use super::ota::{self, FirmwareImage, OtaProgress};
+use super::pairing::{PairedDevice, PairingStore};
+use super::{BleCommand, BleCommandReceiver, LedStatus};
+use crate::app::BluetoothMessage;
+use crate::config::ConfigHandle;
+use crate::events::EventBus;
+
+/// H-Button found during scan
+#[derive(Debug)]
+pub struct ScannedPeripheral {
+    pub local_name: String,
+    pub address: BDAddr,
+    pub rssi: Option<i16>,
+    pub bound: bool,
 }
 
+pub struct BtlteManager<A: Central + 'static = Adapter> {
+    // `None` for simulated adapters
+    _manager: Option<Manager>,
+    adapter: A,
+    // one per connected button, each publishes events with its own address
+    notifications_managers: HashMap<PeripheralId, NotificationsManager<A::Peripheral>>,
+    connected_peripherals: HashSet<PeripheralId>,
+    // peripherals which failed to (re)connect, retried with backoff
+    reconnects: HashMap<PeripheralId, Reconnect>,
+    // firmware too old for `device.old_firmware = "refuse"`, ignored until `forget`
+    refused: HashSet<PeripheralId>,
+    config: ConfigHandle,
+    pairing: PairingStore,
+    state: Arc<watch::Sender<ConnectionState>>,
+    // notifications of connected buttons are published here
+    events: EventBus,
+}
+
+// discovery is restarted this often while nothing is connected, it doesn't resume
+// by itself after adapter is powered off and on, and fails when BlueZ is gone
+const SCAN_WATCHDOG_INTERVAL: Duration = Duration::from_secs(15);
+// unplugged adapter doesn't end the events stream, so its presence is checked periodically
+const ADAPTER_CHECK_INTERVAL: Duration = Duration::from_secs(3);
+
 impl BtlteManager {
-    pub async fn new(peripheral_name_filter: &'static str) -> Self {
-        let manager = Manager::new().await.unwrap();
-        let adapter = Self::get_central(&manager).await;
-        let notifications_manager = None;
+    pub async fn new(config: ConfigHandle) -> anyhow::Result<Self> {
+        let manager = Manager::new()
+            .await
+            .context("failed to connect to bluetooth stack")?;
+        let selector = config.get().device.adapter;
+        let adapter = select_adapter(&manager, selector.as_deref()).await?;
+        info!("Using bluetooth adapter {}", adapter.adapter_info().await?);
+        let mut btlte_manager = Self::with_adapter(adapter, config, PairingStore::open());
+        btlte_manager._manager = Some(manager);
+        Ok(btlte_manager)
+    }
+}
+
+impl<A: Central + 'static> BtlteManager<A> {
+    pub(crate) fn with_adapter(adapter: A, config: ConfigHandle, pairing: PairingStore) -> Self {
         Self {
-            _manager: manager,
+            _manager: None,
             adapter,
-            notifications_manager,
-            connected_peripheral: None,
-            peripheral_name_filter,
+            notifications_managers: HashMap::new(),
+            connected_peripherals: HashSet::new(),
+            reconnects: HashMap::new(),
+            refused: HashSet::new(),
+            config,
+            pairing,
+            state: Arc::new(watch::channel(ConnectionState::Scanning).0),
+            events: EventBus::new(),
         }
     }
 
+    /// Returns `Ok` when shutdown was requested, errors when adapter or BlueZ is gone,
+    /// so the caller can start over with a new manager
     pub(crate) async fn run(
         &mut self,
-        on_connect_cb: OnConnectCallback,
-        on_notification_cb: OnNotificationCallback,
-    ) -> Result<(), Box<dyn Error>> {
+        event_bus: &EventBus,
+        commands: &mut BleCommandReceiver,
+        state: Arc<watch::Sender<ConnectionState>>,
+    ) -> anyhow::Result<()> {
+        self.state = state;
+        self.events = event_bus.clone();
         let mut events = self.adapter.events().await?;
-        self.adapter.start_scan(ScanFilter::default()).await?;
+        self.adapter
+            .start_scan(ScanFilter::default())
+            .await
+            .context("failed to start scan")?;
+        self.publish_state();
+        let mut scan_watchdog = tokio::time::interval(SCAN_WATCHDOG_INTERVAL);
+        let mut adapter_check = tokio::time::interval(ADAPTER_CHECK_INTERVAL);
 
-        while let Some(event) = events.next().await {
-            match event {
-                CentralEvent::DeviceDiscovered(id) => {
-                    if let Some(_valid_peripheral) = self.is_valid_peripheral(&id).await {
-                        println!("Valid DeviceDiscovered: {:?}", id);
-                        self.connect(&id).await;
+        let result = loop {
+            let next_reconnect = self.reconnects.values().map(|r| r.at).min();
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(event) => self.handle_event(event).await,
+                    None => break Err(anyhow::anyhow!("adapter events stream ended")),
+                },
+                Some(command) = commands.recv() => {
+                    if self.handle_command(command).await.is_break() {
+                        break Ok(());
+                    }
+                }
+                _ = Self::sleep_until(next_reconnect) => self.reconnect_due().await,
+                _ = scan_watchdog.tick() => {
+                    if let Err(e) = self.restart_scan_if_idle().await {
+                        break Err(e);
                     }
                 }
-                CentralEvent::DeviceConnected(id) => {
-                    if let Some(valid_peripheral) = self.is_valid_peripheral(&id).await {
-                        println!("DeviceConnected: {:?}", id);
-
-                        let notifications_manager = NotificationsManager::new(
-                            valid_peripheral,
-                            on_connect_cb.clone(),
-                            on_notification_cb.clone(),
-                        )
-                        .await;
-
-                        // send msg to notifications manager to start
-                        notifications_manager.start().await;
-                        self.notifications_manager = Some(notifications_manager);
+                _ = adapter_check.tick() => {
+                    if let Err(e) = self.adapter.adapter_info().await {
+                        break Err(anyhow::anyhow!(e).context("bluetooth adapter is gone"));
                     }
                 }
-                CentralEvent::DeviceDisconnected(id) => {
-                    if let Some(_valid_peripheral) = self.is_valid_peripheral(&id).await {
-                        println!("Device disconnected, stopping notifications manager, attempting reconnect {:?}", id);
-
-                        // stop notifications loop
-                        let notifications_manager = self.notifications_manager.as_mut();
-                        notifications_manager.unwrap().stop().await;
-                        self.notifications_manager = None;
+            }
+        };
+        if result.is_err() {
+            // sessions would be orphaned otherwise, as the caller drops this manager
+            self.disconnect_all().await;
+        }
+        result
+    }
+
+    async fn sleep_until(at: Option<Instant>) {
+        match at {
+            Some(at) => tokio::time::sleep_until(at).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    async fn restart_scan_if_idle(&mut self) -> anyhow::Result<()> {
+        if !self.notifications_managers.is_empty() {
+            return Ok(());
+        }
+        trace!("Restarting scan");
+        let _ = self.adapter.stop_scan().await;
+        self.adapter
+            .start_scan(ScanFilter::default())
+            .await
+            .context("failed to restart scan, adapter is off or BlueZ is gone")
+    }
+
+    async fn reconnect_due(&mut self) {
+        let now = Instant::now();
+        let due: Vec<PeripheralId> = self
+            .reconnects
+            .iter()
+            .filter(|(_, r)| r.at <= now)
+            .map(|(id, _)| id.clone())
+            .collect();
+        for id in due {
+            if self.is_valid_peripheral(&id).await.is_some() {
+                self.connect(&id).await;
+            } else {
+                // forgotten or gone, discovery picks it up again if it comes back
+                self.reconnects.remove(&id);
+            }
+        }
+        self.publish_state();
+    }
+
+    fn schedule_reconnect(&mut self, id: PeripheralId) {
+        let reconnect = self
+            .reconnects
+            .entry(id.clone())
+            .or_insert_with(|| Reconnect {
+                at: Instant::now(),
+                backoff: Backoff::default(),
+            });
+        let delay = reconnect.backoff.next_delay();
+        reconnect.at = Instant::now() + delay;
+        info!(
+            "Reconnecting {id:?} in {delay:?}, attempt {}",
+            reconnect.backoff.attempt()
+        );
+    }
+
+    /// Connected devices take precedence over pending reconnects, which take precedence
+    /// over plain scanning
+    fn publish_state(&self) {
+        let state = if !self.notifications_managers.is_empty() {
+            let mut addresses: Vec<BDAddr> = self
+                .notifications_managers
+                .values()
+                .map(|n| n.address())
+                .collect();
+            addresses.sort();
+            ConnectionState::Connected(addresses)
+        } else if let Some(reconnect) = self.reconnects.values().min_by_key(|r| r.at) {
+            ConnectionState::Backoff {
+                attempt: reconnect.backoff.attempt(),
+                retry_in: reconnect.at.saturating_duration_since(Instant::now()),
+            }
+        } else {
+            ConnectionState::Scanning
+        };
+        self.state.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+    }
+
+    async fn handle_event(&mut self, event: CentralEvent) {
+        match event {
+            CentralEvent::DeviceDiscovered(id) => {
+                // pending reconnect is left to its backoff timer
+                if self.reconnects.contains_key(&id) || self.connected_peripherals.contains(&id) {
+                    return;
+                }
+                if let Some(_valid_peripheral) = self.is_valid_peripheral(&id).await {
+                    debug!("Valid DeviceDiscovered: {:?}", id);
+                    self.connect(&id).await;
+                }
+            }
+            CentralEvent::DeviceConnected(id) => self.start_notifications(id).await,
+            CentralEvent::DeviceDisconnected(id) => {
+                // device may be already forgotten, so it's matched by id, not by filter
+                if self.connected_peripherals.remove(&id) {
+                    info!("Device disconnected, stopping notifications manager, attempting reconnect {id:?}");
+
+                    // stop notifications loop of this device only, other ones keep running
+                    if let Some(notifications_manager) = self.notifications_managers.remove(&id) {
+                        notifications_manager.stop().await;
+                    }
+                    if self.is_valid_peripheral(&id).await.is_some() {
                         self.connect(&id).await;
                     }
                 }
-                event => {
-                    trace!("Unhandled btleplug central event: {:?}", event)
+            }
+            event => {
+                trace!("Unhandled btleplug central event: {:?}", event)
+            }
+        }
+        self.publish_state();
+    }
+
+    /// Starts notifications of a connected button, whoever connected it: the driver, or BlueZ
+    /// reconnecting a trusted device by itself
+    async fn start_notifications(&mut self, id: PeripheralId) {
+        if self.notifications_managers.contains_key(&id) {
+            return;
+        }
+        if let Some(valid_peripheral) = self.is_valid_peripheral(&id).await {
+            info!("DeviceConnected: {:?}", id);
+            self.bind_if_unpaired(&valid_peripheral).await;
+
+            let mut notifications_manager = NotificationsManager::new(
+                valid_peripheral,
+                self.config.get().device,
+                self.events.clone(),
+            )
+            .await;
+
+            // send msg to notifications manager to start
+            match notifications_manager.start().await {
+                Ok(()) => {
+                    self.reconnects.remove(&id);
+                    // BlueZ may have connected it by itself, it's stopped on disconnect all the same
+                    self.connected_peripherals.insert(id.clone());
+                    self.notifications_managers
+                        .insert(id, notifications_manager);
+                }
+                Err(e) => {
+                    self.connected_peripherals.remove(&id);
+                    if let Err(e) = self.disconnect(&id).await {
+                        warn!("Failed to disconnect {id:?}: {e:?}");
+                    }
+                    if let Some(outdated) = e.downcast_ref::<OutdatedFirmware>() {
+                        error!("Refusing to connect: {outdated}");
+                        self.reconnects.remove(&id);
+                        self.refused.insert(id);
+                    } else {
+                        error!("Failed to start notifications of {id:?}: {e:?}");
+                        self.schedule_reconnect(id);
+                    }
+                }
+            }
+        } else if self.connected_peripherals.remove(&id) {
+            // connected while unbound, but other button got bound in the meantime
+            if let Err(e) = self.disconnect(&id).await {
+                warn!("Failed to disconnect {id:?}: {e:?}");
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, command: BleCommand) -> ControlFlow<()> {
+        match command {
+            BleCommand::Forget => {
+                if let Err(e) = self.forget().await {
+                    warn!("Failed to forget paired devices: {e:?}");
+                }
+                self.publish_state();
+                ControlFlow::Continue(())
+            }
+            BleCommand::Flash { image, result } => {
+                let flashed = self.flash(&image).await;
+                if let Err(e) = &flashed {
+                    error!("Firmware update failed: {e:?}");
+                }
+                let _ = result.send(flashed);
+                self.publish_state();
+                ControlFlow::Continue(())
+            }
+            BleCommand::Shutdown => {
+                self.shutdown().await;
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    /// Updates firmware of the only connected button. Events are not handled meanwhile,
+    /// the button is reconnected by the usual disconnect handling afterwards.
+    async fn flash(&mut self, image: &Path) -> anyhow::Result<Option<String>> {
+        let image = FirmwareImage::load(image)?;
+        let mut ids = self.notifications_managers.keys();
+        let (Some(id), None) = (ids.next(), ids.next()) else {
+            anyhow::bail!("firmware update needs exactly one connected button");
+        };
+        let id = id.clone();
+        if let Some(notifications_manager) = self.notifications_managers.remove(&id) {
+            notifications_manager.stop().await;
+        }
+        let peripheral = self.adapter.peripheral(&id).await?;
+        let address = peripheral.address();
+        info!(
+            "Updating firmware of {address} to {}",
+            image.version().unwrap_or("unknown version")
+        );
+
+        let state = self.state.clone();
+        let result = ota::flash(
+            &peripheral,
+            self.config.get().device.ota_characteristic_uuid,
+            &image,
+            |progress| {
+                if let OtaProgress::Uploading { sent, total } = progress {
+                    let percent = (sent * 100 / total) as u8;
+                    state.send_if_modified(|current| {
+                        let updating = ConnectionState::Updating { address, percent };
+                        let modified = *current != updating;
+                        *current = updating;
+                        modified
+                    });
                 }
+            },
+        )
+        .await;
+        // notifications are started again with new firmware, by the events this causes
+        if let Err(e) = self.disconnect(&id).await {
+            warn!("Failed to disconnect {address} after update: {e:?}");
+        }
+        result
+    }
+
+    async fn shutdown(&mut self) {
+        info!("Shutting down bluetooth manager, turning led off");
+        // written directly, led commands on the event bus could come after disconnecting
+        let led_off = BluetoothMessage::SetMicMuteIndicator(LedStatus::Off);
+        for notifications_manager in self.notifications_managers.values() {
+            if let Err(e) = notifications_manager.send(&led_off).await {
+                warn!(
+                    "Failed to turn led of {} off: {e:?}",
+                    notifications_manager.address()
+                );
+            }
+        }
+        if let Err(e) = self.adapter.stop_scan().await {
+            warn!("Failed to stop scan: {e:?}");
+        }
+        self.disconnect_all().await;
+    }
+
+    async fn disconnect_all(&mut self) {
+        self.reconnects.clear();
+        for (_, notifications_manager) in self.notifications_managers.drain() {
+            notifications_manager.stop().await;
+        }
+        for id in std::mem::take(&mut self.connected_peripherals) {
+            if let Err(e) = self.disconnect(&id).await {
+                warn!("Failed to disconnect {id:?}: {e:?}");
+            }
+        }
+    }
+
+    /// Unbinds all devices and connects to the first one matching name filter,
+    /// which is then bound in its place
+    async fn forget(&mut self) -> anyhow::Result<()> {
+        let removed = self.pairing.forget(None)?;
+        info!("Forgot {removed} paired device(s)");
+        self.refused.clear();
+
+        self.disconnect_all().await;
+        // already known peripherals are not discovered again
+        for peripheral in self.adapter.peripherals().await? {
+            if self.is_valid_peripheral(&peripheral.id()).await.is_some() {
+                self.connect(&peripheral.id()).await;
+                break;
             }
         }
         Ok(())
     }
 
-    async fn get_central(manager: &Manager) -> Adapter {
-        let adapters = manager.adapters().await.unwrap();
-        adapters.into_iter().next().unwrap()
+    /// Binds first connected button, when nothing is bound, allowlisted or given a role yet.
+    /// Buttons with roles are allowed anyway, binding one of them would lock the others out.
+    async fn bind_if_unpaired(&self, peripheral: &A::Peripheral) {
+        if !self.allowed_addresses().is_empty() {
+            return;
+        }
+        let Ok(Some(properties)) = peripheral.properties().await else {
+            return;
+        };
+        let device = PairedDevice {
+            address: properties.address,
+            name: properties.local_name.unwrap_or_default(),
+        };
+        match self.pairing.bind(device, true) {
+            Ok(()) => info!(
+                "Bound to {}, run `{} forget` to pair with another button",
+                properties.address,
+                env!("CARGO_PKG_NAME")
+            ),
+            Err(e) => warn!("Failed to bind {}: {e:?}", properties.address),
+        }
     }
 
-    async fn is_valid_peripheral(&self, peripheral: &PeripheralId) -> Option<Peripheral> {
-        let peripheral = self.adapter.peripheral(peripheral).await.unwrap();
+    /// Bound, allowlisted and role addresses, empty when any button matching name filter
+    /// is accepted
+    fn allowed_addresses(&self) -> Vec<BDAddr> {
+        let device = self.config.get().device;
+        let mut addresses = device.allowlist;
+        addresses.extend(device.roles.iter().map(|r| r.address));
+        match self.pairing.devices() {
+            Ok(devices) => addresses.extend(devices.into_iter().map(|d| d.address)),
+            Err(e) => warn!("Failed to read paired devices: {e:?}"),
+        }
+        addresses
+    }
 
-        let local_name = peripheral
-            .properties()
-            .await
-            .unwrap()
-            .unwrap()
-            .local_name
-            .unwrap_or(String::from("(peripheral name unknown)"));
+    async fn is_valid_peripheral(&self, peripheral: &PeripheralId) -> Option<A::Peripheral> {
+        if self.refused.contains(peripheral) {
+            return None;
+        }
+        let peripheral = self.adapter.peripheral(peripheral).await.ok()?;
+        let properties = peripheral.properties().await.ok()??;
 
-        if local_name.contains(self.peripheral_name_filter) {
+        let allowed = self.allowed_addresses();
+        let is_valid = if allowed.is_empty() {
+            self.matches_filter(&properties)
+        } else {
+            allowed.contains(&properties.address)
+        };
+        if is_valid {
             return Some(peripheral);
         }
         None
     }
 
-    async fn connect(&mut self, peripheral: &PeripheralId) {
-        let peripheral = self.adapter.peripheral(peripheral).await.unwrap();
-        let is_connected = peripheral.is_connected().await.unwrap();
+    fn matches_filter(&self, properties: &PeripheralProperties) -> bool {
+        let name_filter = self.config.get().device.name_filter;
+        properties
+            .local_name
+            .as_deref()
+            .is_some_and(|local_name| local_name.contains(&name_filter))
+    }
+
+    /// Scans for `duration` and returns peripherals matching name filter, strongest signal first.
+    /// Bound devices are listed too, but not only them.
+    pub async fn scan(&self, duration: Duration) -> anyhow::Result<Vec<ScannedPeripheral>> {
+        let allowed = self.allowed_addresses();
+        self.adapter.start_scan(ScanFilter::default()).await?;
+        tokio::time::sleep(duration).await;
+        self.adapter.stop_scan().await?;
+
+        let mut found = Vec::new();
+        for peripheral in self.adapter.peripherals().await? {
+            let Some(properties) = peripheral.properties().await? else {
+                continue;
+            };
+            if self.matches_filter(&properties) {
+                found.push(ScannedPeripheral {
+                    local_name: properties.local_name.unwrap_or_default(),
+                    address: properties.address,
+                    rssi: properties.rssi,
+                    bound: allowed.contains(&properties.address),
+                });
+            }
+        }
+        found.sort_by_key(|p| std::cmp::Reverse(p.rssi));
+        Ok(found)
+    }
+
+    pub fn pairing(&self) -> &PairingStore {
+        &self.pairing
+    }
+
+    /// Scans until first matching peripheral shows up, connects to it and discovers services.
+    /// Used by one-shot CLI commands, which don't need the whole event loop. Connection of a
+    /// running driver is reused, `release` keeps it.
+    pub async fn connect_first(&mut self, timeout: Duration) -> anyhow::Result<A::Peripheral> {
+        let mut events = self.adapter.events().await?;
+        self.adapter.start_scan(ScanFilter::default()).await?;
+
+        let peripheral = tokio::time::timeout(timeout, async {
+            // peripherals already known to the adapter are not discovered again
+            for peripheral in self.adapter.peripherals().await? {
+                if let Some(peripheral) = self.is_valid_peripheral(&peripheral.id()).await {
+                    return Ok(peripheral);
+                }
+            }
+            while let Some(event) = events.next().await {
+                if let CentralEvent::DeviceDiscovered(id) | CentralEvent::DeviceUpdated(id) = event
+                {
+                    if let Some(peripheral) = self.is_valid_peripheral(&id).await {
+                        return Ok(peripheral);
+                    }
+                }
+            }
+            anyhow::bail!("adapter events stream ended")
+        })
+        .await
+        .with_context(|| format!("no {} found", self.config.get().device.name_filter))??;
+        self.adapter.stop_scan().await?;
+
+        if !peripheral.is_connected().await? {
+            peripheral.connect().await?;
+            self.connected_peripherals.insert(peripheral.id());
+        }
+        peripheral.discover_services().await?;
+        Ok(peripheral)
+    }
+
+    /// Disconnects peripheral of `connect_first`, unless it was connected before, e.g. by the
+    /// driver running next to the CLI command
+    pub async fn release(&mut self, peripheral: &PeripheralId) -> anyhow::Result<()> {
+        if !self.connected_peripherals.remove(peripheral) {
+            debug!("Leaving {peripheral:?} connected, it was connected before");
+            return Ok(());
+        }
+        self.disconnect(peripheral).await
+    }
+
+    /// Failed attempts are retried with backoff, see `reconnect_due`
+    async fn connect(&mut self, id: &PeripheralId) {
+        if let Err(e) = self.try_connect(id).await {
+            warn!("Failed to connect {id:?}: {e:?}");
+            self.connected_peripherals.remove(id);
+            self.schedule_reconnect(id.clone());
+        }
+    }
+
+    async fn try_connect(&mut self, id: &PeripheralId) -> anyhow::Result<()> {
+        let peripheral = self.adapter.peripheral(id).await?;
+        let is_connected = peripheral.is_connected().await?;
         if is_connected {
-            return;
+            // connected before the driver started, or by BlueZ, no DeviceConnected follows
+            self.start_notifications(id.clone()).await;
+            return Ok(());
         }
-        self.connected_peripheral = Some(peripheral.id());
-        peripheral.connect().await.unwrap();
+        self.connected_peripherals.insert(peripheral.id());
+        self.state
+            .send_replace(ConnectionState::Connecting(peripheral.address()));
+        peripheral.connect().await?;
+        Ok(())
     }
 
-    async fn _disconnect(&self, peripheral: &PeripheralId) {
-        let peripheral = self.adapter.peripheral(peripheral).await.unwrap();
-        let is_connected = peripheral.is_connected().await.unwrap();
+    async fn disconnect(&self, peripheral: &PeripheralId) -> anyhow::Result<()> {
+        let peripheral = self.adapter.peripheral(peripheral).await?;
+        let is_connected = peripheral.is_connected().await?;
         if !is_connected {
-            return;
+            return Ok(());
         }
-        peripheral.disconnect().await.unwrap();
+        peripheral.disconnect().await?;
+        Ok(())
     }
+}
 
-    // move to notifications manager
-    pub async fn send_message(&self, msg: &[u8], characteristic: Uuid) {
-        let connected_peripheral = self.connected_peripheral.clone().unwrap();
-        let peripheral = self
-            .adapter
-            .peripheral(&connected_peripheral)
-            .await
-            .unwrap();
-        let characteristics = peripheral.characteristics();
-        let led_characteristic = characteristics
-            .iter()
-            .find(|c| c.uuid == characteristic && c.properties.contains(CharPropFlags::WRITE))
-            .unwrap();
-        println!("Sending bluetooth msg: {msg:?}");
-        peripheral
-            .write(led_characteristic, msg, WriteType::WithoutResponse)
-            .await
-            .unwrap();
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::ble::simulator::{SimulatedAdapter, SimulatedPeripheral};
+    use crate::config::{Config, DeviceRole, DeviceRoleConfig};
+
+    const VOLUME_KNOB: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+    const MIC_GAIN_KNOB: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x02];
+    const NEIGHBOURS_BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x03];
+
+    #[tokio::test]
+    async fn buttons_with_roles_connect_together() {
+        let mut config = Config::default();
+        config.device.roles = vec![
+            DeviceRoleConfig {
+                address: VOLUME_KNOB.into(),
+                role: DeviceRole::OutputVolume,
+            },
+            DeviceRoleConfig {
+                address: MIC_GAIN_KNOB.into(),
+                role: DeviceRole::MicGain,
+            },
+        ];
+        let config = ConfigHandle::new(None, config);
+        let adapter = SimulatedAdapter::new();
+        let buttons = [VOLUME_KNOB, MIC_GAIN_KNOB, NEIGHBOURS_BUTTON]
+            .map(|address| SimulatedPeripheral::new(address.into(), config.get().device));
+        for button in &buttons {
+            adapter.add(button.clone());
+        }
+        let pairing_file =
+            std::env::temp_dir().join(format!("h-button-roles-{}.toml", std::process::id()));
+        let pairing = PairingStore::at(pairing_file.clone());
+        let mut manager = BtlteManager::with_adapter(adapter, config, pairing.clone());
+
+        let (commands_sender, mut commands) = mpsc::unbounded_channel();
+        let state = Arc::new(watch::channel(ConnectionState::Scanning).0);
+        let mut connection_state = state.subscribe();
+        let run =
+            tokio::spawn(async move { manager.run(&EventBus::new(), &mut commands, state).await });
+
+        let both = ConnectionState::Connected(vec![VOLUME_KNOB.into(), MIC_GAIN_KNOB.into()]);
+        tokio::time::timeout(
+            Duration::from_secs(1),
+            connection_state.wait_for(|state| *state == both),
+        )
+        .await
+        .expect("both buttons with roles connect")
+        .unwrap();
+        assert!(buttons[0].is_subscribed());
+        assert!(buttons[1].is_subscribed());
+        // matches name filter, but roles make an allowlist
+        assert!(!buttons[2].is_connected().await.unwrap());
+        // neither got bound in place of the other
+        assert!(pairing.devices().unwrap().is_empty());
+
+        commands_sender.send(BleCommand::Shutdown).unwrap();
+        run.await.unwrap().unwrap();
+        for button in &buttons {
+            assert!(!button.is_connected().await.unwrap());
+        }
+        let _ = std::fs::remove_file(pairing_file);
     }
 }
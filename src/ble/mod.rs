@@ -1,67 +1,154 @@
+pub mod adapter;
+pub mod battery;
+pub mod connection;
+mod decode_errors;
+pub mod device_info;
+pub mod led;
 mod manager;
 mod notifications;
+pub mod ota;
+pub mod pairing;
+pub mod protocol;
+#[cfg(test)]
+pub(crate) mod simulator;
+#[cfg(all(target_os = "linux", feature = "virtual-button"))]
+pub mod virtual_button;
 
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use btleplug::api::Characteristic;
+use btleplug::api::{CharPropFlags, Characteristic, Peripheral as PeripheralTrait};
 use serde::{Deserialize, Serialize};
-use std::sync::Mutex;
+use tokio::sync::{mpsc::UnboundedReceiver, oneshot, watch};
+use uuid::Uuid;
 
-use self::manager::BtlteManager;
+use crate::app::BluetoothMessage;
+use crate::config::ConfigHandle;
+use crate::events::EventBus;
 
-#[derive(Deserialize, Serialize, Debug, PartialEq, Default)]
+use self::adapter::NoAdapter;
+use self::connection::{Backoff, ConnectionState};
+use self::protocol::DecodeError;
+
+pub use self::manager::BtlteManager;
+#[cfg(test)]
+pub(crate) use self::notifications::NotificationsManager;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
 pub enum LedStatus {
     On,
     #[default]
     Off,
 }
 
-#[derive(Deserialize, Serialize, Debug, Default)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
 pub struct HidStatus {
     pub encoder_position: i32,
     pub mic_mute_button_press_count: u32,
     pub led_status: LedStatus,
 }
 
-pub type OnConnectCallback = Arc<Mutex<dyn FnMut(&[u8]) -> Vec<u8> + Send + Sync>>;
-pub type OnNotificationCallback = Arc<Mutex<dyn FnMut(&[u8]) -> Option<Vec<u8>> + Send + Sync>>;
+/// Message received from the button, or why it couldn't be decoded
+pub type ReceivedMessage = Result<BluetoothMessage, DecodeError>;
 
-pub struct ToBeNamed {
-    btlte_manager: BtlteManager,
+/// Requests sent to the bluetooth thread from other subsystems
+#[derive(Debug)]
+pub enum BleCommand {
+    /// forget bound devices, disconnect and pair with the next matching one
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))] // sent from tray menu only
+    Forget,
+    /// update firmware of the connected button, `result` gets firmware revision after reboot
+    #[cfg_attr(not(feature = "tray"), allow(dead_code))] // sent from tray menu only
+    Flash {
+        image: PathBuf,
+        result: oneshot::Sender<anyhow::Result<Option<String>>>,
+    },
+    /// turn led off, stop notifications, disconnect peripheral and return from `run`
+    Shutdown,
 }
 
-impl ToBeNamed {
-    pub async fn new(peripheral_name_filter: &'static str) -> Self {
-        let btlte_manager = BtlteManager::new(peripheral_name_filter).await;
-        Self { btlte_manager }
-    }
+pub type BleCommandReceiver = UnboundedReceiver<BleCommand>;
 
-    pub async fn run(
-        &mut self,
-        on_connect_cb: OnConnectCallback,
-        on_notification_cb: OnNotificationCallback,
-    ) {
-        self.btlte_manager
-            .run(on_connect_cb, on_notification_cb)
-            .await
-            .unwrap();
-    }
-}
+// service of emulated buttons, real firmware's service uuid doesn't matter as characteristics
+// are looked up by their own uuids
+#[cfg(any(test, all(target_os = "linux", feature = "virtual-button")))]
+pub(crate) const EMULATED_SERVICE_UUID: Uuid =
+    Uuid::from_u128(0x0000ff00_8ed3_4bdf_8a39_a01bebede295);
 
-pub async fn send_message(msg: &[u8], characteristic_str: &str) {
-    let msg = serde_json::to_string(&msg).unwrap().as_bytes().to_vec();
-    // BtlteManager::send_message(msg, LED_STATUS_CHARACTERISTIC_UUID).await;
+/// Looks up discovered characteristic with given uuid supporting all `properties`
+pub(crate) fn find_characteristic<P: PeripheralTrait>(
+    peripheral: &P,
+    uuid: Uuid,
+    properties: CharPropFlags,
+) -> anyhow::Result<Characteristic> {
+    peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.uuid == uuid && c.properties.contains(properties))
+        .ok_or_else(|| anyhow::anyhow!("characteristic {uuid} with {properties:?} not found"))
 }
 
+// manager that ran at least this long before failing is considered healthy,
+// so the next failure starts backing off from the beginning
+const STABLE_RUN: Duration = Duration::from_secs(60);
+// how often to look for adapter, when none is plugged in
+const ADAPTER_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Runs bluetooth manager until `BleCommand::Shutdown`, starting it over with backoff
+/// whenever it fails, e.g. when adapter is unplugged or BlueZ restarts. Button input is
+/// published to `events`, led commands are taken from there.
 pub async fn run(
-    peripheral_name_filter: &'static str,
-    on_connect_cb: OnConnectCallback,
-    on_notification_cb: OnNotificationCallback,
+    config: ConfigHandle,
+    events: EventBus,
+    mut commands: BleCommandReceiver,
+    state: watch::Sender<ConnectionState>,
 ) {
-    let mut manager = BtlteManager::new(peripheral_name_filter).await;
-    manager
-        .run(on_connect_cb, on_notification_cb)
-        .await
-        .unwrap();
-    // block_on(manager.run());
+    // shared with every manager started below
+    let state = Arc::new(state);
+    let mut backoff = Backoff::default();
+    loop {
+        let started = Instant::now();
+        let result = match BtlteManager::new(config.clone()).await {
+            Ok(mut manager) => manager.run(&events, &mut commands, state.clone()).await,
+            Err(e) => Err(e),
+        };
+        let Err(e) = result else {
+            return;
+        };
+
+        let retry_in = if let Some(NoAdapter(selector)) = e.downcast_ref::<NoAdapter>() {
+            // waiting for adapter to be plugged in is not a failure, so no backoff
+            if *state.borrow() != ConnectionState::NoAdapter {
+                warn!("{e}, waiting for it to be plugged in");
+            }
+            trace!("Adapter {selector:?} not present");
+            state.send_replace(ConnectionState::NoAdapter);
+            ADAPTER_POLL_INTERVAL
+        } else {
+            if started.elapsed() > STABLE_RUN {
+                backoff.reset();
+            }
+            let retry_in = backoff.next_delay();
+            error!("Bluetooth manager failed, restarting in {retry_in:?}: {e:?}");
+            state.send_replace(ConnectionState::Backoff {
+                attempt: backoff.attempt(),
+                retry_in,
+            });
+            retry_in
+        };
+
+        // shutdown still has to be handled while waiting
+        let sleep = tokio::time::sleep(retry_in);
+        tokio::pin!(sleep);
+        loop {
+            tokio::select! {
+                _ = &mut sleep => break,
+                command = commands.recv() => match command {
+                    Some(BleCommand::Shutdown) | None => return,
+                    Some(command) => trace!("Ignoring {command:?}, bluetooth is not running"),
+                },
+            }
+        }
+    }
 }
@@ -1,39 +1,127 @@
 use crate::app::BluetoothMessage;
 
-use btleplug::api::{CharPropFlags, Peripheral as PeripheralTrait, WriteType};
+use anyhow::Context;
+use btleplug::api::{BDAddr, CharPropFlags, Characteristic, Peripheral as PeripheralTrait};
 use futures::stream::StreamExt;
 use futures::FutureExt;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::select;
 use tokio::sync::{mpsc::channel, mpsc::Receiver, mpsc::Sender, Mutex};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
-const NOTIFY_CHARACTERISTIC_UUID: Uuid = Uuid::from_u128(0xa3c87500_8ed3_4bdf_8a39_a01bebede295);
-const LED_STATUS_CHARACTERISTIC_UUID: Uuid =
-    Uuid::from_u128(0x3c9a3f00_8ed3_4bdf_8a39_a01bebede295);
-
-use uuid::Uuid;
-
-use super::{OnConnectCallback, OnNotificationCallback};
+use super::battery::{self, BATTERY_LEVEL_UUID};
+use super::decode_errors::DecodeErrors;
+use super::device_info::{DeviceInfo, OutdatedFirmware};
+use super::led::Led;
+use super::protocol::{firmware_protocol_version, Encoding};
+use super::{find_characteristic, HidStatus, ReceivedMessage};
+use crate::config::{DeviceConfig, OldFirmwarePolicy};
+use crate::events::{Event, EventBus};
 
 pub enum NotificationsManagerCommand {
     Stop,
 }
 
+/// Turns `HidStatus` reports, which carry absolute encoder position and press count,
+/// into events relative to the previous report
+struct InputTracker {
+    address: BDAddr,
+    // `None` until the first valid report, which becomes the baseline
+    baseline: Option<HidStatus>,
+    decode_errors: DecodeErrors,
+    // firmware reports button edges, presses are recognized from them instead of press count
+    edges_seen: bool,
+}
+
+impl InputTracker {
+    fn new(address: BDAddr) -> Self {
+        Self {
+            address,
+            baseline: None,
+            decode_errors: DecodeErrors::default(),
+            edges_seen: false,
+        }
+    }
+
+    fn received(&mut self, msg: ReceivedMessage) -> Vec<Event> {
+        let address = self.address;
+        let mut events = Vec::new();
+        if msg.is_ok() && self.decode_errors.good_frame(address) {
+            info!("{address} sends valid messages again");
+            events.push(Event::DeviceWarning {
+                address,
+                warning: None,
+            });
+        }
+        let hid_status = match msg {
+            Ok(BluetoothMessage::HidStatus(hid_status)) => hid_status,
+            Ok(BluetoothMessage::ButtonDown(timestamp) | BluetoothMessage::ButtonUp(timestamp)) => {
+                self.edges_seen = true;
+                events.push(Event::ButtonEdge {
+                    address,
+                    pressed: matches!(msg, Ok(BluetoothMessage::ButtonDown(_))),
+                    timestamp: Duration::from_millis(timestamp.into()),
+                });
+                return events;
+            }
+            Ok(msg) => {
+                warn!("Ignoring unexpected {msg:?} from {address}");
+                return events;
+            }
+            Err(e) if e.is_unknown_message() => {
+                info!("Ignoring {e} from {address}, firmware may be newer than the driver");
+                return events;
+            }
+            Err(e) => {
+                if let Some(warning) = self.decode_errors.bad_frame(address, &e) {
+                    error!("{warning}");
+                    events.push(Event::DeviceWarning {
+                        address,
+                        warning: Some(warning),
+                    });
+                }
+                return events;
+            }
+        };
+        if let Some(baseline) = &self.baseline {
+            if hid_status.encoder_position != baseline.encoder_position {
+                events.push(Event::EncoderDelta {
+                    address,
+                    impulses: hid_status.encoder_position - baseline.encoder_position,
+                    at: Instant::now(),
+                });
+            }
+            if hid_status.mic_mute_button_press_count != baseline.mic_mute_button_press_count
+                && !self.edges_seen
+            {
+                events.push(Event::MutePressed { address });
+            }
+        }
+        self.baseline = Some(hid_status);
+        events
+    }
+}
+
 pub(crate) struct NotificationsManager<T: PeripheralTrait + 'static> {
     peripheral: T,
+    device_config: DeviceConfig,
+    // negotiated in `start`
+    encoding: Encoding,
+    // set up in `start`, once encoding is known
+    led: Option<Led<T>>,
     tx: Arc<Mutex<Sender<NotificationsManagerCommand>>>,
     rx: Arc<Mutex<Receiver<NotificationsManagerCommand>>>,
-    on_connect_cb: OnConnectCallback,
-    on_notification_cb: OnNotificationCallback,
+    events: EventBus,
+    // spawned by `start`, ends with `stop` or when this manager is dropped
+    task: Option<JoinHandle<()>>,
+    // characteristics subscribed by `start`, unsubscribed by `stop`
+    subscriptions: Vec<Characteristic>,
 }
 
 impl<T: PeripheralTrait> NotificationsManager<T> {
-    pub async fn new(
-        peripheral: T,
-        on_connect_cb: OnConnectCallback,
-        on_notification_cb: OnNotificationCallback,
-    ) -> Self {
+    pub async fn new(peripheral: T, device_config: DeviceConfig, events: EventBus) -> Self {
         let (tx, rx): (
             Sender<NotificationsManagerCommand>,
             Receiver<NotificationsManagerCommand>,
@@ -41,96 +129,212 @@ impl<T: PeripheralTrait> NotificationsManager<T> {
 
         Self {
             peripheral,
+            device_config,
+            encoding: Encoding::default(),
+            led: None,
             tx: Arc::new(Mutex::new(tx)),
             rx: Arc::new(Mutex::new(rx)),
-            on_connect_cb,
-            on_notification_cb,
+            events,
+            task: None,
+            subscriptions: Vec::new(),
         }
     }
 
-    pub async fn start(&self) {
-        self.peripheral.discover_services().await.unwrap();
+    pub fn address(&self) -> BDAddr {
+        self.peripheral.address()
+    }
+
+    pub async fn start(&mut self) -> anyhow::Result<()> {
+        self.peripheral
+            .discover_services()
+            .await
+            .context("failed to discover services")?;
+        let protocol_characteristic_uuid = self.device_config.protocol_characteristic_uuid;
+        let protocol_version =
+            firmware_protocol_version(&self.peripheral, protocol_characteristic_uuid).await?;
+        let device_info = DeviceInfo::read(&self.peripheral, protocol_version).await;
+        info!("{} device information:\n{device_info}", self.address());
+        if protocol_version < self.device_config.min_protocol_version {
+            let outdated = OutdatedFirmware {
+                address: self.address(),
+                protocol_version,
+                min_protocol_version: self.device_config.min_protocol_version,
+            };
+            match self.device_config.old_firmware {
+                OldFirmwarePolicy::Warn => warn!("{outdated}"),
+                OldFirmwarePolicy::Refuse => return Err(outdated.into()),
+            }
+        }
+        self.encoding = Encoding::select(
+            &self.peripheral,
+            protocol_characteristic_uuid,
+            protocol_version,
+        )
+        .await?;
+        info!("Using {:?} encoding", self.encoding);
+        let encoding = self.encoding;
+        let led = Led::new(
+            self.peripheral.clone(),
+            self.device_config.led_status_characteristic_uuid,
+            encoding,
+            Duration::from_millis(self.device_config.led_reconcile_ms),
+        )?;
+        self.led = Some(led.clone());
 
         // Read position and set is a base
         for characteristic in self.peripheral.characteristics() {
             info!("Checking characteristic {characteristic:?}");
             // Subscribe to notifications from the characteristic with the selected UUID
-            if characteristic.uuid == NOTIFY_CHARACTERISTIC_UUID
+            if characteristic.uuid == self.device_config.notify_characteristic_uuid
                 && characteristic.properties.contains(CharPropFlags::NOTIFY)
             {
-                println!("Subscribing to characteristic {:?}", characteristic.uuid);
-                self.peripheral.subscribe(&characteristic).await.unwrap();
+                debug!("Subscribing to characteristic {:?}", characteristic.uuid);
+                self.peripheral.subscribe(&characteristic).await?;
+                self.subscriptions.push(characteristic.clone());
                 let peripheral = self.peripheral.clone();
                 let rx: Arc<Mutex<Receiver<NotificationsManagerCommand>>> = self.rx.clone();
-                let on_connect_cb = self.on_connect_cb.clone();
-                let on_notification_cb = self.on_notification_cb.clone();
-                let initial_data = peripheral.read(&characteristic).await.unwrap();
-                let msg = on_connect_cb.lock().unwrap()(&initial_data);
-                let characteristics = peripheral.characteristics();
-                let led_characteristic = characteristics
-                    .iter()
-                    .find(|c| {
-                        c.uuid == LED_STATUS_CHARACTERISTIC_UUID
-                            && c.properties.contains(CharPropFlags::WRITE)
-                    })
-                    .unwrap();
-                peripheral
-                    .write(led_characteristic, &msg, WriteType::WithoutResponse)
-                    .await
-                    .unwrap();
+                let address = peripheral.address();
+                let events = self.events.clone();
+                // stream is opened before the initial read, so no notification is lost in between
+                let mut notification_stream = peripheral.notifications().await?;
+                let initial_data = peripheral.read(&characteristic).await?;
+                let initial_msg = encoding.decode(&initial_data);
+                debug!("Initial value of {address}: {initial_msg:?}");
+                // without it the first valid notification becomes the baseline
+                let mut input = InputTracker::new(address);
+                for event in input.received(initial_msg) {
+                    events.publish(event);
+                }
+                // subscribed before announcing the device, so led command answering it isn't missed
+                let mut led_commands = events.subscribe();
+                events.publish(Event::DeviceConnected {
+                    address,
+                    device_info: device_info.clone(),
+                });
+                let led = led.clone();
+                let notify_characteristic_uuid = characteristic.uuid;
+                // battery is not essential, button works without it
+                match battery::subscribe(&peripheral).await {
+                    Ok(Some(level)) => {
+                        if let Ok(battery_level) = find_characteristic(
+                            &peripheral,
+                            BATTERY_LEVEL_UUID,
+                            CharPropFlags::NOTIFY,
+                        ) {
+                            self.subscriptions.push(battery_level);
+                        }
+                        events.publish(Event::BatteryLevel { address, level })
+                    }
+                    Ok(None) => info!("{address} has no Battery Service"),
+                    Err(e) => warn!("Battery level of {address} won't be shown: {e:?}"),
+                }
 
-                tokio::spawn(async move {
-                    let mut notification_stream = peripheral.notifications().await.unwrap();
-                    let on_notification_cb = on_notification_cb.clone();
+                self.task = Some(tokio::spawn(async move {
                     select!(
                         _ = async {
+                            // a write may get lost even when acknowledged, e.g. by firmware
+                            // restarting in between, so the button is asked what it shows
+                            let mut reconcile = tokio::time::interval(led.reconcile_interval());
+                            reconcile.tick().await;
                             loop {
-                                tokio::time::sleep(Duration::from_secs(1)).await;
-                                trace!("Worker is alive");
+                                reconcile.tick().await;
+                                if let Err(e) = led.reconcile(&characteristic).await {
+                                    warn!("Failed to reconcile led of {address}: {e:?}");
+                                }
                             }
                         }.fuse() => {},
                         _ = async {
                             while let Some(data) = notification_stream.next().await {
-                                let in_msg: BluetoothMessage = serde_json::from_slice(&data.value).unwrap();
-                                println!("Received bluetooth msg: {:?}", in_msg);
-                                let out_msg = on_notification_cb.lock().unwrap()(&data.value);
-                                if let Some(out_msg) = out_msg
-                                {
-                                    let characteristics = peripheral.characteristics();
-                                    let led_characteristic = characteristics
-                                        .iter()
-                                        .find(|c| {
-                                            c.uuid == LED_STATUS_CHARACTERISTIC_UUID
-                                                && c.properties.contains(CharPropFlags::WRITE)
-                                        })
-                                        .unwrap();
-                                    println!("Sending bluetooth msg: {msg:?}");
-                                    peripheral
-                                        .write(
-                                            led_characteristic,
-                                            &out_msg,
-                                            WriteType::WithoutResponse,
-                                        ).await.unwrap();
+                                if data.uuid == BATTERY_LEVEL_UUID {
+                                    match battery::level(&data.value) {
+                                        Some(level) => {
+                                            events.publish(Event::BatteryLevel { address, level })
+                                        }
+                                        None => warn!(
+                                            "Invalid battery level {:?} from {address}",
+                                            data.value
+                                        ),
+                                    }
+                                    continue;
+                                }
+                                if data.uuid != notify_characteristic_uuid {
+                                    debug!(
+                                        "Ignoring notification of {} from {address}: {:?}",
+                                        data.uuid, data.value
+                                    );
+                                    continue;
+                                }
+                                let in_msg = encoding.decode(&data.value);
+                                for event in input.received(in_msg) {
+                                    events.publish(event);
                                 }
                             }
                         }.fuse() => {},
                         _ = async {
-                            while let Some(cmd) = rx.lock().await.recv().await {
-                                match cmd {
-                                    NotificationsManagerCommand::Stop => {
-                                        println!("Stopping notifications manager");
-                                    }
+                            while let Some(event) = led_commands.recv().await {
+                                let Event::LedCommand { address: to, led_status } = event else {
+                                    continue;
+                                };
+                                if to.is_some_and(|to| to != address) {
+                                    continue;
+                                }
+                                let msg = BluetoothMessage::SetMicMuteIndicator(led_status);
+                                if let Err(e) = led.send(&msg).await {
+                                    error!("Failed to send {msg:?} to {address}: {e:?}");
+                                }
+                            }
+                        }.fuse() => {},
+                        _ = async {
+                            // sender is dropped with the manager, which ends the task as well
+                            match rx.lock().await.recv().await {
+                                Some(NotificationsManagerCommand::Stop) => {
+                                    debug!("Stopping notifications manager of {address}");
                                 }
+                                None => debug!("Notifications manager of {address} dropped"),
                             }
                         }.fuse() => {},
                     );
-                });
+                }));
             }
         }
+        Ok(())
+    }
+
+    /// Writes `msg` to LED status characteristic in negotiated encoding
+    pub async fn send(&self, msg: &BluetoothMessage) -> anyhow::Result<()> {
+        let Some(led) = &self.led else {
+            anyhow::bail!("notifications of {} not started", self.address());
+        };
+        led.send(msg).await
     }
 
-    pub async fn stop(&self) {
-        let tx = self.tx.lock().await;
-        tx.send(NotificationsManagerCommand::Stop).await.unwrap();
+    /// Ends notifications task and waits for it, then unsubscribes when the button is still
+    /// connected, e.g. when it's stopped for firmware update
+    pub async fn stop(mut self) {
+        let address = self.address();
+        if let Some(task) = self.task.take() {
+            // the task may have ended already, with notification stream of lost connection
+            let _ = self
+                .tx
+                .lock()
+                .await
+                .send(NotificationsManagerCommand::Stop)
+                .await;
+            if let Err(e) = task.await {
+                warn!("Notifications task of {address} failed: {e:?}");
+            }
+        }
+        // subscriptions end with the connection
+        if self.peripheral.is_connected().await.unwrap_or(false) {
+            for characteristic in self.subscriptions.drain(..) {
+                if let Err(e) = self.peripheral.unsubscribe(&characteristic).await {
+                    warn!(
+                        "Failed to unsubscribe {} of {address}: {e:?}",
+                        characteristic.uuid
+                    );
+                }
+            }
+        }
+        self.events.publish(Event::DeviceDisconnected { address });
     }
 }
@@ -0,0 +1,480 @@
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use btleplug::api::{CharPropFlags, Peripheral as PeripheralTrait, WriteType};
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::device_info::DeviceInfo;
+use super::find_characteristic;
+
+// commands written to OTA characteristic, first byte of the write, integers are little endian
+/// size u32, SHA-256 of the image; firmware keeps received bytes of the same image (resume)
+pub(crate) const OTA_BEGIN: u8 = 0x01;
+/// offset u32, CRC-32 of the payload u32, payload
+pub(crate) const OTA_DATA: u8 = 0x02;
+/// firmware checks SHA-256 of received image and reboots into it
+pub(crate) const OTA_FINISH: u8 = 0x03;
+
+const DATA_HEADER_LEN: usize = 9;
+// opcode and handle of ATT write request
+const ATT_WRITE_HEADER_LEN: usize = 3;
+const DEFAULT_ATT_MTU: u16 = 23;
+
+// ESP-IDF app image: 24 byte image header and 8 byte segment header, then `esp_app_desc_t`
+const APP_DESC_OFFSET: usize = 32;
+const APP_DESC_MAGIC: u32 = 0xabcd5432;
+// after magic word, secure version and 2 reserved words
+const APP_DESC_VERSION_OFFSET: usize = APP_DESC_OFFSET + 16;
+const APP_DESC_VERSION_LEN: usize = 32;
+
+// upload is resumed this many times after the connection drops
+const MAX_RESUMES: u32 = 5;
+const RESUME_DELAY: Duration = Duration::from_millis(500);
+// how long the button may take to reboot into the new image and come back
+const REBOOT_TIMEOUT: Duration = Duration::from_secs(60);
+const REBOOT_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// State of the update on the firmware side, first byte of OTA characteristic value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OtaState {
+    Idle = 0,
+    Receiving = 1,
+    /// image didn't match SHA-256 given in `OTA_BEGIN`, received bytes were dropped
+    Failed = 2,
+}
+
+/// Value of OTA characteristic: state u8, received bytes u32, negotiated ATT MTU u16
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OtaStatus {
+    pub state: OtaState,
+    pub offset: u32,
+    pub mtu: u16,
+}
+
+impl OtaStatus {
+    // firmware side, served by the simulator
+    #[cfg(test)]
+    pub fn encode(&self) -> Vec<u8> {
+        let mut value = vec![self.state as u8];
+        value.extend(self.offset.to_le_bytes());
+        value.extend(self.mtu.to_le_bytes());
+        value
+    }
+
+    pub fn decode(value: &[u8]) -> anyhow::Result<Self> {
+        let [state, o0, o1, o2, o3, m0, m1] = *value else {
+            anyhow::bail!("invalid OTA status {value:02x?}");
+        };
+        let state = match state {
+            0 => OtaState::Idle,
+            1 => OtaState::Receiving,
+            2 => OtaState::Failed,
+            state => anyhow::bail!("unknown OTA state {state}"),
+        };
+        Ok(Self {
+            state,
+            offset: u32::from_le_bytes([o0, o1, o2, o3]),
+            mtu: u16::from_le_bytes([m0, m1]),
+        })
+    }
+}
+
+/// Firmware image to be flashed, with its checksum and version
+#[derive(Debug, Clone)]
+pub struct FirmwareImage {
+    data: Vec<u8>,
+    sha256: [u8; 32],
+    version: Option<String>,
+}
+
+impl FirmwareImage {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("cannot read {}", path.display()))?;
+        anyhow::ensure!(!data.is_empty(), "{} is empty", path.display());
+        anyhow::ensure!(
+            u32::try_from(data.len()).is_ok(),
+            "{} is too big",
+            path.display()
+        );
+        Ok(Self::new(data))
+    }
+
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            sha256: Sha256::digest(&data).into(),
+            version: Self::app_version(&data),
+            data,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn sha256(&self) -> [u8; 32] {
+        self.sha256
+    }
+
+    /// Version from ESP-IDF app description, compared with firmware revision after reboot
+    pub fn version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub(crate) fn app_version(data: &[u8]) -> Option<String> {
+        let magic = data.get(APP_DESC_OFFSET..APP_DESC_OFFSET + 4)?;
+        if u32::from_le_bytes(magic.try_into().unwrap()) != APP_DESC_MAGIC {
+            return None;
+        }
+        let version =
+            data.get(APP_DESC_VERSION_OFFSET..APP_DESC_VERSION_OFFSET + APP_DESC_VERSION_LEN)?;
+        let len = version
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(version.len());
+        let version = String::from_utf8_lossy(&version[..len]).trim().to_string();
+        (!version.is_empty()).then_some(version)
+    }
+}
+
+/// Reported by `flash` while the update goes on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OtaProgress {
+    /// upload (re)started, `offset` is non-zero when resumed
+    Started {
+        offset: usize,
+        total: usize,
+    },
+    Uploading {
+        sent: usize,
+        total: usize,
+    },
+    Verifying,
+    /// waiting for the button to come back with new firmware
+    Rebooting,
+}
+
+/// Button rejected uploaded image, as its SHA-256 didn't match. Not retried.
+#[derive(Debug)]
+pub struct ImageRejected;
+
+impl fmt::Display for ImageRejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "button rejected the image, SHA-256 of received data doesn't match"
+        )
+    }
+}
+
+impl std::error::Error for ImageRejected {}
+
+/// Payload bytes fitting into one write with given ATT MTU
+pub(crate) fn chunk_size(mtu: u16) -> usize {
+    let mtu = mtu.max(DEFAULT_ATT_MTU) as usize;
+    mtu - ATT_WRITE_HEADER_LEN - DATA_HEADER_LEN
+}
+
+/// Uploads `image` through OTA characteristic, resuming when the connection drops,
+/// then waits for the button to reboot and checks it runs the new version.
+/// Returns firmware revision reported after reboot.
+pub async fn flash<P: PeripheralTrait>(
+    peripheral: &P,
+    ota_characteristic_uuid: Uuid,
+    image: &FirmwareImage,
+    mut progress: impl FnMut(OtaProgress),
+) -> anyhow::Result<Option<String>> {
+    let mut resumes = 0;
+    loop {
+        let result = async {
+            ensure_connected(peripheral).await?;
+            upload(peripheral, ota_characteristic_uuid, image, &mut progress).await
+        }
+        .await;
+        match result {
+            Ok(()) => break,
+            Err(e) if e.is::<ImageRejected>() => return Err(e),
+            Err(e) if resumes < MAX_RESUMES => {
+                resumes += 1;
+                warn!("Firmware upload interrupted, resuming ({resumes}/{MAX_RESUMES}): {e:?}");
+                tokio::time::sleep(RESUME_DELAY).await;
+            }
+            Err(e) => return Err(e.context("firmware upload failed")),
+        }
+    }
+
+    progress(OtaProgress::Rebooting);
+    wait_for_reboot(peripheral).await?;
+    let device_info = DeviceInfo::read(peripheral, 0).await;
+    let revision = device_info.firmware_revision;
+    match (image.version(), revision.as_deref()) {
+        (Some(expected), Some(actual)) if expected != actual => {
+            anyhow::bail!("button runs firmware {actual} after update, expected {expected}")
+        }
+        (Some(expected), None) => {
+            warn!("Button doesn't report firmware revision, can't check it's {expected}")
+        }
+        _ => {}
+    }
+    Ok(revision)
+}
+
+async fn ensure_connected<P: PeripheralTrait>(peripheral: &P) -> anyhow::Result<()> {
+    if !peripheral.is_connected().await? {
+        peripheral.connect().await?;
+    }
+    peripheral.discover_services().await?;
+    Ok(())
+}
+
+async fn read_status<P: PeripheralTrait>(
+    peripheral: &P,
+    characteristic: &btleplug::api::Characteristic,
+) -> anyhow::Result<OtaStatus> {
+    let value = peripheral
+        .read(characteristic)
+        .await
+        .context("failed to read OTA status")?;
+    OtaStatus::decode(&value)
+}
+
+async fn upload<P: PeripheralTrait>(
+    peripheral: &P,
+    ota_characteristic_uuid: Uuid,
+    image: &FirmwareImage,
+    progress: &mut impl FnMut(OtaProgress),
+) -> anyhow::Result<()> {
+    let characteristic = find_characteristic(
+        peripheral,
+        ota_characteristic_uuid,
+        CharPropFlags::READ | CharPropFlags::WRITE,
+    )
+    .context("button doesn't support firmware update")?;
+    let total = image.len();
+
+    let mut begin = vec![OTA_BEGIN];
+    begin.extend((total as u32).to_le_bytes());
+    begin.extend(image.sha256());
+    peripheral
+        .write(&characteristic, &begin, WriteType::WithResponse)
+        .await
+        .context("failed to start firmware upload")?;
+
+    let status = read_status(peripheral, &characteristic).await?;
+    let mut offset = status.offset as usize;
+    anyhow::ensure!(
+        offset <= total,
+        "button reports {offset} bytes received out of {total}"
+    );
+    progress(OtaProgress::Started { offset, total });
+
+    let chunk_size = chunk_size(status.mtu);
+    for chunk in image.data[offset..].chunks(chunk_size) {
+        let mut data = Vec::with_capacity(DATA_HEADER_LEN + chunk.len());
+        data.push(OTA_DATA);
+        data.extend((offset as u32).to_le_bytes());
+        data.extend(crc32fast::hash(chunk).to_le_bytes());
+        data.extend(chunk);
+        peripheral
+            .write(&characteristic, &data, WriteType::WithResponse)
+            .await
+            .with_context(|| format!("failed to write firmware at {offset}"))?;
+        offset += chunk.len();
+        progress(OtaProgress::Uploading {
+            sent: offset,
+            total,
+        });
+    }
+
+    progress(OtaProgress::Verifying);
+    if let Err(e) = peripheral
+        .write(&characteristic, &[OTA_FINISH], WriteType::WithResponse)
+        .await
+    {
+        // connection may be gone too, that's resumed like any other failure
+        return match read_status(peripheral, &characteristic).await {
+            Ok(status) if status.state == OtaState::Failed => Err(ImageRejected.into()),
+            _ => Err(anyhow::anyhow!(e).context("failed to finish firmware upload")),
+        };
+    }
+    Ok(())
+}
+
+// firmware reboots on its own after successful `OTA_FINISH`
+async fn wait_for_reboot<P: PeripheralTrait>(peripheral: &P) -> anyhow::Result<()> {
+    let deadline = Instant::now() + REBOOT_TIMEOUT;
+    while peripheral.is_connected().await? {
+        anyhow::ensure!(
+            Instant::now() < deadline,
+            "button didn't reboot after update"
+        );
+        tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+    }
+    loop {
+        match ensure_connected(peripheral).await {
+            Ok(()) => return Ok(()),
+            Err(e) if Instant::now() < deadline => {
+                trace!("Button is not back yet: {e:?}");
+                tokio::time::sleep(REBOOT_POLL_INTERVAL).await;
+            }
+            Err(e) => return Err(e.context("button didn't come back after update")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btleplug::api::Peripheral as _;
+
+    use super::*;
+    use crate::ble::simulator::{SimulatedPeripheral, SIMULATED_MTU};
+    use crate::config::DeviceConfig;
+
+    const BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+
+    /// ESP-IDF like image of `len` bytes with `version` in its app description
+    fn esp_image(version: &str, len: usize) -> Vec<u8> {
+        let mut data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        data[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        let version_field = &mut data[APP_DESC_VERSION_OFFSET..][..APP_DESC_VERSION_LEN];
+        version_field.fill(0);
+        version_field[..version.len()].copy_from_slice(version.as_bytes());
+        data
+    }
+
+    async fn connected_button() -> SimulatedPeripheral {
+        let button = SimulatedPeripheral::new(BUTTON.into(), DeviceConfig::default());
+        button.connect().await.unwrap();
+        button
+    }
+
+    fn ota_uuid() -> Uuid {
+        DeviceConfig::default().ota_characteristic_uuid
+    }
+
+    #[test]
+    fn version_is_read_from_esp_app_description() {
+        assert_eq!(
+            FirmwareImage::new(esp_image("v1.4.2", 1000)).version(),
+            Some("v1.4.2")
+        );
+        assert_eq!(FirmwareImage::new(vec![0; 1000]).version(), None);
+        assert_eq!(FirmwareImage::new(vec![0; 10]).version(), None);
+    }
+
+    #[test]
+    fn chunks_fit_into_mtu() {
+        assert_eq!(chunk_size(23), 11);
+        assert_eq!(chunk_size(247), 235);
+        // below the minimum the default is used
+        assert_eq!(chunk_size(0), 11);
+    }
+
+    #[test]
+    fn status_round_trips() {
+        let status = OtaStatus {
+            state: OtaState::Receiving,
+            offset: 70000,
+            mtu: 247,
+        };
+        assert_eq!(
+            status.encode(),
+            vec![0x01, 0x70, 0x11, 0x01, 0x00, 0xf7, 0x00]
+        );
+        assert_eq!(OtaStatus::decode(&status.encode()).unwrap(), status);
+        assert!(OtaStatus::decode(&[0x01, 0x00]).is_err());
+    }
+
+    #[tokio::test]
+    async fn image_is_flashed_and_version_checked() {
+        let button = connected_button().await;
+        let image = FirmwareImage::new(esp_image("v2.0.0", 4000));
+        let mut reported = Vec::new();
+
+        let version = flash(&button, ota_uuid(), &image, |p| reported.push(p))
+            .await
+            .unwrap();
+
+        assert_eq!(version.as_deref(), Some("v2.0.0"));
+        assert_eq!(button.firmware_image().unwrap(), esp_image("v2.0.0", 4000));
+        let chunks = button.ota_chunks();
+        assert!(chunks
+            .iter()
+            .all(|&(_, len)| len <= chunk_size(SIMULATED_MTU)));
+        assert_eq!(chunks.iter().map(|&(_, len)| len).sum::<usize>(), 4000);
+        assert_eq!(
+            reported.first(),
+            Some(&OtaProgress::Started {
+                offset: 0,
+                total: 4000
+            })
+        );
+        assert!(reported.contains(&OtaProgress::Uploading {
+            sent: 4000,
+            total: 4000
+        }));
+        assert_eq!(reported.last(), Some(&OtaProgress::Rebooting));
+        assert!(button.is_connected().await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn upload_resumes_after_disconnect() {
+        let button = connected_button().await;
+        button.disconnect_during_ota(1000);
+        let image = FirmwareImage::new(esp_image("v2.0.0", 4000));
+        let mut started = Vec::new();
+
+        flash(&button, ota_uuid(), &image, |p| {
+            if let OtaProgress::Started { offset, .. } = p {
+                started.push(offset)
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(started.len(), 2);
+        assert!(started[1] >= 1000, "restarted from {}", started[1]);
+        // nothing sent twice
+        assert_eq!(
+            button
+                .ota_chunks()
+                .iter()
+                .map(|&(_, len)| len)
+                .sum::<usize>(),
+            4000
+        );
+        assert_eq!(button.firmware_image().unwrap(), esp_image("v2.0.0", 4000));
+    }
+
+    #[tokio::test]
+    async fn corrupted_image_is_rejected() {
+        let button = connected_button().await;
+        let mut image = FirmwareImage::new(esp_image("v2.0.0", 4000));
+        image.sha256 = [0; 32];
+
+        let error = flash(&button, ota_uuid(), &image, |_| {})
+            .await
+            .unwrap_err();
+
+        assert!(error.is::<ImageRejected>(), "{error:?}");
+        assert_eq!(button.firmware_image(), None);
+    }
+
+    #[tokio::test]
+    async fn unexpected_version_after_reboot_fails() {
+        let button = connected_button().await;
+        // image claims other version than the simulator boots into
+        let mut image = FirmwareImage::new(esp_image("v2.0.0", 4000));
+        image.version = Some("v3.0.0".to_string());
+
+        let error = flash(&button, ota_uuid(), &image, |_| {})
+            .await
+            .unwrap_err();
+
+        assert!(error.to_string().contains("v2.0.0"), "{error:?}");
+    }
+}
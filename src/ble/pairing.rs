@@ -0,0 +1,149 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Context;
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+
+use crate::config::address;
+
+const PAIRING_FILE_NAME: &str = "paired.toml";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PairedDevice {
+    #[serde(deserialize_with = "address::deserialize")]
+    pub address: BDAddr,
+    #[serde(default)]
+    pub name: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct PairingFile {
+    #[serde(default, rename = "device")]
+    devices: Vec<PairedDevice>,
+}
+
+/// Buttons bound with `h-button-driver bind`, persisted between runs.
+/// File is read on every access, so changes made by CLI are seen by running driver.
+#[derive(Clone, Debug)]
+pub struct PairingStore {
+    path: Option<PathBuf>,
+}
+
+impl PairingStore {
+    /// `$XDG_STATE_HOME/h-button-driver/paired.toml` on Linux
+    pub fn open() -> Self {
+        let path = dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(PAIRING_FILE_NAME));
+        if path.is_none() {
+            warn!("No state directory, paired devices won't be remembered");
+        }
+        Self { path }
+    }
+
+    /// Store in given file, for tests not to touch devices paired on this machine
+    #[cfg(test)]
+    pub fn at(path: PathBuf) -> Self {
+        Self { path: Some(path) }
+    }
+
+    pub fn devices(&self) -> anyhow::Result<Vec<PairedDevice>> {
+        let Some(path) = self.path.as_ref().filter(|path| path.exists()) else {
+            return Ok(Vec::new());
+        };
+        let content =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        let file: PairingFile =
+            toml::from_str(&content).with_context(|| format!("invalid {}", path.display()))?;
+        Ok(file.devices)
+    }
+
+    /// Adds `device`, or makes it the only one bound when `replace` is set
+    pub fn bind(&self, device: PairedDevice, replace: bool) -> anyhow::Result<()> {
+        let mut devices = if replace { Vec::new() } else { self.devices()? };
+        devices.retain(|d| d.address != device.address);
+        devices.push(device);
+        self.save(devices)
+    }
+
+    /// Forgets device with given address, or all of them. Returns how many were removed.
+    pub fn forget(&self, address: Option<BDAddr>) -> anyhow::Result<usize> {
+        let mut devices = self.devices()?;
+        let count = devices.len();
+        devices.retain(|d| address.is_some_and(|address| d.address != address));
+        let removed = count - devices.len();
+        self.save(devices)?;
+        Ok(removed)
+    }
+
+    fn save(&self, devices: Vec<PairedDevice>) -> anyhow::Result<()> {
+        let path = self
+            .path
+            .as_ref()
+            .context("no state directory to store paired devices in")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        let content = toml::to_string(&PairingFile { devices })?;
+        fs::write(path, content).with_context(|| format!("cannot write {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(last: u8, name: &str) -> PairedDevice {
+        PairedDevice {
+            address: [0x24, 0x0a, 0xc4, 0x00, 0x00, last].into(),
+            name: name.to_string(),
+        }
+    }
+
+    fn store(test: &str) -> (PairingStore, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("h-button-pairing-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        (PairingStore::at(dir.join(PAIRING_FILE_NAME)), dir)
+    }
+
+    #[test]
+    fn bind_adds_device() {
+        let (store, dir) = store("add");
+        store.bind(device(1, "Knob"), false).unwrap();
+        store.bind(device(2, "Button"), false).unwrap();
+        // binding again updates the name instead of adding a duplicate
+        store.bind(device(1, "Volume"), false).unwrap();
+        assert_eq!(
+            store.devices().unwrap(),
+            vec![device(2, "Button"), device(1, "Volume")]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn bind_replaces_devices() {
+        let (store, dir) = store("replace");
+        store.bind(device(1, "Knob"), false).unwrap();
+        store.bind(device(2, "Button"), false).unwrap();
+        store.bind(device(3, "Volume"), true).unwrap();
+        assert_eq!(store.devices().unwrap(), vec![device(3, "Volume")]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn forget_unknown_address_keeps_devices() {
+        let (store, dir) = store("forget");
+        assert_eq!(store.forget(Some(device(1, "").address)).unwrap(), 0);
+        store.bind(device(1, "Knob"), false).unwrap();
+        store.bind(device(2, "Button"), false).unwrap();
+
+        assert_eq!(store.forget(Some(device(3, "").address)).unwrap(), 0);
+        assert_eq!(store.devices().unwrap().len(), 2);
+        assert_eq!(store.forget(Some(device(1, "").address)).unwrap(), 1);
+        assert_eq!(store.devices().unwrap(), vec![device(2, "Button")]);
+        assert_eq!(store.forget(None).unwrap(), 1);
+        assert!(store.devices().unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
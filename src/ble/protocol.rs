@@ -0,0 +1,375 @@
+use std::fmt;
+
+use anyhow::Context;
+use btleplug::api::{CharPropFlags, Peripheral as PeripheralTrait, WriteType};
+use uuid::Uuid;
+
+use super::find_characteristic;
+use crate::app::BluetoothMessage;
+
+/// Version of binary encoding, highest one the driver speaks
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// message types, low nibble of the header byte
+const HID_STATUS: u8 = 0x1;
+const SET_MIC_MUTE_INDICATOR: u8 = 0x2;
+const BUTTON_DOWN: u8 = 0x3;
+const BUTTON_UP: u8 = 0x4;
+
+// variant names of `BluetoothMessage`, tags of JSON messages
+const KNOWN_MESSAGES: [&str; 4] = ["HidStatus", "SetMicMuteIndicator", "ButtonDown", "ButtonUp"];
+
+/// Why bytes received from the button couldn't be turned into `BluetoothMessage`
+#[derive(Debug)]
+pub enum DecodeError {
+    /// well-formed message of a type this driver doesn't know, e.g. sent by newer firmware
+    UnknownMessage(String),
+    /// binary frame of protocol version other than negotiated one
+    UnsupportedVersion(u8),
+    EmptyFrame,
+    Json(serde_json::Error),
+    Binary(postcard::Error),
+}
+
+impl DecodeError {
+    /// Unknown messages are expected from newer firmware and should be ignored,
+    /// all other errors mean a corrupted frame
+    pub fn is_unknown_message(&self) -> bool {
+        matches!(self, DecodeError::UnknownMessage(_))
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnknownMessage(tag) => write!(f, "unknown message {tag}"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {version}")
+            }
+            DecodeError::EmptyFrame => write!(f, "empty frame"),
+            DecodeError::Json(e) => write!(f, "invalid JSON message: {e}"),
+            DecodeError::Binary(e) => write!(f, "invalid binary message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DecodeError::Json(e) => Some(e),
+            DecodeError::Binary(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// How `BluetoothMessage` is put on the wire, chosen per connection by `negotiate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// `serde_json`, the only one understood by firmware without protocol characteristic
+    #[default]
+    Json,
+    /// header byte (version in high nibble, message type in low nibble) followed by
+    /// postcard encoded payload, `HidStatus` fits into 12 bytes. Holds version negotiated with
+    /// the firmware, which may be lower than `PROTOCOL_VERSION`.
+    Binary(u8),
+}
+
+/// Highest binary protocol version connected firmware speaks, 0 when it has no protocol
+/// characteristic and speaks JSON only
+pub async fn firmware_protocol_version<P: PeripheralTrait>(
+    peripheral: &P,
+    protocol_characteristic_uuid: Uuid,
+) -> anyhow::Result<u8> {
+    let Ok(characteristic) = find_characteristic(
+        peripheral,
+        protocol_characteristic_uuid,
+        CharPropFlags::READ | CharPropFlags::WRITE,
+    ) else {
+        return Ok(0);
+    };
+    let supported = peripheral
+        .read(&characteristic)
+        .await
+        .context("failed to read protocol version")?;
+    Ok(supported.first().copied().unwrap_or(0))
+}
+
+impl Encoding {
+    /// Agrees on encoding with connected firmware.
+    ///
+    /// Firmware supporting binary encoding has protocol characteristic, reading it returns the
+    /// highest binary version it speaks. Writing a version to it switches both directions of
+    /// this connection to binary, until disconnect. Without the characteristic JSON is used.
+    pub async fn negotiate<P: PeripheralTrait>(
+        peripheral: &P,
+        protocol_characteristic_uuid: Uuid,
+    ) -> anyhow::Result<Self> {
+        let firmware_version =
+            firmware_protocol_version(peripheral, protocol_characteristic_uuid).await?;
+        Self::select(peripheral, protocol_characteristic_uuid, firmware_version).await
+    }
+
+    /// Second half of `negotiate`, for callers which need `firmware_protocol_version` too
+    pub async fn select<P: PeripheralTrait>(
+        peripheral: &P,
+        protocol_characteristic_uuid: Uuid,
+        firmware_version: u8,
+    ) -> anyhow::Result<Self> {
+        if firmware_version == 0 {
+            return Ok(Encoding::Json);
+        }
+        let version = firmware_version.min(PROTOCOL_VERSION);
+        let characteristic = find_characteristic(
+            peripheral,
+            protocol_characteristic_uuid,
+            CharPropFlags::READ | CharPropFlags::WRITE,
+        )?;
+        peripheral
+            .write(&characteristic, &[version], WriteType::WithResponse)
+            .await
+            .context("failed to select protocol version")?;
+        Ok(Encoding::Binary(version))
+    }
+
+    pub fn encode(self, msg: &BluetoothMessage) -> Vec<u8> {
+        match self {
+            Encoding::Json => serde_json::to_vec(msg).unwrap(),
+            Encoding::Binary(version) => {
+                let (message_type, payload) = match msg {
+                    BluetoothMessage::HidStatus(hid_status) => {
+                        (HID_STATUS, postcard::to_allocvec(hid_status))
+                    }
+                    BluetoothMessage::SetMicMuteIndicator(led_status) => {
+                        (SET_MIC_MUTE_INDICATOR, postcard::to_allocvec(led_status))
+                    }
+                    BluetoothMessage::ButtonDown(timestamp) => {
+                        (BUTTON_DOWN, postcard::to_allocvec(timestamp))
+                    }
+                    BluetoothMessage::ButtonUp(timestamp) => {
+                        (BUTTON_UP, postcard::to_allocvec(timestamp))
+                    }
+                };
+                let mut frame = vec![version << 4 | message_type];
+                // serializing into a Vec can't fail
+                frame.extend(payload.unwrap());
+                frame
+            }
+        }
+    }
+
+    pub fn decode(self, data: &[u8]) -> Result<BluetoothMessage, DecodeError> {
+        match self {
+            Encoding::Json => serde_json::from_slice(data).map_err(|e| {
+                // enum is externally tagged: `{"NewMessage": ...}` or `"NewMessage"`
+                let tag = match serde_json::from_slice(data) {
+                    Ok(serde_json::Value::Object(map)) if map.len() == 1 => {
+                        map.keys().next().cloned()
+                    }
+                    Ok(serde_json::Value::String(tag)) => Some(tag),
+                    _ => None,
+                };
+                match tag {
+                    Some(tag) if !KNOWN_MESSAGES.contains(&tag.as_str()) => {
+                        DecodeError::UnknownMessage(tag)
+                    }
+                    _ => DecodeError::Json(e),
+                }
+            }),
+            Encoding::Binary(negotiated) => {
+                let (&header, payload) = data.split_first().ok_or(DecodeError::EmptyFrame)?;
+                let version = header >> 4;
+                if version != negotiated {
+                    return Err(DecodeError::UnsupportedVersion(version));
+                }
+                match header & 0x0f {
+                    HID_STATUS => postcard::from_bytes(payload).map(BluetoothMessage::HidStatus),
+                    SET_MIC_MUTE_INDICATOR => {
+                        postcard::from_bytes(payload).map(BluetoothMessage::SetMicMuteIndicator)
+                    }
+                    BUTTON_DOWN => postcard::from_bytes(payload).map(BluetoothMessage::ButtonDown),
+                    BUTTON_UP => postcard::from_bytes(payload).map(BluetoothMessage::ButtonUp),
+                    message_type => {
+                        return Err(DecodeError::UnknownMessage(format!("type {message_type}")))
+                    }
+                }
+                .map_err(DecodeError::Binary)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use btleplug::api::Peripheral as _;
+
+    use super::*;
+    use crate::ble::simulator::SimulatedPeripheral;
+    use crate::ble::{HidStatus, LedStatus};
+    use crate::config::DeviceConfig;
+
+    // golden vectors below are frames of version 1
+    const V1: Encoding = Encoding::Binary(1);
+
+    fn hid_status(
+        encoder_position: i32,
+        mic_mute_button_press_count: u32,
+        led_status: LedStatus,
+    ) -> BluetoothMessage {
+        BluetoothMessage::HidStatus(HidStatus {
+            encoder_position,
+            mic_mute_button_press_count,
+            led_status,
+        })
+    }
+
+    // shared with firmware, any change here breaks buttons in the field
+    fn golden_vectors() -> Vec<(BluetoothMessage, Vec<u8>)> {
+        vec![
+            (
+                hid_status(0, 0, LedStatus::Off),
+                vec![0x11, 0x00, 0x00, 0x01],
+            ),
+            // encoder position is zigzag encoded, -3 -> 5
+            (
+                hid_status(-3, 2, LedStatus::On),
+                vec![0x11, 0x05, 0x02, 0x00],
+            ),
+            // integers are varints, 7 bits per byte, least significant first
+            (
+                hid_status(300, 70000, LedStatus::On),
+                vec![0x11, 0xd8, 0x04, 0xf0, 0xa2, 0x04, 0x00],
+            ),
+            (
+                hid_status(i32::MIN, u32::MAX, LedStatus::Off),
+                vec![
+                    0x11, 0xff, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0xff, 0x0f, 0x01,
+                ],
+            ),
+            (
+                BluetoothMessage::SetMicMuteIndicator(LedStatus::On),
+                vec![0x12, 0x00],
+            ),
+            (
+                BluetoothMessage::SetMicMuteIndicator(LedStatus::Off),
+                vec![0x12, 0x01],
+            ),
+            (BluetoothMessage::ButtonDown(1000), vec![0x13, 0xe8, 0x07]),
+            (BluetoothMessage::ButtonUp(1250), vec![0x14, 0xe2, 0x09]),
+        ]
+    }
+
+    #[test]
+    fn binary_encoding_matches_golden_vectors() {
+        for (msg, frame) in golden_vectors() {
+            assert_eq!(V1.encode(&msg), frame, "{msg:?}");
+        }
+    }
+
+    #[test]
+    fn binary_decoding_matches_golden_vectors() {
+        for (msg, frame) in golden_vectors() {
+            assert_eq!(V1.decode(&frame).unwrap(), msg);
+        }
+    }
+
+    async fn negotiate_with(firmware_version: u8) -> (SimulatedPeripheral, Encoding) {
+        let device_config = DeviceConfig::default();
+        let button = SimulatedPeripheral::with_protocol(
+            [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01].into(),
+            device_config.clone(),
+            firmware_version,
+        );
+        button.connect().await.unwrap();
+        let encoding = Encoding::negotiate(&button, device_config.protocol_characteristic_uuid)
+            .await
+            .unwrap();
+        (button, encoding)
+    }
+
+    // firmware in the field keeps speaking v1 after `PROTOCOL_VERSION` is bumped
+    #[tokio::test]
+    async fn older_firmware_keeps_its_version() {
+        let (button, encoding) = negotiate_with(1).await;
+        assert_eq!(encoding, V1);
+        assert_eq!(button.encoding(), V1);
+        for (msg, frame) in golden_vectors() {
+            assert_eq!(encoding.encode(&msg), frame, "{msg:?}");
+            assert_eq!(encoding.decode(&frame).unwrap(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn newer_firmware_speaks_driver_version() {
+        let (button, encoding) = negotiate_with(PROTOCOL_VERSION + 1).await;
+        assert_eq!(encoding, Encoding::Binary(PROTOCOL_VERSION));
+        assert_eq!(button.encoding(), encoding);
+    }
+
+    #[test]
+    fn frames_of_other_version_than_negotiated_are_rejected() {
+        for (_, frame) in golden_vectors() {
+            let error = Encoding::Binary(2).decode(&frame).unwrap_err();
+            assert!(
+                matches!(error, DecodeError::UnsupportedVersion(1)),
+                "{error}"
+            );
+        }
+    }
+
+    #[test]
+    fn hid_status_fits_default_att_payload() {
+        let frame = V1.encode(&hid_status(i32::MIN, u32::MAX, LedStatus::Off));
+        assert!(frame.len() <= 20);
+    }
+
+    #[test]
+    fn json_encoding_is_unchanged() {
+        assert_eq!(
+            Encoding::Json.encode(&hid_status(-3, 2, LedStatus::On)),
+            br#"{"HidStatus":{"encoder_position":-3,"mic_mute_button_press_count":2,"led_status":"On"}}"#
+        );
+        assert_eq!(
+            Encoding::Json.encode(&BluetoothMessage::SetMicMuteIndicator(LedStatus::Off)),
+            br#"{"SetMicMuteIndicator":"Off"}"#
+        );
+        assert_eq!(
+            Encoding::Json.encode(&BluetoothMessage::ButtonDown(1000)),
+            br#"{"ButtonDown":1000}"#
+        );
+    }
+
+    #[test]
+    fn binary_decoding_rejects_bad_frames() {
+        for frame in [
+            &[][..],
+            &[0x21, 0x00, 0x00, 0x01],
+            &[0x11, 0x00],
+            &[0x12, 0x02],
+        ] {
+            let error = V1.decode(frame).unwrap_err();
+            assert!(!error.is_unknown_message(), "{frame:02x?}: {error}");
+        }
+    }
+
+    #[test]
+    fn unknown_messages_are_told_apart_from_garbage() {
+        for (encoding, data) in [
+            (V1, &[0x15, 0x50][..]),
+            (Encoding::Json, br#"{"BatteryLevel":80}"#),
+            (Encoding::Json, br#""Reboot""#),
+        ] {
+            let error = encoding.decode(data).unwrap_err();
+            assert!(error.is_unknown_message(), "{data:02x?}: {error}");
+        }
+        for data in [
+            &br#"{"HidStatus":{"encoder_position":"x"}}"#[..],
+            br#"{"HidSta"#,
+            b"\xff\x00",
+            b"",
+        ] {
+            let error = Encoding::Json.decode(data).unwrap_err();
+            assert!(!error.is_unknown_message(), "{data:02x?}: {error}");
+        }
+    }
+}
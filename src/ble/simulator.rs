@@ -0,0 +1,698 @@
+use std::collections::BTreeSet;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use btleplug::api::{
+    BDAddr, Central, CentralEvent, CharPropFlags, Characteristic, Descriptor, Peripheral,
+    PeripheralProperties, ScanFilter, Service, ValueNotification, WriteType,
+};
+use btleplug::platform::PeripheralId;
+use btleplug::{Error, Result};
+use futures::stream::{self, Stream};
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use super::battery::{BATTERY_LEVEL_UUID, BATTERY_SERVICE_UUID};
+use super::device_info::{
+    DEVICE_INFORMATION_SERVICE_UUID, FIRMWARE_REVISION_UUID, HARDWARE_REVISION_UUID,
+    MANUFACTURER_NAME_UUID, MODEL_NUMBER_UUID, SERIAL_NUMBER_UUID,
+};
+use super::ota::{FirmwareImage, OtaState, OtaStatus, OTA_BEGIN, OTA_DATA, OTA_FINISH};
+use super::protocol::Encoding;
+use super::{HidStatus, LedStatus, EMULATED_SERVICE_UUID};
+use crate::app::BluetoothMessage;
+use crate::config::DeviceConfig;
+
+/// ATT MTU the simulated firmware reports in OTA status
+pub(crate) const SIMULATED_MTU: u16 = 185;
+
+/// Things a user (or the firmware) does with the button, see `SimulatedPeripheral::play`
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum SimulatedEvent {
+    /// turn the knob by given number of impulses, negative is counter clockwise
+    TurnEncoder(i32),
+    PressMute,
+    /// mute button goes down, at given milliseconds of firmware clock
+    ButtonDown(u32),
+    /// mute button goes up, firmware reporting edges still counts the press in `HidStatus`
+    ButtonUp(u32),
+    /// firmware restarts: status is reset and connection is dropped
+    Reboot,
+}
+
+/// In-process H-Button: serves notify and LED characteristics (and protocol one when created
+/// `with_protocol`, Battery Service once `set_battery_level` is called), Device Information
+/// Service and OTA characteristic, emits `HidStatus` notifications and records everything written
+/// to the LED characteristic
+#[derive(Clone, Debug)]
+pub(crate) struct SimulatedPeripheral {
+    inner: Arc<Inner>,
+}
+
+// firmware side of an update
+#[derive(Debug, Default)]
+struct Ota {
+    size: usize,
+    sha256: [u8; 32],
+    received: Vec<u8>,
+    failed: bool,
+    chunks: Vec<(usize, usize)>,
+    flashed: Option<Vec<u8>>,
+    disconnect_at: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    id: PeripheralId,
+    address: BDAddr,
+    device_config: DeviceConfig,
+    // highest binary protocol version, `None` for old firmware without protocol characteristic
+    protocol_version: Option<u8>,
+    encoding: Mutex<Encoding>,
+    hid_status: Mutex<HidStatus>,
+    connected: AtomicBool,
+    subscribed: AtomicBool,
+    // `None` for buttons powered over USB, without Battery Service
+    battery_level: Mutex<Option<u8>>,
+    battery_subscribed: AtomicBool,
+    led_writes: Mutex<Vec<Vec<u8>>>,
+    led_states: Mutex<Vec<LedStatus>>,
+    led_write_types: Mutex<Vec<WriteType>>,
+    // next LED writes failing with an error, then next ones silently lost
+    failing_led_writes: Mutex<usize>,
+    lost_led_writes: Mutex<usize>,
+    firmware_revision: Mutex<String>,
+    ota: Mutex<Ota>,
+    // replaced on disconnect, which ends all notification streams handed out so far
+    notifications: Mutex<broadcast::Sender<ValueNotification>>,
+    // events of `SimulatedAdapter` the button was added to
+    central_events: Mutex<Option<broadcast::Sender<CentralEvent>>>,
+}
+
+impl SimulatedPeripheral {
+    /// Button with firmware speaking JSON only
+    pub fn new(address: BDAddr, device_config: DeviceConfig) -> Self {
+        Self::create(address, device_config, None)
+    }
+
+    /// Button with firmware supporting binary encoding up to `protocol_version`
+    pub fn with_protocol(
+        address: BDAddr,
+        device_config: DeviceConfig,
+        protocol_version: u8,
+    ) -> Self {
+        Self::create(address, device_config, Some(protocol_version))
+    }
+
+    fn create(address: BDAddr, device_config: DeviceConfig, protocol_version: Option<u8>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                id: Self::peripheral_id(address),
+                address,
+                device_config,
+                protocol_version,
+                encoding: Mutex::new(Encoding::Json),
+                hid_status: Mutex::new(HidStatus::default()),
+                connected: AtomicBool::new(false),
+                subscribed: AtomicBool::new(false),
+                battery_level: Mutex::new(None),
+                battery_subscribed: AtomicBool::new(false),
+                led_writes: Mutex::new(Vec::new()),
+                led_states: Mutex::new(Vec::new()),
+                led_write_types: Mutex::new(Vec::new()),
+                failing_led_writes: Mutex::new(0),
+                lost_led_writes: Mutex::new(0),
+                firmware_revision: Mutex::new(match protocol_version {
+                    Some(version) => format!("2.{version}.0"),
+                    None => "1.0.0".to_string(),
+                }),
+                ota: Mutex::new(Ota::default()),
+                notifications: Mutex::new(broadcast::channel(64).0),
+                central_events: Mutex::new(None),
+            }),
+        }
+    }
+
+    // PeripheralId is opaque, but deserializable; on Linux it's a BlueZ object path
+    fn peripheral_id(address: BDAddr) -> PeripheralId {
+        let path = format!(
+            "/org/bluez/hci0/dev_{}",
+            address.to_string().replace(':', "_")
+        );
+        serde_json::from_value(serde_json::json!({ "object_path": path })).unwrap()
+    }
+
+    pub fn hid_status(&self) -> HidStatus {
+        self.inner.hid_status.lock().unwrap().clone()
+    }
+
+    /// Raw messages written to LED characteristic, oldest first
+    pub fn led_writes(&self) -> Vec<Vec<u8>> {
+        self.inner.led_writes.lock().unwrap().clone()
+    }
+
+    /// Decoded `SetMicMuteIndicator` messages written to LED characteristic
+    pub fn led_states(&self) -> Vec<LedStatus> {
+        self.inner.led_states.lock().unwrap().clone()
+    }
+
+    /// Write types used for LED characteristic, including failed and lost writes
+    pub fn led_write_types(&self) -> Vec<WriteType> {
+        self.inner.led_write_types.lock().unwrap().clone()
+    }
+
+    /// Next `count` LED writes fail, as when radio link is bad
+    pub fn fail_led_writes(&self, count: usize) {
+        *self.inner.failing_led_writes.lock().unwrap() = count;
+    }
+
+    /// Next `count` LED writes succeed, but never reach the firmware
+    pub fn lose_led_writes(&self, count: usize) {
+        *self.inner.lost_led_writes.lock().unwrap() = count;
+    }
+
+    /// Encoding selected by the driver for current connection
+    pub fn encoding(&self) -> Encoding {
+        *self.inner.encoding.lock().unwrap()
+    }
+
+    /// Adds Battery Service, or changes its level and notifies subscribed driver
+    pub fn set_battery_level(&self, level: u8) {
+        *self.inner.battery_level.lock().unwrap() = Some(level);
+        if self.inner.battery_subscribed.load(Ordering::SeqCst) {
+            self.send_notification(BATTERY_LEVEL_UUID, vec![level]);
+        }
+    }
+
+    pub fn play(&self, events: &[SimulatedEvent]) {
+        for event in events {
+            self.apply(*event);
+        }
+    }
+
+    pub fn apply(&self, event: SimulatedEvent) {
+        let mut hid_status = self.inner.hid_status.lock().unwrap();
+        match event {
+            SimulatedEvent::TurnEncoder(impulses) => hid_status.encoder_position += impulses,
+            SimulatedEvent::PressMute => hid_status.mic_mute_button_press_count += 1,
+            SimulatedEvent::ButtonDown(at) => {
+                drop(hid_status);
+                self.notify(self.encoding().encode(&BluetoothMessage::ButtonDown(at)));
+                return;
+            }
+            SimulatedEvent::ButtonUp(at) => {
+                self.notify(self.encoding().encode(&BluetoothMessage::ButtonUp(at)));
+                hid_status.mic_mute_button_press_count += 1;
+            }
+            SimulatedEvent::Reboot => {
+                *hid_status = HidStatus::default();
+                drop(hid_status);
+                self.drop_connection();
+                return;
+            }
+        }
+        let value = self.encode(&hid_status);
+        drop(hid_status);
+        self.notify(value);
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.inner.subscribed.load(Ordering::SeqCst)
+            || self.inner.battery_subscribed.load(Ordering::SeqCst)
+    }
+
+    /// Notification streams opened by the driver and not dropped yet
+    pub fn notification_streams(&self) -> usize {
+        self.inner.notifications.lock().unwrap().receiver_count()
+    }
+
+    /// Sends raw bytes as notification, e.g. to check how garbage from firmware is handled
+    pub fn notify(&self, value: Vec<u8>) {
+        if !self.inner.subscribed.load(Ordering::SeqCst) {
+            return;
+        }
+        self.send_notification(self.inner.device_config.notify_characteristic_uuid, value);
+    }
+
+    /// Sends raw bytes as notification of another characteristic, as firmware notifying on its
+    /// LED or protocol characteristic does
+    pub fn notify_characteristic(&self, uuid: Uuid, value: Vec<u8>) {
+        if !self.inner.subscribed.load(Ordering::SeqCst) {
+            return;
+        }
+        self.send_notification(uuid, value);
+    }
+
+    fn send_notification(&self, uuid: Uuid, value: Vec<u8>) {
+        let notification = ValueNotification { uuid, value };
+        // no receivers is fine, nobody is listening yet
+        let _ = self.inner.notifications.lock().unwrap().send(notification);
+    }
+
+    /// Device Information Service characteristics and their values
+    pub fn device_information(&self) -> Vec<(Uuid, String)> {
+        let firmware_revision = self.inner.firmware_revision.lock().unwrap().clone();
+        vec![
+            (MANUFACTURER_NAME_UUID, "H-Button".to_string()),
+            (MODEL_NUMBER_UUID, "HB-SIM".to_string()),
+            (
+                SERIAL_NUMBER_UUID,
+                self.inner.address.to_string().replace(':', ""),
+            ),
+            (HARDWARE_REVISION_UUID, "rev-sim".to_string()),
+            (FIRMWARE_REVISION_UUID, firmware_revision),
+        ]
+    }
+
+    /// Image the button rebooted into after successful update
+    pub fn firmware_image(&self) -> Option<Vec<u8>> {
+        self.inner.ota.lock().unwrap().flashed.clone()
+    }
+
+    /// Offset and length of every OTA chunk accepted, oldest first
+    pub fn ota_chunks(&self) -> Vec<(usize, usize)> {
+        self.inner.ota.lock().unwrap().chunks.clone()
+    }
+
+    /// Connection drops once, when at least `offset` bytes of an update are received
+    pub fn disconnect_during_ota(&self, offset: usize) {
+        self.inner.ota.lock().unwrap().disconnect_at = Some(offset);
+    }
+
+    fn ota_status(&self) -> OtaStatus {
+        let ota = self.inner.ota.lock().unwrap();
+        let state = if ota.failed {
+            OtaState::Failed
+        } else if ota.size > 0 {
+            OtaState::Receiving
+        } else {
+            OtaState::Idle
+        };
+        OtaStatus {
+            state,
+            offset: ota.received.len() as u32,
+            mtu: SIMULATED_MTU,
+        }
+    }
+
+    fn ota_write(&self, data: &[u8]) -> Result<()> {
+        let rejected = |reason: &str| Err(Error::Other(reason.to_string().into()));
+        let mut ota = self.inner.ota.lock().unwrap();
+        match data {
+            [OTA_BEGIN, header @ ..] if header.len() == 36 => {
+                let size = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+                let sha256: [u8; 32] = header[4..].try_into().unwrap();
+                // same image is resumed, other one starts over
+                if (size, sha256) != (ota.size, ota.sha256) {
+                    *ota = Ota {
+                        size,
+                        sha256,
+                        flashed: ota.flashed.take(),
+                        disconnect_at: ota.disconnect_at,
+                        ..Default::default()
+                    };
+                }
+                ota.failed = false;
+                Ok(())
+            }
+            [OTA_DATA, header @ ..] if header.len() >= 8 => {
+                let offset = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+                let crc = u32::from_le_bytes(header[4..8].try_into().unwrap());
+                let payload = &header[8..];
+                if offset != ota.received.len() || offset + payload.len() > ota.size {
+                    return rejected("unexpected offset");
+                }
+                if crc32fast::hash(payload) != crc {
+                    return rejected("CRC mismatch");
+                }
+                ota.received.extend(payload);
+                ota.chunks.push((offset, payload.len()));
+                if ota.disconnect_at.is_some_and(|at| ota.received.len() >= at) {
+                    ota.disconnect_at = None;
+                    drop(ota);
+                    self.drop_connection();
+                    return Err(Error::NotConnected);
+                }
+                Ok(())
+            }
+            [OTA_FINISH] => {
+                let received = std::mem::take(&mut ota.received);
+                let image = FirmwareImage::new(received.clone());
+                if image.len() != ota.size || image.sha256() != ota.sha256 {
+                    ota.failed = true;
+                    return rejected("image verification failed");
+                }
+                *self.inner.firmware_revision.lock().unwrap() =
+                    image.version().unwrap_or("unknown").to_string();
+                *ota = Ota {
+                    flashed: Some(received),
+                    chunks: std::mem::take(&mut ota.chunks),
+                    ..Default::default()
+                };
+                drop(ota);
+                self.apply(SimulatedEvent::Reboot);
+                Ok(())
+            }
+            _ => rejected("invalid OTA command"),
+        }
+    }
+
+    fn drop_connection(&self) {
+        if self.inner.connected.swap(false, Ordering::SeqCst) {
+            self.send_central_event(CentralEvent::DeviceDisconnected(self.id()));
+        }
+        self.inner.subscribed.store(false, Ordering::SeqCst);
+        self.inner.battery_subscribed.store(false, Ordering::SeqCst);
+        *self.inner.encoding.lock().unwrap() = Encoding::Json;
+        *self.inner.notifications.lock().unwrap() = broadcast::channel(64).0;
+    }
+
+    fn send_central_event(&self, event: CentralEvent) {
+        if let Some(events) = self.inner.central_events.lock().unwrap().as_ref() {
+            let _ = events.send(event);
+        }
+    }
+
+    fn encode(&self, hid_status: &HidStatus) -> Vec<u8> {
+        self.encoding()
+            .encode(&BluetoothMessage::HidStatus(hid_status.clone()))
+    }
+
+    fn ensure_connected(&self) -> Result<()> {
+        if !self.inner.connected.load(Ordering::SeqCst) {
+            return Err(Error::NotConnected);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Peripheral for SimulatedPeripheral {
+    fn id(&self) -> PeripheralId {
+        self.inner.id.clone()
+    }
+
+    fn address(&self) -> BDAddr {
+        self.inner.address
+    }
+
+    async fn properties(&self) -> Result<Option<PeripheralProperties>> {
+        Ok(Some(PeripheralProperties {
+            address: self.inner.address,
+            local_name: Some(self.inner.device_config.name_filter.clone()),
+            rssi: Some(-40),
+            ..Default::default()
+        }))
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        let characteristic = |uuid, properties| Characteristic {
+            uuid,
+            service_uuid: EMULATED_SERVICE_UUID,
+            properties,
+            descriptors: BTreeSet::new(),
+        };
+        let device_config = &self.inner.device_config;
+        let mut characteristics = BTreeSet::from([
+            characteristic(
+                device_config.notify_characteristic_uuid,
+                CharPropFlags::READ | CharPropFlags::NOTIFY,
+            ),
+            characteristic(
+                device_config.led_status_characteristic_uuid,
+                CharPropFlags::WRITE | CharPropFlags::WRITE_WITHOUT_RESPONSE,
+            ),
+        ]);
+        characteristics.insert(characteristic(
+            device_config.ota_characteristic_uuid,
+            CharPropFlags::READ | CharPropFlags::WRITE,
+        ));
+        if self.inner.protocol_version.is_some() {
+            characteristics.insert(characteristic(
+                device_config.protocol_characteristic_uuid,
+                CharPropFlags::READ | CharPropFlags::WRITE,
+            ));
+        }
+        let mut services = BTreeSet::from([Service {
+            uuid: EMULATED_SERVICE_UUID,
+            primary: true,
+            characteristics,
+        }]);
+        services.insert(Service {
+            uuid: DEVICE_INFORMATION_SERVICE_UUID,
+            primary: true,
+            characteristics: self
+                .device_information()
+                .into_iter()
+                .map(|(uuid, _)| Characteristic {
+                    uuid,
+                    service_uuid: DEVICE_INFORMATION_SERVICE_UUID,
+                    properties: CharPropFlags::READ,
+                    descriptors: BTreeSet::new(),
+                })
+                .collect(),
+        });
+        if self.inner.battery_level.lock().unwrap().is_some() {
+            services.insert(Service {
+                uuid: BATTERY_SERVICE_UUID,
+                primary: true,
+                characteristics: BTreeSet::from([Characteristic {
+                    uuid: BATTERY_LEVEL_UUID,
+                    service_uuid: BATTERY_SERVICE_UUID,
+                    properties: CharPropFlags::READ | CharPropFlags::NOTIFY,
+                    descriptors: BTreeSet::new(),
+                }]),
+            });
+        }
+        services
+    }
+
+    async fn is_connected(&self) -> Result<bool> {
+        Ok(self.inner.connected.load(Ordering::SeqCst))
+    }
+
+    async fn connect(&self) -> Result<()> {
+        if !self.inner.connected.swap(true, Ordering::SeqCst) {
+            self.send_central_event(CentralEvent::DeviceConnected(self.id()));
+        }
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<()> {
+        self.drop_connection();
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> Result<()> {
+        self.ensure_connected()
+    }
+
+    async fn write(
+        &self,
+        characteristic: &Characteristic,
+        data: &[u8],
+        write_type: WriteType,
+    ) -> Result<()> {
+        self.ensure_connected()?;
+        let device_config = &self.inner.device_config;
+        if let Some(protocol_version) = self
+            .inner
+            .protocol_version
+            .filter(|_| characteristic.uuid == device_config.protocol_characteristic_uuid)
+        {
+            return match data {
+                [version] if (1..=protocol_version).contains(version) => {
+                    *self.inner.encoding.lock().unwrap() = Encoding::Binary(*version);
+                    Ok(())
+                }
+                _ => Err(Error::Other(
+                    format!("unsupported protocol {data:?}").into(),
+                )),
+            };
+        }
+        if characteristic.uuid == device_config.ota_characteristic_uuid {
+            return self.ota_write(data);
+        }
+        if characteristic.uuid != device_config.led_status_characteristic_uuid {
+            return Err(Error::NoSuchCharacteristic);
+        }
+        self.inner.led_write_types.lock().unwrap().push(write_type);
+        for (remaining, failed) in [
+            (&self.inner.failing_led_writes, true),
+            (&self.inner.lost_led_writes, false),
+        ] {
+            let mut remaining = remaining.lock().unwrap();
+            if *remaining > 0 {
+                *remaining -= 1;
+                return match failed {
+                    true => Err(Error::Other("simulated write failure".into())),
+                    false => Ok(()),
+                };
+            }
+        }
+        // firmware keeps led state in its status, so it's reported back in next notification
+        if let Ok(BluetoothMessage::SetMicMuteIndicator(led_status)) = self.encoding().decode(data)
+        {
+            self.inner.hid_status.lock().unwrap().led_status = led_status;
+            self.inner.led_states.lock().unwrap().push(led_status);
+        }
+        self.inner.led_writes.lock().unwrap().push(data.to_vec());
+        Ok(())
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> Result<Vec<u8>> {
+        self.ensure_connected()?;
+        let device_config = &self.inner.device_config;
+        match self.inner.protocol_version {
+            Some(version) if characteristic.uuid == device_config.protocol_characteristic_uuid => {
+                return Ok(vec![version]);
+            }
+            _ => {}
+        }
+        if let Some((_, value)) = self
+            .device_information()
+            .into_iter()
+            .find(|(uuid, _)| *uuid == characteristic.uuid)
+        {
+            return Ok(value.into_bytes());
+        }
+        if characteristic.uuid == device_config.ota_characteristic_uuid {
+            return Ok(self.ota_status().encode());
+        }
+        if characteristic.uuid == BATTERY_LEVEL_UUID {
+            let level = *self.inner.battery_level.lock().unwrap();
+            return level
+                .map(|level| vec![level])
+                .ok_or(Error::NoSuchCharacteristic);
+        }
+        if characteristic.uuid != device_config.notify_characteristic_uuid {
+            return Err(Error::NoSuchCharacteristic);
+        }
+        Ok(self.encode(&self.inner.hid_status.lock().unwrap()))
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        self.ensure_connected()?;
+        let subscribed =
+            if characteristic.uuid == self.inner.device_config.notify_characteristic_uuid {
+                &self.inner.subscribed
+            } else if characteristic.uuid == BATTERY_LEVEL_UUID
+                && self.inner.battery_level.lock().unwrap().is_some()
+            {
+                &self.inner.battery_subscribed
+            } else {
+                return Err(Error::NoSuchCharacteristic);
+            };
+        subscribed.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> Result<()> {
+        if characteristic.uuid == BATTERY_LEVEL_UUID {
+            self.inner.battery_subscribed.store(false, Ordering::SeqCst);
+        } else {
+            self.inner.subscribed.store(false, Ordering::SeqCst);
+        }
+        Ok(())
+    }
+
+    async fn notifications(&self) -> Result<Pin<Box<dyn Stream<Item = ValueNotification> + Send>>> {
+        let receiver = self.inner.notifications.lock().unwrap().subscribe();
+        Ok(Box::pin(stream::unfold(receiver, |mut receiver| async {
+            loop {
+                match receiver.recv().await {
+                    Ok(notification) => return Some((notification, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })))
+    }
+
+    async fn write_descriptor(&self, _descriptor: &Descriptor, _data: &[u8]) -> Result<()> {
+        Err(Error::NotSupported("descriptors".to_string()))
+    }
+
+    async fn read_descriptor(&self, _descriptor: &Descriptor) -> Result<Vec<u8>> {
+        Err(Error::NotSupported("descriptors".to_string()))
+    }
+}
+
+/// In-process bluetooth adapter: simulated buttons added to it are discovered while scanning,
+/// and their connects and disconnects are reported as `CentralEvent`s
+#[derive(Clone, Debug)]
+pub(crate) struct SimulatedAdapter {
+    peripherals: Arc<Mutex<Vec<SimulatedPeripheral>>>,
+    events: broadcast::Sender<CentralEvent>,
+}
+
+impl SimulatedAdapter {
+    pub fn new() -> Self {
+        Self {
+            peripherals: Arc::new(Mutex::new(Vec::new())),
+            events: broadcast::channel(64).0,
+        }
+    }
+
+    /// Button comes in range, it's discovered by the next scan
+    pub fn add(&self, peripheral: SimulatedPeripheral) {
+        *peripheral.inner.central_events.lock().unwrap() = Some(self.events.clone());
+        self.peripherals.lock().unwrap().push(peripheral);
+    }
+}
+
+#[async_trait]
+impl Central for SimulatedAdapter {
+    type Peripheral = SimulatedPeripheral;
+
+    async fn events(&self) -> Result<Pin<Box<dyn Stream<Item = CentralEvent> + Send>>> {
+        let receiver = self.events.subscribe();
+        Ok(Box::pin(stream::unfold(receiver, |mut receiver| async {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        })))
+    }
+
+    // like BlueZ, every button in range is announced when scan starts
+    async fn start_scan(&self, _filter: ScanFilter) -> Result<()> {
+        for peripheral in self.peripherals.lock().unwrap().iter() {
+            let _ = self
+                .events
+                .send(CentralEvent::DeviceDiscovered(peripheral.id()));
+        }
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> Result<Vec<SimulatedPeripheral>> {
+        Ok(self.peripherals.lock().unwrap().clone())
+    }
+
+    async fn peripheral(&self, id: &PeripheralId) -> Result<SimulatedPeripheral> {
+        self.peripherals
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|p| p.id() == *id)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    async fn add_peripheral(&self, id: &PeripheralId) -> Result<SimulatedPeripheral> {
+        self.peripheral(id).await
+    }
+
+    async fn adapter_info(&self) -> Result<String> {
+        Ok("sim0 (simulated)".to_string())
+    }
+}
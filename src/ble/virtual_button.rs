@@ -0,0 +1,191 @@
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use bluer::adv::Advertisement;
+use bluer::gatt::local::{
+    Application, Characteristic, CharacteristicNotifier, CharacteristicNotify,
+    CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite, CharacteristicWriteMethod,
+    Service,
+};
+use futures::FutureExt;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use super::protocol::{Encoding, PROTOCOL_VERSION};
+use super::{HidStatus, EMULATED_SERVICE_UUID};
+use crate::app::BluetoothMessage;
+use crate::config::ConfigHandle;
+
+// impulses per `+`/`-` key, 1/24 of a rotation with default encoder config
+const ENCODER_STEP: i32 = 10;
+
+type Notifier = Arc<tokio::sync::Mutex<Option<CharacteristicNotifier>>>;
+
+/// H-Button emulated by BlueZ GATT server on `adapter` (default one when `None`).
+/// Serves the same characteristics as the firmware and reads keys from stdin until `q` or Ctrl-C
+pub async fn run(config: ConfigHandle, adapter: Option<String>) -> anyhow::Result<()> {
+    let device_config = config.get().device;
+    let session = bluer::Session::new().await?;
+    let adapter = match adapter {
+        Some(name) => session.adapter(&name)?,
+        None => session.default_adapter().await?,
+    };
+    adapter
+        .set_powered(true)
+        .await
+        .with_context(|| format!("failed to power on {}", adapter.name()))?;
+
+    let hid_status = Arc::new(Mutex::new(HidStatus::default()));
+    let notifier: Notifier = Arc::new(tokio::sync::Mutex::new(None));
+    // selected by the driver through protocol characteristic, back to JSON when it goes away
+    let encoding = Arc::new(Mutex::new(Encoding::Json));
+
+    let read_status = hid_status.clone();
+    let read_encoding = encoding.clone();
+    let write_status = hid_status.clone();
+    let write_encoding = encoding.clone();
+    let protocol_encoding = encoding.clone();
+    let session_notifier = notifier.clone();
+    let application = Application {
+        services: vec![Service {
+            uuid: EMULATED_SERVICE_UUID,
+            primary: true,
+            characteristics: vec![
+                Characteristic {
+                    uuid: device_config.notify_characteristic_uuid,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(move |_| {
+                            let encoding = *read_encoding.lock().unwrap();
+                            let value = encode(encoding, &read_status.lock().unwrap());
+                            async move { Ok(value) }.boxed()
+                        }),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(move |new_notifier| {
+                            let notifier = session_notifier.clone();
+                            async move {
+                                println!("Driver subscribed to notifications");
+                                *notifier.lock().await = Some(new_notifier);
+                            }
+                            .boxed()
+                        })),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: device_config.led_status_characteristic_uuid,
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        write_without_response: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(move |value, _| {
+                            // reported back in next notification, as firmware does
+                            let encoding = *write_encoding.lock().unwrap();
+                            match encoding.decode(&value) {
+                                Ok(BluetoothMessage::SetMicMuteIndicator(led_status)) => {
+                                    println!("LED: {led_status:?}");
+                                    write_status.lock().unwrap().led_status = led_status;
+                                }
+                                _ => println!("Unexpected LED message: {value:02x?}"),
+                            }
+                            async { Ok(()) }.boxed()
+                        })),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: device_config.protocol_characteristic_uuid,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(|_| async { Ok(vec![PROTOCOL_VERSION]) }.boxed()),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(move |value, _| {
+                            let result = match value[..] {
+                                [version] if (1..=PROTOCOL_VERSION).contains(&version) => {
+                                    println!("Driver selected binary protocol v{version}");
+                                    *protocol_encoding.lock().unwrap() = Encoding::Binary(version);
+                                    Ok(())
+                                }
+                                _ => Err(bluer::gatt::local::ReqError::NotSupported),
+                            };
+                            async move { result }.boxed()
+                        })),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let _application_handle = adapter.serve_gatt_application(application).await?;
+    let _advertisement_handle = adapter
+        .advertise(Advertisement {
+            service_uuids: [EMULATED_SERVICE_UUID].into_iter().collect(),
+            discoverable: Some(true),
+            local_name: Some(device_config.name_filter.clone()),
+            ..Default::default()
+        })
+        .await?;
+
+    println!(
+        "Virtual {} advertised on {} ({})",
+        device_config.name_filter,
+        adapter.name(),
+        adapter.address().await?
+    );
+    println!("Keys (followed by Enter): + turn right, - turn left, m press mute, q quit");
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = tokio::signal::ctrl_c() => None,
+        };
+        let Some(line) = line else { break };
+        for key in line.chars() {
+            let value = {
+                let mut hid_status = hid_status.lock().unwrap();
+                match key {
+                    '+' => hid_status.encoder_position += ENCODER_STEP,
+                    '-' => hid_status.encoder_position -= ENCODER_STEP,
+                    'm' => hid_status.mic_mute_button_press_count += 1,
+                    'q' => return Ok(()),
+                    _ => continue,
+                }
+                println!("{hid_status:?}");
+                encode(*encoding.lock().unwrap(), &hid_status)
+            };
+            if !notify(&notifier, value).await {
+                *encoding.lock().unwrap() = Encoding::Json;
+            }
+        }
+    }
+    Ok(())
+}
+
+// false when the driver is gone
+async fn notify(notifier: &Notifier, value: Vec<u8>) -> bool {
+    let mut notifier = notifier.lock().await;
+    let Some(session) = notifier.as_mut() else {
+        println!("Driver is not subscribed, notification dropped");
+        return false;
+    };
+    if let Err(e) = session.notify(value).await {
+        println!("Notification session ended: {e}");
+        *notifier = None;
+        return false;
+    }
+    true
+}
+
+fn encode(encoding: Encoding, hid_status: &HidStatus) -> Vec<u8> {
+    encoding.encode(&BluetoothMessage::HidStatus(hid_status.clone()))
+}
@@ -0,0 +1,437 @@
+use std::io::Write as _;
+use std::path::Path;
+use std::time::Duration;
+
+use btleplug::api::{BDAddr, CharPropFlags, Peripheral as _};
+use futures::StreamExt;
+
+#[cfg(target_os = "linux")]
+use crate::sound::{
+    backend::SoundBackendKind,
+    devices,
+    selection::{SelectedElement, SelectionStore},
+};
+use crate::{
+    app::BluetoothMessage,
+    ble::{
+        adapter::list_adapters,
+        connection::StateFile,
+        device_info::DeviceInfo,
+        find_characteristic,
+        led::led_characteristic,
+        ota::{self, FirmwareImage, OtaProgress},
+        pairing::{PairedDevice, PairingStore},
+        protocol::{firmware_protocol_version, Encoding},
+        BtlteManager, LedStatus,
+    },
+    config::ConfigHandle,
+    sound::{backend::Direction, sound_controller::SoundController},
+};
+
+use super::{Command, LedState};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Executes one-shot diagnostic command, `Command::Run` is handled by `App`
+pub async fn execute(command: Command, config: ConfigHandle) -> anyhow::Result<()> {
+    match command {
+        Command::Run(_) => unreachable!("run is handled by App"),
+        Command::Scan { duration } => scan(config, Duration::from_secs(duration)).await,
+        Command::Status => status(config).await,
+        Command::Monitor => monitor(config).await,
+        Command::Volume {
+            percent,
+            db,
+            balance,
+            capture,
+        } => volume(config, percent, db, balance, capture),
+        Command::Led { state } => led(config, state).await,
+        Command::Bind {
+            address,
+            add,
+            duration,
+        } => bind(config, address, add, Duration::from_secs(duration)).await,
+        Command::Forget { address } => forget(address),
+        Command::Adapters => adapters().await,
+        #[cfg(target_os = "linux")]
+        Command::Devices {
+            playback,
+            capture,
+            reset,
+        } => sound_devices(config, playback, capture, reset),
+        Command::Flash { file } => flash(config, &file).await,
+        #[cfg(all(target_os = "linux", feature = "virtual-button"))]
+        Command::VirtualButton { adapter } => {
+            crate::ble::virtual_button::run(config, adapter).await
+        }
+    }
+}
+
+async fn scan(config: ConfigHandle, duration: Duration) -> anyhow::Result<()> {
+    let manager = BtlteManager::new(config.clone()).await?;
+    println!("Scanning for {}s...", duration.as_secs());
+    let peripherals = manager.scan(duration).await?;
+    if peripherals.is_empty() {
+        println!("No {} found", config.get().device.name_filter);
+    }
+    for peripheral in peripherals {
+        let rssi = peripheral
+            .rssi
+            .map(|rssi| format!("{rssi} dBm"))
+            .unwrap_or_else(|| "unknown".to_string());
+        let bound = if peripheral.bound { "  (bound)" } else { "" };
+        println!(
+            "{}  {:<20} RSSI: {}{}",
+            peripheral.address, peripheral.local_name, rssi, bound
+        );
+    }
+    Ok(())
+}
+
+async fn status(config: ConfigHandle) -> anyhow::Result<()> {
+    match StateFile::open().read() {
+        Some(state) => println!("Driver: {state}"),
+        None => println!("Driver: not running"),
+    }
+
+    // printed before looking for the button, it doesn't need one; missing microphone doesn't
+    // hide the button either
+    let mut sound_controller = SoundController::new(config.clone())?;
+    match sound_controller.get_microphone_status() {
+        Ok(status) => println!("Microphone: {status:?}"),
+        Err(e) => println!("Microphone: {e:#}"),
+    }
+    for direction in [Direction::Playback, Direction::Capture] {
+        if let Err(e) = print_volume(&mut sound_controller, direction) {
+            println!("{direction:?} volume: {e:#}");
+        }
+    }
+
+    let mut manager = BtlteManager::new(config.clone()).await?;
+    let peripheral = manager.connect_first(CONNECT_TIMEOUT).await?;
+
+    let device_config = config.get().device;
+    // button is released even when reading it fails
+    let read = async {
+        let protocol_characteristic_uuid = device_config.protocol_characteristic_uuid;
+        let protocol_version =
+            firmware_protocol_version(&peripheral, protocol_characteristic_uuid).await?;
+        let device_info = DeviceInfo::read(&peripheral, protocol_version).await;
+        let encoding =
+            Encoding::select(&peripheral, protocol_characteristic_uuid, protocol_version).await?;
+        let characteristic = find_characteristic(
+            &peripheral,
+            device_config.notify_characteristic_uuid,
+            CharPropFlags::READ,
+        )?;
+        let data = peripheral.read(&characteristic).await?;
+        anyhow::Ok((protocol_version, device_info, encoding, data))
+    }
+    .await;
+    manager.release(&peripheral.id()).await?;
+    let (protocol_version, device_info, encoding, data) = read?;
+
+    println!("Device: {} ({encoding:?} encoding)", peripheral.address());
+    println!("{device_info}");
+    if protocol_version < device_config.min_protocol_version {
+        println!(
+            "Firmware is outdated, at least protocol {} is required",
+            device_config.min_protocol_version
+        );
+    }
+    match encoding.decode(&data) {
+        Ok(BluetoothMessage::HidStatus(hid_status)) => println!("{hid_status:#?}"),
+        Ok(msg) => println!("Unexpected message: {msg:?}"),
+        Err(e) => println!(
+            "Undecodable status {:?}: {e}",
+            String::from_utf8_lossy(&data)
+        ),
+    }
+    Ok(())
+}
+
+fn volume(
+    config: ConfigHandle,
+    percent: Option<f64>,
+    db: Option<f64>,
+    balance: Option<f64>,
+    capture: bool,
+) -> anyhow::Result<()> {
+    let direction = if capture {
+        Direction::Capture
+    } else {
+        Direction::Playback
+    };
+    let mut sound_controller = SoundController::new(config)?;
+    if let Some(balance) = balance {
+        sound_controller.set_balance(direction, balance)?;
+    }
+    if let Some(percent) = percent {
+        sound_controller.set_volume_percent(direction, percent)?;
+    }
+    if let Some(db) = db {
+        sound_controller.set_volume_db(direction, db)?;
+    }
+    print_volume(&mut sound_controller, direction)
+}
+
+fn print_volume(
+    sound_controller: &mut SoundController,
+    direction: Direction,
+) -> anyhow::Result<()> {
+    let name = match direction {
+        Direction::Playback => "Volume",
+        Direction::Capture => "Microphone gain",
+    };
+    let percent = sound_controller.volume_percent(direction)?;
+    let db = match sound_controller.volume_db(direction)? {
+        Some(db) => format!(", {db:.2} dB"),
+        None => String::new(),
+    };
+    let balance = sound_controller.balance(direction)?;
+    println!("{name}: {percent:.0}%{db}, balance {balance:.2}");
+    Ok(())
+}
+
+async fn monitor(config: ConfigHandle) -> anyhow::Result<()> {
+    let mut manager = BtlteManager::new(config.clone()).await?;
+    let peripheral = manager.connect_first(CONNECT_TIMEOUT).await?;
+
+    let device_config = config.get().device;
+    let encoding =
+        Encoding::negotiate(&peripheral, device_config.protocol_characteristic_uuid).await?;
+    let characteristic = find_characteristic(
+        &peripheral,
+        device_config.notify_characteristic_uuid,
+        CharPropFlags::NOTIFY,
+    )?;
+    let mut notifications = peripheral.notifications().await?;
+    peripheral.subscribe(&characteristic).await?;
+    println!(
+        "Monitoring {} ({encoding:?} encoding), press Ctrl-C to stop",
+        peripheral.address()
+    );
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            notification = notifications.next() => {
+                let Some(notification) = notification else {
+                    println!("Device disconnected");
+                    break;
+                };
+                match encoding.decode(&notification.value) {
+                    Ok(msg) => println!("{msg:?}"),
+                    Err(e) => println!(
+                        "Undecodable message {:?}: {e}",
+                        String::from_utf8_lossy(&notification.value)
+                    ),
+                }
+            }
+        }
+    }
+
+    peripheral.unsubscribe(&characteristic).await?;
+    manager.release(&peripheral.id()).await
+}
+
+async fn led(config: ConfigHandle, state: LedState) -> anyhow::Result<()> {
+    let mut manager = BtlteManager::new(config.clone()).await?;
+    let peripheral = manager.connect_first(CONNECT_TIMEOUT).await?;
+
+    let led_status = match state {
+        LedState::On => LedStatus::On,
+        LedState::Off => LedStatus::Off,
+    };
+    let device_config = config.get().device;
+    let encoding =
+        Encoding::negotiate(&peripheral, device_config.protocol_characteristic_uuid).await?;
+    let msg = encoding.encode(&BluetoothMessage::SetMicMuteIndicator(led_status));
+    let (characteristic, write_type) =
+        led_characteristic(&peripheral, device_config.led_status_characteristic_uuid)?;
+    peripheral.write(&characteristic, &msg, write_type).await?;
+    println!("Led turned {state:?}");
+
+    manager.release(&peripheral.id()).await
+}
+
+async fn flash(config: ConfigHandle, file: &Path) -> anyhow::Result<()> {
+    let image = FirmwareImage::load(file)?;
+    if StateFile::open().read().is_some() {
+        println!("Driver is running, it may interfere; use its tray menu or stop it first");
+    }
+    let mut manager = BtlteManager::new(config.clone()).await?;
+    let peripheral = manager.connect_first(CONNECT_TIMEOUT).await?;
+    println!(
+        "Flashing {} ({} bytes, version {}) to {}",
+        file.display(),
+        image.len(),
+        image.version().unwrap_or("unknown"),
+        peripheral.address()
+    );
+
+    let mut last_percent = None;
+    let version = ota::flash(
+        &peripheral,
+        config.get().device.ota_characteristic_uuid,
+        &image,
+        |progress| match progress {
+            OtaProgress::Started { offset, total } if offset > 0 => {
+                println!("Resuming from {offset}/{total} bytes")
+            }
+            OtaProgress::Started { .. } => {}
+            OtaProgress::Uploading { sent, total } => {
+                let percent = sent * 100 / total;
+                if last_percent != Some(percent) {
+                    last_percent = Some(percent);
+                    print!("\rUploading: {percent}% ({sent}/{total} bytes)");
+                    let _ = std::io::stdout().flush();
+                }
+            }
+            OtaProgress::Verifying => println!("\nVerifying"),
+            OtaProgress::Rebooting => println!("Waiting for the button to reboot"),
+        },
+    )
+    .await?;
+    println!(
+        "Button runs firmware {}",
+        version.as_deref().unwrap_or("of unknown version")
+    );
+
+    manager.release(&peripheral.id()).await
+}
+
+async fn bind(
+    config: ConfigHandle,
+    address: Option<BDAddr>,
+    add: bool,
+    duration: Duration,
+) -> anyhow::Result<()> {
+    let manager = BtlteManager::new(config.clone()).await?;
+    println!("Scanning for {}s...", duration.as_secs());
+    let peripherals = manager.scan(duration).await?;
+
+    // scan results are sorted by signal strength, so first one is the nearest
+    let peripheral = match address {
+        Some(address) => peripherals.into_iter().find(|p| p.address == address),
+        None => peripherals.into_iter().next(),
+    };
+    let Some(peripheral) = peripheral else {
+        anyhow::bail!("no {} found", config.get().device.name_filter);
+    };
+
+    let device = PairedDevice {
+        address: peripheral.address,
+        name: peripheral.local_name,
+    };
+    manager.pairing().bind(device.clone(), !add)?;
+    println!("Bound {}  {}", device.address, device.name);
+    Ok(())
+}
+
+fn forget(address: Option<BDAddr>) -> anyhow::Result<()> {
+    let removed = PairingStore::open().forget(address)?;
+    match (address, removed) {
+        (Some(address), 0) => println!("{address} was not bound"),
+        (_, 0) => println!("No bound buttons"),
+        (_, removed) => println!("Forgot {removed} button(s)"),
+    }
+    Ok(())
+}
+
+async fn adapters() -> anyhow::Result<()> {
+    let adapters = list_adapters().await?;
+    if adapters.is_empty() {
+        println!("No bluetooth adapters found");
+    }
+    for adapter in adapters {
+        let address = adapter
+            .address
+            .map(|address| address.to_string())
+            .unwrap_or_else(|| "unknown address".to_string());
+        println!("{:<6} {}  {}", adapter.name, address, adapter.info);
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sound_devices(
+    config: ConfigHandle,
+    playback: Option<String>,
+    capture: Option<String>,
+    reset: bool,
+) -> anyhow::Result<()> {
+    let store = SelectionStore::open();
+    for (direction, element) in [
+        (Direction::Playback, playback),
+        (Direction::Capture, capture),
+    ] {
+        if let Some(element) = element {
+            let Some((device, element)) = element.split_once('/') else {
+                anyhow::bail!("{element} is not DEVICE/ELEMENT");
+            };
+            let element = SelectedElement {
+                device: device.to_string(),
+                element: element.to_string(),
+            };
+            store.select(direction, Some(element))?;
+        } else if reset {
+            store.select(direction, None)?;
+        }
+    }
+
+    let mut sound_config = config.get().sound;
+    store.apply(&mut sound_config);
+    if sound_config.backend != SoundBackendKind::Alsa {
+        println!("Mixer elements are used by the alsa sound backend only");
+    }
+
+    let devices = devices::list_devices()?;
+    if devices.is_empty() {
+        println!("No sound cards found");
+    }
+    for device in devices {
+        println!("{:<8} {}", device.device, device.description);
+        // elements the driver uses, fallbacks included
+        let in_use: Vec<(&str, String)> = [
+            (Direction::Playback, "playback"),
+            (Direction::Capture, "capture"),
+        ]
+        .into_iter()
+        .filter_map(|(direction, label)| {
+            let (used_device, name) = sound_config.element(direction);
+            if used_device != device.device {
+                return None;
+            }
+            let element = devices::resolve(&device.elements, name, direction)?;
+            let picked = matches!(store.selected(direction), Ok(Some(_)));
+            let label = if picked {
+                format!("{label}, picked")
+            } else {
+                label.to_string()
+            };
+            Some((element, label))
+        })
+        .collect();
+        for element in &device.elements {
+            let controls = match (element.playback, element.capture) {
+                (true, true) => "playback, capture",
+                (true, false) => "playback",
+                (false, true) => "capture",
+                (false, false) => "",
+            };
+            let used: Vec<&str> = in_use
+                .iter()
+                .filter(|(name, _)| *name == element.name)
+                .map(|(_, label)| label.as_str())
+                .collect();
+            let used = if used.is_empty() {
+                String::new()
+            } else {
+                format!("  (used for {})", used.join(" and "))
+            };
+            println!("  {:<24} {controls:<18}{used}", element.name);
+        }
+    }
+    Ok(())
+}
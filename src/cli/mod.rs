@@ -0,0 +1,116 @@
+mod commands;
+
+use std::path::PathBuf;
+
+use btleplug::api::BDAddr;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+pub use self::commands::execute;
+
+#[derive(Parser, Debug)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Config file, defaults to `$XDG_CONFIG_HOME/h-button-driver/config.toml`
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    // `h-button-driver --headless` is the same as `h-button-driver run --headless`
+    #[command(flatten)]
+    pub run: RunArgs,
+}
+
+impl Cli {
+    /// Subcommand to execute, `run` when none was given
+    pub fn command(self) -> Command {
+        self.command.unwrap_or(Command::Run(self.run))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the driver (default)
+    Run(RunArgs),
+    /// List nearby H-Buttons with signal strength and address
+    Scan {
+        /// How long to scan, in seconds
+        #[arg(short, long, default_value_t = 5)]
+        duration: u64,
+    },
+    /// Print button status and microphone/volume state
+    Status,
+    /// Print messages received from the button until Ctrl-C
+    Monitor,
+    /// Print or set volume, the same way as the knob sees it
+    Volume {
+        /// Volume in percent of the mixer element range
+        #[arg(conflicts_with = "db")]
+        percent: Option<f64>,
+        /// Volume in dB, for elements with dB scale
+        #[arg(long, allow_negative_numbers = true)]
+        db: Option<f64>,
+        /// Balance from -1 (left only) to 1 (right only)
+        #[arg(long, allow_negative_numbers = true)]
+        balance: Option<f64>,
+        /// Microphone gain instead of playback volume
+        #[arg(long)]
+        capture: bool,
+    },
+    /// Turn mute indicator led on or off
+    Led { state: LedState },
+    /// Only connect to given button from now on, nearest one when no address is given
+    Bind {
+        address: Option<BDAddr>,
+        /// Keep previously bound buttons, instead of replacing them
+        #[arg(long)]
+        add: bool,
+        /// How long to scan, in seconds
+        #[arg(short, long, default_value_t = 5)]
+        duration: u64,
+    },
+    /// Forget bound button, all of them when no address is given
+    Forget { address: Option<BDAddr> },
+    /// List bluetooth adapters, to choose one with `device.adapter` in config
+    Adapters,
+    /// List sound cards and their mixer elements, or pick the ones to use instead of `[sound]`
+    /// config, as the tray does
+    #[cfg(target_os = "linux")]
+    Devices {
+        /// Playback element as `DEVICE/ELEMENT`, e.g. `hw:1/PCM`
+        #[arg(long, value_name = "DEVICE/ELEMENT")]
+        playback: Option<String>,
+        /// Capture element as `DEVICE/ELEMENT`, e.g. `hw:1/Mic`
+        #[arg(long, value_name = "DEVICE/ELEMENT")]
+        capture: Option<String>,
+        /// Forget picked elements, use the ones from config again
+        #[arg(long, conflicts_with_all = ["playback", "capture"])]
+        reset: bool,
+    },
+    /// Update button firmware over bluetooth, interrupted update is resumed when run again
+    Flash {
+        /// Firmware image, e.g. `build/h-button.bin` from ESP-IDF
+        file: PathBuf,
+    },
+    /// Emulate H-Button with BlueZ GATT server, to test the driver without hardware
+    #[cfg(all(target_os = "linux", feature = "virtual-button"))]
+    VirtualButton {
+        /// Adapter serving the button, e.g. `hci1`, must not be the one used by the driver
+        #[arg(long)]
+        adapter: Option<String>,
+    },
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Run without system tray
+    #[arg(long)]
+    pub headless: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LedState {
+    On,
+    Off,
+}
@@ -0,0 +1,27 @@
+// Deserializers for addresses in toml files, to be used with `deserialize_with`.
+// btleplug only parses borrowed strings, which toml doesn't give out.
+
+use btleplug::api::BDAddr;
+use serde::{de::Error, Deserialize, Deserializer};
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BDAddr, D::Error> {
+    let address = String::deserialize(deserializer)?;
+    BDAddr::from_str_delim(&address).map_err(D::Error::custom)
+}
+
+pub fn deserialize_vec<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<BDAddr>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|address| BDAddr::from_str_delim(address).map_err(D::Error::custom))
+        .collect()
+}
+
+pub fn deserialize_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<BDAddr>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|address| BDAddr::from_str_delim(&address).map_err(D::Error::custom))
+        .transpose()
+}
@@ -0,0 +1,747 @@
+pub(crate) mod address;
+mod watcher;
+
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use btleplug::api::BDAddr;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::actions::{gestures::GestureTimings, Action, Input};
+use crate::ble::protocol::PROTOCOL_VERSION;
+use crate::sound::{
+    backend::{Direction, SoundBackendKind},
+    volume_curve::VolumeCurve,
+};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub device: DeviceConfig,
+    pub encoder: EncoderConfig,
+    pub sound: SoundConfig,
+    pub gestures: GestureConfig,
+    /// actions bound to inputs, inputs not bound anywhere follow `device.roles`
+    pub bindings: Vec<BindingConfig>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// peripherals whose local name contains this string are connected
+    pub name_filter: String,
+    /// adapter interface name (`hci1`) or address, first adapter found when not set
+    pub adapter: Option<String>,
+    /// addresses always allowed to connect, in addition to ones bound with `bind`
+    #[serde(deserialize_with = "address::deserialize_vec")]
+    pub allowlist: Vec<BDAddr>,
+    /// what the knob of given button controls, `output_volume` for buttons not listed.
+    /// Buttons listed here are allowed to connect, like the allowlisted ones.
+    pub roles: Vec<DeviceRoleConfig>,
+    pub notify_characteristic_uuid: Uuid,
+    pub led_status_characteristic_uuid: Uuid,
+    /// firmware supporting binary encoding exposes it, JSON is used with firmware that doesn't
+    pub protocol_characteristic_uuid: Uuid,
+    /// firmware images are uploaded through it by `flash`
+    pub ota_characteristic_uuid: Uuid,
+    /// percent of battery charge below which desktop notification is shown, 0 disables it
+    pub low_battery_threshold: u8,
+    /// binary protocol version firmware has to speak, 0 accepts JSON-only firmware
+    pub min_protocol_version: u8,
+    /// what happens with firmware older than `min_protocol_version`
+    pub old_firmware: OldFirmwarePolicy,
+    /// how often led state is read back from the button and corrected, in milliseconds
+    pub led_reconcile_ms: u64,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            name_filter: "H-Button".to_string(),
+            adapter: None,
+            allowlist: Vec::new(),
+            roles: Vec::new(),
+            notify_characteristic_uuid: Uuid::from_u128(0xa3c87500_8ed3_4bdf_8a39_a01bebede295),
+            led_status_characteristic_uuid: Uuid::from_u128(0x3c9a3f00_8ed3_4bdf_8a39_a01bebede295),
+            protocol_characteristic_uuid: Uuid::from_u128(0x5d2e7c00_8ed3_4bdf_8a39_a01bebede295),
+            ota_characteristic_uuid: Uuid::from_u128(0x7b1e4a00_8ed3_4bdf_8a39_a01bebede295),
+            low_battery_threshold: 20,
+            min_protocol_version: 0,
+            old_firmware: OldFirmwarePolicy::default(),
+            led_reconcile_ms: 5000,
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn role(&self, address: &BDAddr) -> DeviceRole {
+        self.roles
+            .iter()
+            .find(|r| r.address == *address)
+            .map(|r| r.role)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceRole {
+    #[default]
+    OutputVolume,
+    MicGain,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum OldFirmwarePolicy {
+    /// connect anyway, warning is logged and shown in tray
+    #[default]
+    Warn,
+    /// disconnect and don't reconnect until restart or `forget`
+    Refuse,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceRoleConfig {
+    #[serde(deserialize_with = "address::deserialize")]
+    pub address: BDAddr,
+    pub role: DeviceRole,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BindingConfig {
+    /// button the binding is for, buttons without their own binding use one without address
+    #[serde(deserialize_with = "address::deserialize_option")]
+    pub address: Option<BDAddr>,
+    pub clockwise: Option<Action>,
+    pub counter_clockwise: Option<Action>,
+    pub press: Option<Action>,
+    /// gestures below need firmware reporting button edges
+    pub double_press: Option<Action>,
+    pub long_press: Option<Action>,
+    pub hold: Option<Action>,
+}
+
+impl BindingConfig {
+    fn action(&self, input: Input) -> Option<&Action> {
+        match input {
+            Input::Clockwise => self.clockwise.as_ref(),
+            Input::CounterClockwise => self.counter_clockwise.as_ref(),
+            Input::Press => self.press.as_ref(),
+            Input::DoublePress => self.double_press.as_ref(),
+            Input::LongPress => self.long_press.as_ref(),
+            Input::Hold => self.hold.as_ref(),
+        }
+    }
+}
+
+/// How long the mute button has to be held for gestures, in milliseconds
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct GestureConfig {
+    pub hold_ms: u64,
+    pub long_press_ms: u64,
+    /// second press has to come this soon after the first one is released, single press is
+    /// delayed by it when double press is bound
+    pub double_press_ms: u64,
+}
+
+impl Default for GestureConfig {
+    fn default() -> Self {
+        Self {
+            hold_ms: 300,
+            long_press_ms: 800,
+            double_press_ms: 250,
+        }
+    }
+}
+
+impl GestureConfig {
+    pub fn timings(&self) -> GestureTimings {
+        GestureTimings {
+            hold: Some(Duration::from_millis(self.hold_ms)),
+            long_press: Duration::from_millis(self.long_press_ms),
+            double_press: Duration::from_millis(self.double_press_ms),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderConfig {
+    pub impulses_per_rotation: u32,
+    /// knob resolution, scaled onto the mixer element's own range; one full rotation sweeps
+    /// all of it
+    pub volume_range: i64,
+    pub curve: VolumeCurve,
+    /// attenuation at the lowest knob position above silence, `db` curve only
+    pub db_range: f64,
+    /// fast turns move volume up to this many times further, 1 disables acceleration
+    pub acceleration: f64,
+    /// rotations per second at which acceleration is at its maximum
+    pub acceleration_speed: f64,
+    /// impulses from one detent to the next, 0 for encoders without detents
+    pub detent_impulses: u32,
+    /// volume snaps to steps, this many per detent, when `detent_impulses` is set
+    pub steps_per_detent: u32,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            impulses_per_rotation: 240,
+            volume_range: 65536,
+            curve: VolumeCurve::default(),
+            db_range: 60.0,
+            acceleration: 3.0,
+            acceleration_speed: 2.0,
+            detent_impulses: 0,
+            steps_per_detent: 1,
+        }
+    }
+}
+
+impl EncoderConfig {
+    /// Volume steps in one rotation, `None` when volume doesn't snap to detents
+    pub fn steps_per_rotation(&self) -> Option<f64> {
+        if self.detent_impulses == 0 {
+            return None;
+        }
+        let detents = self.impulses_per_rotation as f64 / self.detent_impulses as f64;
+        Some(detents * self.steps_per_detent as f64)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct SoundConfig {
+    pub backend: SoundBackendKind,
+    /// ALSA mixer device, e.g. `default` or `hw:1`
+    pub mixer_device: String,
+    /// mixer device of the playback element, `mixer_device` when not set
+    pub playback_device: Option<String>,
+    /// mixer device of the capture element, `mixer_device` when not set
+    pub capture_device: Option<String>,
+    pub playback_element: String,
+    pub capture_element: String,
+}
+
+impl SoundConfig {
+    /// ALSA mixer device and simple element name of `direction`
+    pub fn element(&self, direction: Direction) -> (&str, &str) {
+        match direction {
+            Direction::Playback => (
+                self.playback_device
+                    .as_deref()
+                    .unwrap_or(&self.mixer_device),
+                &self.playback_element,
+            ),
+            Direction::Capture => (
+                self.capture_device.as_deref().unwrap_or(&self.mixer_device),
+                &self.capture_element,
+            ),
+        }
+    }
+}
+
+impl Default for SoundConfig {
+    fn default() -> Self {
+        Self {
+            backend: SoundBackendKind::default(),
+            mixer_device: "default".to_string(),
+            playback_device: None,
+            capture_device: None,
+            playback_element: "Master".to_string(),
+            capture_element: "Capture".to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, io::Error),
+    Parse(PathBuf, toml::de::Error),
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => write!(f, "cannot read {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "invalid {}: {e}", path.display()),
+            ConfigError::Invalid { field, reason } => write!(f, "invalid `{field}`: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Read(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+impl Config {
+    /// Action bound to `input` of given button: its own binding first, then binding without
+    /// address, then knob follows the role and press toggles microphone mute. Other gestures
+    /// do nothing unless bound.
+    pub fn action(&self, address: &BDAddr, input: Input) -> Option<Action> {
+        let bound = |address: Option<BDAddr>| {
+            self.bindings
+                .iter()
+                .filter(|binding| binding.address == address)
+                .find_map(|binding| binding.action(input))
+        };
+        if let Some(action) = bound(Some(*address)).or_else(|| bound(None)) {
+            return Some(action.clone());
+        }
+        match (input, self.device.role(address)) {
+            (Input::Press, _) => Some(Action::MicMuteToggle),
+            (Input::Clockwise | Input::CounterClockwise, DeviceRole::OutputVolume) => {
+                Some(Action::OutputVolume)
+            }
+            (Input::Clockwise | Input::CounterClockwise, DeviceRole::MicGain) => {
+                Some(Action::MicGain)
+            }
+            _ => None,
+        }
+    }
+
+    /// `$XDG_CONFIG_HOME/h-button-driver/config.toml` on Linux
+    pub fn default_path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(CONFIG_FILE_NAME))
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+        let config: Config =
+            toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| {
+            Err(ConfigError::Invalid {
+                field,
+                reason: reason.to_string(),
+            })
+        };
+
+        if self.device.name_filter.trim().is_empty() {
+            return invalid("device.name_filter", "must not be empty");
+        }
+        if self.device.notify_characteristic_uuid == self.device.led_status_characteristic_uuid {
+            return invalid(
+                "device.led_status_characteristic_uuid",
+                "must differ from device.notify_characteristic_uuid",
+            );
+        }
+        if [
+            self.device.notify_characteristic_uuid,
+            self.device.led_status_characteristic_uuid,
+        ]
+        .contains(&self.device.protocol_characteristic_uuid)
+        {
+            return invalid(
+                "device.protocol_characteristic_uuid",
+                "must differ from notify and led status characteristics",
+            );
+        }
+        if [
+            self.device.notify_characteristic_uuid,
+            self.device.led_status_characteristic_uuid,
+            self.device.protocol_characteristic_uuid,
+        ]
+        .contains(&self.device.ota_characteristic_uuid)
+        {
+            return invalid(
+                "device.ota_characteristic_uuid",
+                "must differ from other characteristics",
+            );
+        }
+        if self.device.low_battery_threshold > 100 {
+            return invalid(
+                "device.low_battery_threshold",
+                "must be a percent, 0 to 100",
+            );
+        }
+        if self.device.min_protocol_version > PROTOCOL_VERSION {
+            return invalid(
+                "device.min_protocol_version",
+                &format!("this driver speaks protocol {PROTOCOL_VERSION} at most"),
+            );
+        }
+        if self.device.led_reconcile_ms == 0 {
+            return invalid("device.led_reconcile_ms", "must be greater than 0");
+        }
+        if self
+            .device
+            .adapter
+            .as_ref()
+            .is_some_and(|adapter| adapter.trim().is_empty())
+        {
+            return invalid(
+                "device.adapter",
+                "must not be empty, remove it to use any adapter",
+            );
+        }
+        for (i, role) in self.device.roles.iter().enumerate() {
+            if self.device.roles[..i]
+                .iter()
+                .any(|r| r.address == role.address)
+            {
+                return invalid("device.roles", &format!("{} listed twice", role.address));
+            }
+        }
+        for (i, binding) in self.bindings.iter().enumerate() {
+            if self.bindings[..i]
+                .iter()
+                .any(|b| b.address == binding.address)
+            {
+                let button = match binding.address {
+                    Some(address) => address.to_string(),
+                    None => "binding without address".to_string(),
+                };
+                return invalid("bindings", &format!("{button} listed twice"));
+            }
+            let knob = [&binding.clockwise, &binding.counter_clockwise];
+            let button = [
+                &binding.press,
+                &binding.double_press,
+                &binding.long_press,
+                &binding.hold,
+            ];
+            if knob.iter().chain(&button).filter_map(|action| action.as_ref()).any(
+                |action| matches!(action, Action::Command(command) if command.trim().is_empty()),
+            ) {
+                return invalid("bindings", "command must not be empty");
+            }
+            if button
+                .iter()
+                .filter_map(|action| action.as_ref())
+                .any(Action::is_knob)
+            {
+                return invalid("bindings", "button can't be bound to a knob action");
+            }
+            // releasing `hold` is what mutes again
+            if knob
+                .iter()
+                .chain(&button[..3])
+                .any(|action| action.as_ref() == Some(&Action::PushToTalk))
+            {
+                return invalid("bindings", "push_to_talk can be bound to hold only");
+            }
+        }
+        if self.gestures.hold_ms > self.gestures.long_press_ms {
+            return invalid(
+                "gestures.hold_ms",
+                "must not be longer than gestures.long_press_ms",
+            );
+        }
+        if self.encoder.impulses_per_rotation == 0 {
+            return invalid("encoder.impulses_per_rotation", "must be greater than 0");
+        }
+        if self.encoder.volume_range <= 0 {
+            return invalid("encoder.volume_range", "must be greater than 0");
+        }
+        if self.encoder.db_range.is_nan() || self.encoder.db_range <= 0.0 {
+            return invalid("encoder.db_range", "must be greater than 0");
+        }
+        if self.encoder.acceleration.is_nan() || self.encoder.acceleration < 1.0 {
+            return invalid("encoder.acceleration", "must be at least 1");
+        }
+        if self.encoder.acceleration_speed.is_nan() || self.encoder.acceleration_speed <= 0.0 {
+            return invalid("encoder.acceleration_speed", "must be greater than 0");
+        }
+        if self.encoder.detent_impulses > self.encoder.impulses_per_rotation {
+            return invalid(
+                "encoder.detent_impulses",
+                "must not be more than encoder.impulses_per_rotation",
+            );
+        }
+        if self.encoder.steps_per_detent == 0 {
+            return invalid("encoder.steps_per_detent", "must be greater than 0");
+        }
+        for (field, value) in [
+            ("sound.mixer_device", &self.sound.mixer_device),
+            ("sound.playback_element", &self.sound.playback_element),
+            ("sound.capture_element", &self.sound.capture_element),
+        ]
+        .into_iter()
+        .chain(
+            [
+                ("sound.playback_device", &self.sound.playback_device),
+                ("sound.capture_device", &self.sound.capture_device),
+            ]
+            .into_iter()
+            .filter_map(|(field, value)| Some((field, value.as_ref()?))),
+        ) {
+            if value.trim().is_empty() {
+                return invalid(field, "must not be empty");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shared, live reloaded configuration.
+/// Consumers should call `get` each time they need a value instead of caching it.
+#[derive(Clone, Debug)]
+pub struct ConfigHandle {
+    path: Option<PathBuf>,
+    config: Arc<RwLock<Config>>,
+}
+
+impl ConfigHandle {
+    /// Loads config from `path` or from the default location.
+    /// Missing file at the default location is not an error, defaults are used then.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, ConfigError> {
+        let explicit = path.is_some();
+        let path = path.or_else(Config::default_path);
+        let config = match &path {
+            Some(path) if explicit || path.exists() => {
+                info!("Using config file {}", path.display());
+                Config::load(path)?
+            }
+            _ => {
+                // path is still kept, so the file is picked up once created
+                info!("No config file found, using defaults");
+                Config::default()
+            }
+        };
+        Ok(Self::new(path, config))
+    }
+
+    pub fn new(path: Option<PathBuf>, config: Config) -> Self {
+        Self {
+            path,
+            config: Arc::new(RwLock::new(config)),
+        }
+    }
+
+    pub fn get(&self) -> Config {
+        self.config.read().unwrap().clone()
+    }
+
+    fn reload(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        if !path.exists() {
+            return;
+        }
+        match Config::load(path) {
+            Ok(config) => {
+                let mut current = self.config.write().unwrap();
+                if *current == config {
+                    return;
+                }
+                if current.sound.backend != config.sound.backend {
+                    warn!("Sound backend change takes effect after restart");
+                }
+                info!("Config reloaded from {}", path.display());
+                *current = config;
+            }
+            Err(e) => error!("Config not reloaded, keeping previous one: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUTTON: [u8; 6] = [0x24, 0x0a, 0xc4, 0x00, 0x00, 0x01];
+
+    // `field` of the config changed by `change` is reported as invalid
+    fn assert_rejected(field: &str, change: impl FnOnce(&mut Config)) {
+        let mut config = Config::default();
+        change(&mut config);
+        match config.validate() {
+            Err(ConfigError::Invalid { field: invalid, .. }) => assert_eq!(invalid, field),
+            result => panic!("{field}: expected to be invalid, got {result:?}"),
+        }
+    }
+
+    fn binding(change: impl FnOnce(&mut BindingConfig)) -> impl FnOnce(&mut Config) {
+        move |config| {
+            let mut binding = BindingConfig::default();
+            change(&mut binding);
+            config.bindings.push(binding);
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        Config::default().validate().unwrap();
+    }
+
+    #[test]
+    fn partial_config_falls_back_to_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+            [device]
+            name_filter = "Knob"
+
+            [encoder]
+            acceleration = 1.0
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+
+        assert_eq!(
+            config,
+            Config {
+                device: DeviceConfig {
+                    name_filter: "Knob".to_string(),
+                    ..Default::default()
+                },
+                encoder: EncoderConfig {
+                    acceleration: 1.0,
+                    ..Default::default()
+                },
+                ..Default::default()
+            }
+        );
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn allowlist_is_read() {
+        let config: Config =
+            toml::from_str("[device]\nallowlist = [\"24:0A:C4:00:00:01\"]").unwrap();
+        assert_eq!(config.device.allowlist, vec![BUTTON.into()]);
+        assert!(toml::from_str::<Config>("[device]\nallowlist = [\"24:0A:C4\"]").is_err());
+    }
+
+    #[test]
+    fn roles_are_read() {
+        let config: Config = toml::from_str(
+            r#"
+            [device]
+            roles = [{ address = "24:0A:C4:00:00:01", role = "mic_gain" }]
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.device.role(&BUTTON.into()), DeviceRole::MicGain);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(toml::from_str::<Config>("[device]\nname = \"Knob\"").is_err());
+    }
+
+    #[test]
+    fn invalid_device_is_rejected() {
+        assert_rejected("device.name_filter", |c| {
+            c.device.name_filter = " ".to_string()
+        });
+        assert_rejected("device.led_status_characteristic_uuid", |c| {
+            c.device.led_status_characteristic_uuid = c.device.notify_characteristic_uuid
+        });
+        assert_rejected("device.protocol_characteristic_uuid", |c| {
+            c.device.protocol_characteristic_uuid = c.device.led_status_characteristic_uuid
+        });
+        assert_rejected("device.ota_characteristic_uuid", |c| {
+            c.device.ota_characteristic_uuid = c.device.protocol_characteristic_uuid
+        });
+        assert_rejected("device.low_battery_threshold", |c| {
+            c.device.low_battery_threshold = 101
+        });
+        assert_rejected("device.min_protocol_version", |c| {
+            c.device.min_protocol_version = PROTOCOL_VERSION + 1
+        });
+        assert_rejected("device.led_reconcile_ms", |c| c.device.led_reconcile_ms = 0);
+        assert_rejected("device.adapter", |c| c.device.adapter = Some(String::new()));
+        assert_rejected("device.roles", |c| {
+            let role = DeviceRoleConfig {
+                address: BUTTON.into(),
+                role: DeviceRole::MicGain,
+            };
+            c.device.roles = vec![role.clone(), role];
+        });
+    }
+
+    #[test]
+    fn invalid_bindings_are_rejected() {
+        assert_rejected("bindings", |c| {
+            c.bindings = vec![BindingConfig::default(), BindingConfig::default()]
+        });
+        assert_rejected(
+            "bindings",
+            binding(|b| b.press = Some(Action::Command(" ".to_string()))),
+        );
+        assert_rejected(
+            "bindings",
+            binding(|b| b.double_press = Some(Action::OutputVolume)),
+        );
+        assert_rejected(
+            "bindings",
+            binding(|b| b.long_press = Some(Action::PushToTalk)),
+        );
+        assert_rejected(
+            "bindings",
+            binding(|b| b.clockwise = Some(Action::PushToTalk)),
+        );
+        // same binding on its own is fine
+        let mut config = Config::default();
+        binding(|b| b.hold = Some(Action::PushToTalk))(&mut config);
+        config.validate().unwrap();
+    }
+
+    #[test]
+    fn invalid_gestures_and_encoder_are_rejected() {
+        assert_rejected("gestures.hold_ms", |c| c.gestures.hold_ms = 1000);
+        assert_rejected("encoder.impulses_per_rotation", |c| {
+            c.encoder.impulses_per_rotation = 0
+        });
+        assert_rejected("encoder.volume_range", |c| c.encoder.volume_range = 0);
+        assert_rejected("encoder.db_range", |c| c.encoder.db_range = f64::NAN);
+        assert_rejected("encoder.db_range", |c| c.encoder.db_range = -10.0);
+        assert_rejected("encoder.acceleration", |c| c.encoder.acceleration = 0.5);
+        assert_rejected("encoder.acceleration_speed", |c| {
+            c.encoder.acceleration_speed = 0.0
+        });
+        assert_rejected("encoder.detent_impulses", |c| {
+            c.encoder.detent_impulses = c.encoder.impulses_per_rotation + 1
+        });
+        assert_rejected("encoder.steps_per_detent", |c| {
+            c.encoder.steps_per_detent = 0
+        });
+    }
+
+    #[test]
+    fn empty_sound_names_are_rejected() {
+        assert_rejected("sound.mixer_device", |c| {
+            c.sound.mixer_device = String::new()
+        });
+        assert_rejected("sound.playback_element", |c| {
+            c.sound.playback_element = " ".to_string()
+        });
+        assert_rejected("sound.capture_element", |c| {
+            c.sound.capture_element = String::new()
+        });
+        assert_rejected("sound.playback_device", |c| {
+            c.sound.playback_device = Some(String::new())
+        });
+        assert_rejected("sound.capture_device", |c| {
+            c.sound.capture_device = Some(" ".to_string())
+        });
+    }
+}
@@ -0,0 +1,68 @@
+use std::fs;
+
+use anyhow::Context;
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use super::ConfigHandle;
+
+impl ConfigHandle {
+    /// Reloads config whenever the file changes, until returned watcher is dropped.
+    /// Parent directory is watched, as editors usually replace the file instead of writing to it.
+    /// It's created when missing, e.g. on fresh install, so the file is picked up once created.
+    pub fn watch(&self) -> anyhow::Result<Option<RecommendedWatcher>> {
+        let Some(path) = self.path.clone() else {
+            return Ok(None);
+        };
+        let Some(dir) = path.parent().map(|dir| dir.to_path_buf()) else {
+            return Ok(None);
+        };
+        fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir.display()))?;
+
+        let handle = self.clone();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+                Ok(event) => {
+                    let is_change = matches!(
+                        event.kind,
+                        EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                    );
+                    if is_change && event.paths.contains(&path) {
+                        handle.reload();
+                    }
+                }
+                Err(e) => warn!("Config watcher error: {e:?}"),
+            })?;
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+        Ok(Some(watcher))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn config_created_later_is_loaded() {
+        let dir = std::env::temp_dir()
+            .join(format!("h-button-config-{}", std::process::id()))
+            .join(env!("CARGO_PKG_NAME"));
+        let path = dir.join("config.toml");
+        // as on fresh install, without config directory
+        let handle = ConfigHandle::new(Some(path.clone()), Config::default());
+        let _watcher = handle
+            .watch()
+            .unwrap()
+            .expect("config directory is watched");
+        fs::write(&path, "[device]\nname_filter = \"Knob\"\n").unwrap();
+
+        let loaded = (0..100).any(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            handle.get().device.name_filter == "Knob"
+        });
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+        assert!(loaded, "config not reloaded within 1s");
+    }
+}
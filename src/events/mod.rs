@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use btleplug::api::BDAddr;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::Instant;
+
+use crate::actions::gestures::Gesture;
+use crate::ble::{device_info::DeviceInfo, LedStatus};
+use crate::sound::sound_controller::MicrophoneStatus;
+
+// events are small and handled right away, a subscriber lagging this much is stuck
+const EVENT_BUS_CAPACITY: usize = 256;
+
+/// Everything subsystems tell each other, address tells which button it is about
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// button is connected and its notifications are started
+    DeviceConnected {
+        address: BDAddr,
+        device_info: DeviceInfo,
+    },
+    DeviceDisconnected {
+        address: BDAddr,
+    },
+    /// encoder turned by `impulses` since the previous report, negative is counterclockwise,
+    /// `at` is when the report was received
+    EncoderDelta {
+        address: BDAddr,
+        impulses: i32,
+        at: Instant,
+    },
+    /// press count went up, firmware reporting button edges doesn't cause it
+    MutePressed {
+        address: BDAddr,
+    },
+    /// mute button went down or up, `timestamp` is taken on the button's clock
+    ButtonEdge {
+        address: BDAddr,
+        pressed: bool,
+        timestamp: Duration,
+    },
+    /// recognized from `ButtonEdge`s
+    Gesture {
+        address: BDAddr,
+        gesture: Gesture,
+    },
+    /// percent of charge, once on connect and then on every change
+    BatteryLevel {
+        address: BDAddr,
+        level: u8,
+    },
+    /// button misbehaves, e.g. keeps sending undecodable messages, `None` when it's over
+    DeviceWarning {
+        address: BDAddr,
+        warning: Option<String>,
+    },
+    /// microphone was (un)muted, by the button or by other app
+    MicStateChanged(MicrophoneStatus),
+    /// mute indicator to show on the button, all connected buttons when `address` is `None`
+    LedCommand {
+        address: Option<BDAddr>,
+        led_status: LedStatus,
+    },
+}
+
+/// Broadcast channel connecting bluetooth, sound and tray, every subscriber gets every event
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(EVENT_BUS_CAPACITY).0,
+        }
+    }
+
+    pub fn publish(&self, event: Event) {
+        if let Err(broadcast::error::SendError(event)) = self.sender.send(event) {
+            trace!("Nobody listens to {event:?}");
+        }
+    }
+
+    #[cfg(test)]
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    /// Receives events published from now on
+    pub fn subscribe(&self) -> EventReceiver {
+        EventReceiver {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+pub struct EventReceiver {
+    receiver: broadcast::Receiver<Event>,
+}
+
+impl EventReceiver {
+    /// Next event, `None` once every `EventBus` is dropped. Events missed by a lagging
+    /// subscriber are skipped.
+    pub async fn recv(&mut self) -> Option<Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => warn!("Missed {missed} events, handling is slow"),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// Logs every event, makes the flow between subsystems easy to follow
+pub async fn log_events(mut events: EventReceiver) {
+    while let Some(event) = events.recv().await {
+        debug!("Event: {event:?}");
+    }
+}
@@ -1,10 +1,18 @@
+mod actions; // what encoder and button inputs do
 mod app; // glue code between bluetooth, sound and tray
 
 mod ble; // bluetooth related code
+mod cli; // command line interface
+mod config; // config file handling
+mod events; // event bus connecting the subsystems
 mod sound; // sound related code
+#[cfg(feature = "tray")]
 mod tray; // tray related code
 
 use app::App;
+use clap::Parser;
+use cli::{Cli, Command};
+use config::ConfigHandle;
 use std::error::Error;
 
 extern crate pretty_env_logger;
@@ -15,13 +23,24 @@ extern crate log;
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     pretty_env_logger::init();
+    let cli = Cli::parse();
+    let config = ConfigHandle::load(cli.config.clone())?;
+    let args = match cli.command() {
+        Command::Run(args) => args,
+        command => return Ok(cli::execute(command, config).await?),
+    };
+
     let do_something = async {
         println!("Initialized tokio runtime");
     };
     do_something.await;
+    #[cfg(feature = "tray")]
     tauri::async_runtime::set(tokio::runtime::Handle::current());
 
-    let mut core = App::new();
-    core.run().await?;
+    // without tray there is nothing to block on besides the daemon itself
+    let headless = !cfg!(feature = "tray") || args.headless;
+
+    let mut core = App::new(config)?;
+    core.run(headless).await?;
     Ok(())
 }
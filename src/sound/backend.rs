@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::sound_controller::MicrophoneStatus;
+use crate::config::ConfigHandle;
+
+pub type OnMicrophoneChangeCallback = Arc<dyn Fn(MicrophoneStatus) + Send + Sync>;
+
+/// Which volume: output one, or microphone gain
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Playback,
+    Capture,
+}
+
+/// Where a channel plays, balance moves volume between left and right ones. Mono channels
+/// and the ones in the middle (center, LFE) are `Center`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Left,
+    Right,
+    Center,
+}
+
+/// Operations every audio backend (ALSA, PulseAudio, mock, ...) has to provide.
+/// `SoundController` only talks to the sound system through this trait.
+pub trait SoundBackend: Send {
+    fn get_microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus>;
+
+    fn mute_mic(&mut self) -> anyhow::Result<()>;
+
+    fn unmute_mic(&mut self) -> anyhow::Result<()>;
+
+    fn toggle_microphone_mute(&mut self) -> anyhow::Result<()> {
+        match self.get_microphone_status()? {
+            MicrophoneStatus::Muted => self.unmute_mic(),
+            MicrophoneStatus::Unmuted => self.mute_mic(),
+        }
+    }
+
+    /// `(min, max)` raw volume of the element, every element has its own
+    fn volume_range(&mut self, direction: Direction) -> anyhow::Result<(i64, i64)>;
+
+    /// Raw volume of every channel of the element
+    fn channel_volumes(&mut self, direction: Direction) -> anyhow::Result<Vec<(Channel, i64)>>;
+
+    /// Sets raw volume of every channel, in the order `channel_volumes` returns them
+    fn set_channel_volumes(&mut self, direction: Direction, volumes: &[i64]) -> anyhow::Result<()>;
+
+    /// Attenuation of `raw` volume in dB, `None` when the element has no dB scale
+    fn volume_db(&mut self, _direction: Direction, _raw: i64) -> anyhow::Result<Option<f64>> {
+        Ok(None)
+    }
+
+    /// Raw volume closest to `db`, `None` when the element has no dB scale
+    fn db_volume(&mut self, _direction: Direction, _db: f64) -> anyhow::Result<Option<i64>> {
+        Ok(None)
+    }
+
+    /// Registers callback fired when microphone status is changed outside of this backend
+    fn subscribe(&mut self, _on_change: OnMicrophoneChangeCallback) -> anyhow::Result<()> {
+        anyhow::bail!("change notifications are not supported by this sound backend")
+    }
+}
+
+/// Default is the native backend of the platform, pulse when built with it
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum SoundBackendKind {
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(not(feature = "pulseaudio"), default)]
+    Alsa,
+    #[cfg(all(target_os = "linux", feature = "pulseaudio"))]
+    #[default]
+    Pulse,
+    #[cfg(target_os = "macos")]
+    #[default]
+    CoreAudio,
+    #[cfg(target_os = "windows")]
+    #[default]
+    Wasapi,
+    // platforms without a native backend
+    #[cfg_attr(
+        not(any(target_os = "linux", target_os = "macos", target_os = "windows")),
+        default
+    )]
+    Mock,
+}
+
+impl SoundBackendKind {
+    pub fn create(self, config: ConfigHandle) -> anyhow::Result<Box<dyn SoundBackend>> {
+        let backend: Box<dyn SoundBackend> = match self {
+            #[cfg(target_os = "linux")]
+            SoundBackendKind::Alsa => Box::new(super::linux::LinuxSoundController::new(config)?),
+            #[cfg(all(target_os = "linux", feature = "pulseaudio"))]
+            SoundBackendKind::Pulse => Box::new(super::pulse::PulseSoundController::new()?),
+            #[cfg(target_os = "macos")]
+            SoundBackendKind::CoreAudio => Box::new(super::macos::MacOsSoundController::new()),
+            #[cfg(target_os = "windows")]
+            SoundBackendKind::Wasapi => Box::new(super::windows::WindowsSoundController::new()),
+            SoundBackendKind::Mock => Box::new(super::mock::MockSoundBackend::new()),
+        };
+        Ok(backend)
+    }
+}
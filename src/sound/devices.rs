@@ -0,0 +1,182 @@
+use alsa::{
+    card,
+    mixer::{Selem, SelemId},
+    Mixer,
+};
+use anyhow::Context;
+
+use super::backend::Direction;
+
+// tried in this order when the configured element is missing, USB headsets often have only
+// `PCM`/`Headset` and `Mic`
+const PLAYBACK_FALLBACKS: &[&str] = &["Master", "PCM", "Headset", "Headphone", "Speaker"];
+const CAPTURE_FALLBACKS: &[&str] = &["Capture", "Mic", "Headset", "Internal Mic"];
+
+/// Simple mixer element and what it can control
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MixerElement {
+    pub name: String,
+    /// has playback volume
+    pub playback: bool,
+    /// has capture volume or capture switch, so it can mute a microphone
+    pub capture: bool,
+}
+
+impl MixerElement {
+    fn of(selem: &Selem) -> Option<Self> {
+        let id = selem.get_id();
+        // elements with the same name are told apart by index, only the first one is used
+        if id.get_index() != 0 {
+            return None;
+        }
+        Some(Self {
+            name: id.get_name().ok()?.to_string(),
+            playback: selem.has_playback_volume(),
+            capture: selem.has_capture_volume() || selem.has_capture_switch(),
+        })
+    }
+
+    pub fn supports(&self, direction: Direction) -> bool {
+        match direction {
+            Direction::Playback => self.playback,
+            Direction::Capture => self.capture,
+        }
+    }
+}
+
+/// ALSA mixer device, `default` or one sound card
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MixerDevice {
+    /// name to open the mixer with, e.g. `hw:1`
+    pub device: String,
+    pub description: String,
+    pub elements: Vec<MixerElement>,
+}
+
+/// `default` mixer followed by every sound card, the ones which can't be opened are skipped
+pub fn list_devices() -> anyhow::Result<Vec<MixerDevice>> {
+    let mut devices = Vec::new();
+    match open_device("default", "Default".to_string()) {
+        Ok(device) => devices.push(device),
+        Err(e) => debug!("No default mixer: {e:#}"),
+    }
+    for card in card::Iter::new() {
+        let card = card.context("failed to list sound cards")?;
+        let device = format!("hw:{}", card.get_index());
+        let description = card.get_name()?;
+        match open_device(&device, description) {
+            Ok(device) => devices.push(device),
+            Err(e) => warn!("Skipping sound card {device}: {e:#}"),
+        }
+    }
+    Ok(devices)
+}
+
+fn open_device(device: &str, description: String) -> anyhow::Result<MixerDevice> {
+    let mixer =
+        Mixer::new(device, false).with_context(|| format!("failed to open ALSA mixer {device}"))?;
+    Ok(MixerDevice {
+        device: device.to_string(),
+        description,
+        elements: elements(&mixer),
+    })
+}
+
+fn elements(mixer: &Mixer) -> Vec<MixerElement> {
+    mixer
+        .iter()
+        .filter_map(Selem::new)
+        .filter_map(|selem| MixerElement::of(&selem))
+        .collect()
+}
+
+/// `name` element of the mixer, or its fallback, see `resolve`
+pub fn find_element<'a>(mixer: &'a Mixer, name: &str, direction: Direction) -> Option<Selem<'a>> {
+    if let Some(selem) = mixer.find_selem(&SelemId::new(name, 0)) {
+        return Some(selem);
+    }
+    let elements = elements(mixer);
+    let element = resolve(&elements, name, direction)?;
+    debug!("ALSA mixer element {name} not found, using {element} instead");
+    mixer.find_selem(&SelemId::new(element, 0))
+}
+
+/// Element to use for `direction`: `name` when there is one, otherwise the first usual one for
+/// `direction`, otherwise the first one able to do it at all
+pub fn resolve<'a>(
+    elements: &'a [MixerElement],
+    name: &str,
+    direction: Direction,
+) -> Option<&'a str> {
+    let fallbacks = match direction {
+        Direction::Playback => PLAYBACK_FALLBACKS,
+        Direction::Capture => CAPTURE_FALLBACKS,
+    };
+    let usable = || {
+        elements
+            .iter()
+            .filter(|element| element.supports(direction))
+    };
+    elements
+        .iter()
+        .find(|element| element.name == name)
+        .or_else(|| {
+            fallbacks
+                .iter()
+                .find_map(|name| usable().find(|element| element.name == *name))
+        })
+        .or_else(|| usable().next())
+        .map(|element| element.name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(name: &str, playback: bool, capture: bool) -> MixerElement {
+        MixerElement {
+            name: name.to_string(),
+            playback,
+            capture,
+        }
+    }
+
+    #[test]
+    fn usb_headset_falls_back_to_its_elements() {
+        let headset = [
+            element("Mic", false, true),
+            element("Auto Gain Control", false, false),
+            element("PCM", true, false),
+        ];
+
+        assert_eq!(
+            resolve(&headset, "Master", Direction::Playback),
+            Some("PCM")
+        );
+        assert_eq!(
+            resolve(&headset, "Capture", Direction::Capture),
+            Some("Mic")
+        );
+        // configured element is used even when it looks unusable
+        assert_eq!(
+            resolve(&headset, "Auto Gain Control", Direction::Capture),
+            Some("Auto Gain Control")
+        );
+    }
+
+    #[test]
+    fn unusual_names_fall_back_to_first_usable_element() {
+        let card = [
+            element("Sidetone", false, false),
+            element("Earphone", true, false),
+            element("Line", true, true),
+        ];
+
+        assert_eq!(
+            resolve(&card, "Master", Direction::Playback),
+            Some("Earphone")
+        );
+        assert_eq!(resolve(&card, "Capture", Direction::Capture), Some("Line"));
+        assert_eq!(resolve(&card[..1], "Master", Direction::Playback), None);
+    }
+}
@@ -1,110 +1,486 @@
+use std::time::Duration;
+
 use alsa::{
-    mixer::{SelemChannelId, SelemId},
-    Mixer,
+    mixer::{MilliBel, Selem, SelemChannelId},
+    Mixer, Round,
+};
+use anyhow::Context;
+
+use super::{
+    backend::{Channel, Direction, OnMicrophoneChangeCallback, SoundBackend},
+    devices,
+    selection::SelectionStore,
+    sound_controller::MicrophoneStatus,
 };
+use crate::config::{ConfigHandle, SoundConfig};
+
+// how often mixer events watcher checks if capture device was changed
+const DEVICE_CHECK_INTERVAL_MS: i32 = 1000;
+
+/// Mixer events come for any element change, so only real mute transitions are passed on
+struct StatusTransitions {
+    last: Option<MicrophoneStatus>,
+}
 
-use super::sound_controller::MicrophoneStatus;
+impl StatusTransitions {
+    /// New status when it differs from the last one read
+    fn update(&mut self, status: anyhow::Result<MicrophoneStatus>) -> Option<MicrophoneStatus> {
+        match status {
+            Ok(status) if Some(status) != self.last => {
+                info!("Microphone status changed to {:?}", status);
+                self.last = Some(status);
+                Some(status)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                if self.last.is_some() {
+                    warn!("Microphone status unknown: {e:#}");
+                }
+                self.last = None;
+                None
+            }
+        }
+    }
+}
+
+/// Volumes of capture element without switch, which is muted by turning it down to minimum.
+/// Volumes it had are saved in `saved` and brought back on unmute.
+fn capture_volumes(
+    muted: bool,
+    current: &[i64],
+    (min, max): (i64, i64),
+    saved: &mut Option<Vec<i64>>,
+) -> Vec<i64> {
+    let silent = current.iter().all(|volume| *volume <= min);
+    match (muted, silent) {
+        (true, false) => {
+            *saved = Some(current.to_vec());
+            vec![min; current.len()]
+        }
+        // muting again must not save the silence
+        (true, true) => current.to_vec(),
+        (false, false) => {
+            *saved = None;
+            current.to_vec()
+        }
+        (false, true) => match saved.take() {
+            Some(volumes) if volumes.len() == current.len() => volumes,
+            // nothing to go back to, e.g. it was muted before the driver started
+            _ => vec![min + (max - min) / 2; current.len()],
+        },
+    }
+}
 
 pub struct LinuxSoundController {
-    current_microphone_status: MicrophoneStatus,
+    config: ConfigHandle,
+    selection: SelectionStore,
+    // capture volumes before muting element without switch
+    saved_capture_volumes: Option<Vec<i64>>,
 }
 
 impl LinuxSoundController {
-    pub fn new() -> Self {
-        let mixer = Mixer::new("default", false).unwrap();
-        let mut channels = SelemChannelId::all().iter();
-        let sound = mixer
-            .find_selem(&SelemId::new("Capture", 0))
-            .unwrap()
-            .get_capture_switch(*channels.next().unwrap())
-            .unwrap();
-
-        let current_microphone_status = if sound == 0 {
-            MicrophoneStatus::Muted
-        } else {
-            MicrophoneStatus::Unmuted
+    pub fn new(config: ConfigHandle) -> anyhow::Result<Self> {
+        let controller = LinuxSoundController {
+            config,
+            selection: SelectionStore::open(),
+            saved_capture_volumes: None,
         };
+        // buttons still work without microphone, e.g. until the headset is plugged in
+        for direction in [Direction::Playback, Direction::Capture] {
+            match controller.element_in_use(direction) {
+                Ok((device, element)) => info!("{direction:?} element: {element} of {device}"),
+                Err(e) => warn!("{e:#}, see `h-button-driver devices`"),
+            }
+        }
+        match controller.read_microphone_status() {
+            Ok(status) => info!("Initial microphone status: {:?}", status),
+            Err(e) => warn!("Failed to read microphone status: {e:#}"),
+        }
+        Ok(controller)
+    }
+
+    // mixer and element names are read on every call, so config reloads and elements picked in
+    // the tray apply immediately
+    fn sound_config(config: &ConfigHandle, selection: &SelectionStore) -> SoundConfig {
+        let mut config = config.get().sound;
+        selection.apply(&mut config);
+        config
+    }
+
+    fn open_mixer(device: &str) -> anyhow::Result<Mixer> {
+        Mixer::new(device, false).with_context(|| format!("failed to open ALSA mixer {device}"))
+    }
+
+    /// Configured element of `direction`, or its fallback when the mixer doesn't have it
+    fn find_selem<'a>(
+        mixer: &'a Mixer,
+        config: &SoundConfig,
+        direction: Direction,
+    ) -> anyhow::Result<Selem<'a>> {
+        let (device, name) = config.element(direction);
+        devices::find_element(mixer, name, direction).with_context(|| {
+            format!("ALSA mixer {device} has neither element {name} nor other {direction:?} one")
+        })
+    }
+
+    /// Device and name of the element used for `direction`
+    fn element_in_use(&self, direction: Direction) -> anyhow::Result<(String, String)> {
+        let config = Self::sound_config(&self.config, &self.selection);
+        let device = config.element(direction).0;
+        let mixer = Self::open_mixer(device)?;
+        let selem = Self::find_selem(&mixer, &config, direction)?;
+        let name = selem.get_id().get_name()?.to_string();
+        Ok((device.to_string(), name))
+    }
 
-        LinuxSoundController {
-            current_microphone_status,
+    /// Opens the mixer and runs `f` with the element of `direction`
+    fn with_selem<R>(
+        &self,
+        direction: Direction,
+        f: impl FnOnce(&Selem) -> anyhow::Result<R>,
+    ) -> anyhow::Result<R> {
+        let config = Self::sound_config(&self.config, &self.selection);
+        let mixer = Self::open_mixer(config.element(direction).0)?;
+        let selem = Self::find_selem(&mixer, &config, direction)?;
+        f(&selem)
+    }
+
+    // channels the element has, mono elements have only `FrontLeft`
+    fn channels(selem: &Selem, direction: Direction) -> Vec<SelemChannelId> {
+        SelemChannelId::all()
+            .iter()
+            .copied()
+            .filter(|channel| !matches!(channel, SelemChannelId::Unknown | SelemChannelId::Last))
+            .filter(|channel| match direction {
+                Direction::Playback => selem.has_playback_channel(*channel),
+                Direction::Capture => selem.has_capture_channel(*channel),
+            })
+            .collect()
+    }
+
+    fn channel(selem: &Selem, direction: Direction, channel: SelemChannelId) -> Channel {
+        let mono = match direction {
+            Direction::Playback => selem.is_playback_mono(),
+            Direction::Capture => selem.is_capture_mono(),
+        };
+        match channel {
+            _ if mono => Channel::Center,
+            SelemChannelId::FrontLeft | SelemChannelId::RearLeft | SelemChannelId::SideLeft => {
+                Channel::Left
+            }
+            SelemChannelId::FrontRight | SelemChannelId::RearRight | SelemChannelId::SideRight => {
+                Channel::Right
+            }
+            _ => Channel::Center,
         }
     }
 
-    pub fn get_microphone_status(&self) -> MicrophoneStatus {
-        let mixer = Mixer::new("default", false).unwrap();
-        let mut channels = SelemChannelId::all().iter();
+    fn read_microphone_status(&self) -> anyhow::Result<MicrophoneStatus> {
+        let config = Self::sound_config(&self.config, &self.selection);
+        let mixer = Self::open_mixer(config.element(Direction::Capture).0)?;
+        Self::microphone_status(&mixer, &config)
+    }
 
-        let selem = mixer.find_selem(&SelemId::new("Capture", 0)).unwrap();
-        let capture_switch_state = selem.get_capture_switch(*channels.next().unwrap()).unwrap();
-        let volume = selem.get_capture_volume(*channels.next().unwrap()).unwrap();
+    // USB headsets often have only a capture switch or only a capture volume
+    fn microphone_status(mixer: &Mixer, config: &SoundConfig) -> anyhow::Result<MicrophoneStatus> {
+        let selem = Self::find_selem(mixer, config, Direction::Capture)?;
+        let channel = Self::channels(&selem, Direction::Capture)
+            .first()
+            .copied()
+            .unwrap_or(SelemChannelId::mono());
 
-        println!("switch: {}, volume: {}", capture_switch_state, volume);
+        let switched_off = selem.has_capture_switch() && selem.get_capture_switch(channel)? == 0;
+        let silent = selem.has_capture_volume()
+            && selem.get_capture_volume(channel)? <= selem.get_capture_volume_range().0;
 
-        let state = match (volume, capture_switch_state) {
-            (0, _) => MicrophoneStatus::Muted,
-            (_, 0) => MicrophoneStatus::Muted,
-            (_, _) => MicrophoneStatus::Unmuted,
+        debug!("switched off: {}, silent: {}", switched_off, silent);
+
+        let state = if switched_off || silent {
+            MicrophoneStatus::Muted
+        } else {
+            MicrophoneStatus::Unmuted
         };
 
-        println!("Microphone status: {:?}", state);
-        state
+        debug!("Microphone status: {:?}", state);
+        Ok(state)
     }
 
-    pub fn toggle_microphone_mute(&mut self) {
-        match self.get_microphone_status() {
-            MicrophoneStatus::Muted => {
-                self.unmute_mic();
-                self.current_microphone_status = MicrophoneStatus::Unmuted;
-            }
-            MicrophoneStatus::Unmuted => {
-                self.mute_mic();
-                self.current_microphone_status = MicrophoneStatus::Muted;
+    /// Blocks until the mixer changes or device check interval passes
+    fn wait_for_events(mixer: &Mixer) -> anyhow::Result<()> {
+        alsa::poll::poll_all(&[mixer], DEVICE_CHECK_INTERVAL_MS)?;
+        mixer.handle_events()?;
+        Ok(())
+    }
+
+    // blocks on mixer poll descriptors, so it wakes up when some element is changed, and once in
+    // a while to follow the capture device when another one is configured or picked in the tray
+    fn watch_mixer_events(
+        config: ConfigHandle,
+        selection: SelectionStore,
+        on_change: OnMicrophoneChangeCallback,
+    ) {
+        let sound_config = || Self::sound_config(&config, &selection);
+        let mut transitions: Option<StatusTransitions> = None;
+        loop {
+            // e.g. the headset isn't plugged in yet, or was unplugged
+            let (mut device, mut mixer) = loop {
+                let device = sound_config().element(Direction::Capture).0.to_string();
+                match Self::open_mixer(&device) {
+                    Ok(mixer) => break (device, mixer),
+                    Err(e) => debug!("{e:#}"),
+                }
+                std::thread::sleep(Duration::from_millis(DEVICE_CHECK_INTERVAL_MS as u64));
+            };
+            // status before the mixer is reopened is kept, so only its changes are reported
+            let transitions = transitions.get_or_insert_with(|| StatusTransitions {
+                last: Self::microphone_status(&mixer, &sound_config()).ok(),
+            });
+            loop {
+                if let Err(e) = Self::wait_for_events(&mixer) {
+                    warn!("ALSA mixer {device} events failed, reopening it: {e:#}");
+                    std::thread::sleep(Duration::from_millis(DEVICE_CHECK_INTERVAL_MS as u64));
+                    break;
+                }
+
+                let sound_config = sound_config();
+                let capture_device = sound_config.element(Direction::Capture).0;
+                if capture_device != device {
+                    // unplugged device is tried again on the next check
+                    match Self::open_mixer(capture_device) {
+                        Ok(opened) => {
+                            info!("Watching microphone of ALSA mixer {capture_device}");
+                            mixer = opened;
+                            device = capture_device.to_string();
+                        }
+                        Err(e) => debug!("{e:#}"),
+                    }
+                }
+
+                if let Some(status) =
+                    transitions.update(Self::microphone_status(&mixer, &sound_config))
+                {
+                    on_change(status);
+                }
             }
         }
     }
 
-    pub fn set_volume(&mut self, volume: i64) {
-        let mixer = Mixer::new("default", false).unwrap();
+    // capture switch when the element has one, its volume otherwise
+    fn set_microphone_muted(&mut self, muted: bool) -> anyhow::Result<()> {
+        let mut saved = self.saved_capture_volumes.take();
+        let result = self.with_selem(Direction::Capture, |selem| {
+            if selem.has_capture_switch() {
+                selem.set_capture_switch_all(if muted { 0 } else { 1 })?;
+                return Ok(());
+            }
+            anyhow::ensure!(
+                selem.has_capture_volume(),
+                "capture element {} has neither switch nor volume to mute it with",
+                selem.get_id().get_name()?
+            );
+            let channels = Self::channels(selem, Direction::Capture);
+            let current = channels
+                .iter()
+                .map(|channel| selem.get_capture_volume(*channel))
+                .collect::<Result<Vec<_>, _>>()?;
+            let range = selem.get_capture_volume_range();
+            let volumes = capture_volumes(muted, &current, range, &mut saved);
+            for (channel, volume) in channels.into_iter().zip(volumes) {
+                selem.set_capture_volume(channel, volume)?;
+            }
+            Ok(())
+        });
+        self.saved_capture_volumes = saved;
+        result
+    }
+}
+
+impl SoundBackend for LinuxSoundController {
+    fn get_microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus> {
+        self.read_microphone_status()
+    }
+
+    fn volume_range(&mut self, direction: Direction) -> anyhow::Result<(i64, i64)> {
+        self.with_selem(direction, |selem| {
+            Ok(match direction {
+                Direction::Playback => selem.get_playback_volume_range(),
+                Direction::Capture => selem.get_capture_volume_range(),
+            })
+        })
+    }
+
+    fn channel_volumes(&mut self, direction: Direction) -> anyhow::Result<Vec<(Channel, i64)>> {
+        self.with_selem(direction, |selem| {
+            let volumes = Self::channels(selem, direction)
+                .into_iter()
+                .map(|channel| {
+                    let volume = match direction {
+                        Direction::Playback => selem.get_playback_volume(channel)?,
+                        Direction::Capture => selem.get_capture_volume(channel)?,
+                    };
+                    Ok((Self::channel(selem, direction, channel), volume))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            debug!("Current {direction:?} volume: {volumes:?}");
+            Ok(volumes)
+        })
+    }
+
+    fn set_channel_volumes(&mut self, direction: Direction, volumes: &[i64]) -> anyhow::Result<()> {
+        self.with_selem(direction, |selem| {
+            let channels = Self::channels(selem, direction);
+            anyhow::ensure!(
+                channels.len() == volumes.len(),
+                "{direction:?} element has {} channels, got {} volumes",
+                channels.len(),
+                volumes.len()
+            );
+            debug!("Setting {direction:?} volume to {volumes:?}");
+            for (channel, volume) in channels.into_iter().zip(volumes) {
+                match direction {
+                    Direction::Playback => selem.set_playback_volume(channel, *volume)?,
+                    Direction::Capture => selem.set_capture_volume(channel, *volume)?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // elements without dB information (no TLV) fail to convert
+    fn volume_db(&mut self, direction: Direction, raw: i64) -> anyhow::Result<Option<f64>> {
+        self.with_selem(direction, |selem| {
+            let db = match direction {
+                Direction::Playback => selem.ask_playback_vol_db(raw),
+                Direction::Capture => selem.ask_capture_vol_db(raw),
+            };
+            Ok(db.ok().map(|db| db.to_db() as f64))
+        })
+    }
+
+    fn db_volume(&mut self, direction: Direction, db: f64) -> anyhow::Result<Option<i64>> {
+        self.with_selem(direction, |selem| {
+            let db = MilliBel::from_db(db as f32);
+            // rounds down, asked dB value is never exceeded
+            let raw = match direction {
+                Direction::Playback => selem.ask_playback_db_vol(db, Round::Floor),
+                Direction::Capture => selem.ask_capture_db_vol(db, Round::Floor),
+            };
+            Ok(raw.ok())
+        })
+    }
+
+    fn mute_mic(&mut self) -> anyhow::Result<()> {
+        debug!("Muting mic");
+        self.set_microphone_muted(true)
+    }
+
+    fn unmute_mic(&mut self) -> anyhow::Result<()> {
+        debug!("Unmuting mic");
+        self.set_microphone_muted(false)
+    }
 
-        println!("Setting volume to {}", volume);
-        mixer
-            .find_selem(&SelemId::new("Master", 0))
-            .unwrap()
-            .set_playback_volume_all(volume)
-            .unwrap();
+    fn subscribe(&mut self, on_change: OnMicrophoneChangeCallback) -> anyhow::Result<()> {
+        let config = self.config.clone();
+        let selection = self.selection.clone();
+        std::thread::Builder::new()
+            .name("alsa-mixer-events".to_string())
+            .spawn(move || Self::watch_mixer_events(config, selection, on_change))?;
+        Ok(())
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::sound::sound_controller::SoundController;
 
-    pub fn get_current_volume(&mut self) -> i64 {
-        let mixer = Mixer::new("default", false).unwrap();
+    // controller and percent of one raw volume step; needs a card to play with, e.g.
+    // `modprobe snd-dummy` and `H_BUTTON_TEST_MIXER=hw:Dummy cargo test -- --ignored`
+    fn controller() -> (SoundController, f64) {
+        let mut config = Config::default();
+        config.sound.mixer_device =
+            std::env::var("H_BUTTON_TEST_MIXER").expect("H_BUTTON_TEST_MIXER is not set");
+        if let Ok(element) = std::env::var("H_BUTTON_TEST_ELEMENT") {
+            config.sound.playback_element = element;
+        }
+        let mut backend = LinuxSoundController {
+            config: ConfigHandle::new(None, config),
+            selection: SelectionStore::empty(),
+            saved_capture_volumes: None,
+        };
+        let (min, max) = backend.volume_range(Direction::Playback).unwrap();
+        let step = 100.0 / (max - min) as f64;
+        (SoundController::with_backend(Box::new(backend)), step)
+    }
 
-        let current_volume = mixer
-            .find_selem(&SelemId::new("Master", 0))
-            .unwrap()
-            .get_playback_volume(SelemChannelId::mono())
-            .unwrap();
+    #[test]
+    fn capture_volume_is_restored_on_unmute() {
+        let mut saved = None;
+        let muted = capture_volumes(true, &[30, 40], (0, 100), &mut saved);
+        assert_eq!(muted, vec![0, 0]);
+        // muted twice, first volumes are still the ones restored
+        assert_eq!(capture_volumes(true, &muted, (0, 100), &mut saved), muted);
+        assert_eq!(
+            capture_volumes(false, &muted, (0, 100), &mut saved),
+            vec![30, 40]
+        );
+        assert_eq!(saved, None);
+    }
 
-        println!("Current volume: {current_volume}");
-        current_volume
+    #[test]
+    fn capture_volume_muted_elsewhere_is_raised_on_unmute() {
+        let mut saved = None;
+        assert_eq!(
+            capture_volumes(false, &[-10], (-10, 20), &mut saved),
+            vec![5]
+        );
+        // already unmuted one is left alone
+        assert_eq!(capture_volumes(false, &[7], (-10, 20), &mut saved), vec![7]);
     }
 
-    pub fn mute_mic(&mut self) {
-        let mixer = Mixer::new("default", false).unwrap();
+    #[test]
+    fn only_transitions_are_reported() {
+        use MicrophoneStatus::*;
+        let mut transitions = StatusTransitions { last: Some(Muted) };
+        assert_eq!(transitions.update(Ok(Muted)), None);
+        assert_eq!(transitions.update(Ok(Unmuted)), Some(Unmuted));
+        assert_eq!(transitions.update(Ok(Unmuted)), None);
+        assert_eq!(transitions.update(Ok(Muted)), Some(Muted));
+    }
 
-        println!("Muting mic");
-        mixer
-            .find_selem(&alsa::mixer::SelemId::new("Capture", 0))
-            .unwrap()
-            .set_capture_switch_all(0)
-            .unwrap();
+    #[test]
+    fn status_is_reported_after_unknown() {
+        use MicrophoneStatus::*;
+        // e.g. the headset isn't plugged in yet
+        let mut transitions = StatusTransitions { last: None };
+        assert_eq!(transitions.update(Ok(Muted)), Some(Muted));
+        assert_eq!(transitions.update(Err(anyhow::anyhow!("unplugged"))), None);
+        assert_eq!(transitions.update(Err(anyhow::anyhow!("unplugged"))), None);
+        assert_eq!(transitions.update(Ok(Muted)), Some(Muted));
     }
 
-    pub fn unmute_mic(&mut self) {
-        let mixer = Mixer::new("default", false).unwrap();
-        println!("Unmuting mic");
-        mixer
-            .find_selem(&alsa::mixer::SelemId::new("Capture", 0))
-            .unwrap()
-            .set_capture_switch_all(65536)
-            .unwrap();
+    #[test]
+    #[ignore = "changes volume of H_BUTTON_TEST_MIXER"]
+    fn volume_follows_element_range() {
+        let (mut sc, step) = controller();
+
+        sc.set_volume_percent(Direction::Playback, 50.0).unwrap();
+        let percent = sc.volume_percent(Direction::Playback).unwrap();
+        assert!((percent - 50.0).abs() <= step, "{percent}");
+
+        sc.set_balance(Direction::Playback, 0.5).unwrap();
+        let balance = sc.balance(Direction::Playback).unwrap();
+        if balance != 0.0 {
+            assert!((balance - 0.5).abs() < 0.1, "{balance}");
+        }
+        sc.set_volume_percent(Direction::Playback, 60.0).unwrap();
+        assert_eq!(sc.balance(Direction::Playback).unwrap(), balance);
+
+        if sc.volume_db(Direction::Playback).unwrap().is_some() {
+            sc.set_volume_db(Direction::Playback, -20.0).unwrap();
+            let db = sc.volume_db(Direction::Playback).unwrap().unwrap();
+            assert!(db <= -20.0 && db > -25.0, "{db}");
+        }
     }
 }
@@ -1,35 +1,42 @@
-use super::sound_controller::MicrophoneStatus;
+use super::{
+    backend::{Channel, Direction, SoundBackend},
+    sound_controller::MicrophoneStatus,
+};
 
-pub struct MacOsSoundController {
-    current_microphone_status: MicrophoneStatus,
-}
+pub struct MacOsSoundController {}
 
 impl MacOsSoundController {
     pub fn new() -> Self {
         todo!();
     }
+}
 
-    pub fn get_microphone_status(&self) -> MicrophoneStatus {
+impl SoundBackend for MacOsSoundController {
+    fn get_microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus> {
         todo!();
     }
 
-    pub fn toggle_microphone_mute(&mut self) {
+    fn volume_range(&mut self, _direction: Direction) -> anyhow::Result<(i64, i64)> {
         todo!();
     }
 
-    pub fn set_volume(&mut self, volume: i64) {
+    fn channel_volumes(&mut self, _direction: Direction) -> anyhow::Result<Vec<(Channel, i64)>> {
         todo!();
     }
 
-    pub fn get_current_volume(&mut self) -> i64 {
+    fn set_channel_volumes(
+        &mut self,
+        _direction: Direction,
+        _volumes: &[i64],
+    ) -> anyhow::Result<()> {
         todo!();
     }
 
-    pub fn mute_mic(&mut self) {
+    fn mute_mic(&mut self) -> anyhow::Result<()> {
         todo!();
     }
 
-    pub fn unmute_mic(&mut self) {
+    fn unmute_mic(&mut self) -> anyhow::Result<()> {
         todo!();
     }
 }
@@ -0,0 +1,139 @@
+use std::sync::{Arc, Mutex};
+
+use super::{
+    backend::{Channel, Direction, SoundBackend},
+    sound_controller::MicrophoneStatus,
+};
+
+/// Mixer element of the mock, like ALSA simple element with linear dB scale
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockElement {
+    pub range: (i64, i64),
+    pub channels: Vec<(Channel, i64)>,
+    /// dB at the minimum and maximum raw volume, every raw step is the same number of dB
+    pub db_range: Option<(f64, f64)>,
+}
+
+impl MockElement {
+    /// Element at the minimum of `range`
+    pub fn new(range: (i64, i64), channels: &[Channel], db_range: Option<(f64, f64)>) -> Self {
+        Self {
+            range,
+            channels: channels.iter().map(|channel| (*channel, range.0)).collect(),
+            db_range,
+        }
+    }
+
+    fn volume_db(&self, raw: i64) -> Option<f64> {
+        let (min_db, max_db) = self.db_range?;
+        let (min, max) = self.range;
+        Some(min_db + (raw - min) as f64 / (max - min) as f64 * (max_db - min_db))
+    }
+
+    fn db_volume(&self, db: f64) -> Option<i64> {
+        let (min_db, max_db) = self.db_range?;
+        let (min, max) = self.range;
+        let level = ((db - min_db) / (max_db - min_db)).clamp(0.0, 1.0);
+        Some(min + (level * (max - min) as f64).round() as i64)
+    }
+}
+
+#[derive(Debug)]
+struct MockState {
+    microphone_status: MicrophoneStatus,
+    playback: MockElement,
+    capture: MockElement,
+}
+
+impl MockState {
+    fn element(&mut self, direction: Direction) -> &mut MockElement {
+        match direction {
+            Direction::Playback => &mut self.playback,
+            Direction::Capture => &mut self.capture,
+        }
+    }
+}
+
+/// In-memory backend, doesn't touch any sound card. Clones share the state, so it can be
+/// checked while `SoundController` owns the backend. Playback is stereo and capture mono, both
+/// in 0..=65536 range without dB scale, unless replaced with `set_element`.
+#[derive(Clone)]
+pub struct MockSoundBackend {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl MockSoundBackend {
+    pub fn new() -> Self {
+        MockSoundBackend {
+            state: Arc::new(Mutex::new(MockState {
+                microphone_status: MicrophoneStatus::Unmuted,
+                playback: MockElement::new((0, 65536), &[Channel::Left, Channel::Right], None),
+                capture: MockElement::new((0, 65536), &[Channel::Center], None),
+            })),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn set_element(&self, direction: Direction, element: MockElement) {
+        *self.state.lock().unwrap().element(direction) = element;
+    }
+
+    #[cfg(test)]
+    pub fn element(&self, direction: Direction) -> MockElement {
+        self.state.lock().unwrap().element(direction).clone()
+    }
+}
+
+impl SoundBackend for MockSoundBackend {
+    fn get_microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus> {
+        Ok(self.state.lock().unwrap().microphone_status)
+    }
+
+    fn mute_mic(&mut self) -> anyhow::Result<()> {
+        self.state.lock().unwrap().microphone_status = MicrophoneStatus::Muted;
+        Ok(())
+    }
+
+    fn unmute_mic(&mut self) -> anyhow::Result<()> {
+        self.state.lock().unwrap().microphone_status = MicrophoneStatus::Unmuted;
+        Ok(())
+    }
+
+    fn volume_range(&mut self, direction: Direction) -> anyhow::Result<(i64, i64)> {
+        Ok(self.state.lock().unwrap().element(direction).range)
+    }
+
+    fn channel_volumes(&mut self, direction: Direction) -> anyhow::Result<Vec<(Channel, i64)>> {
+        Ok(self
+            .state
+            .lock()
+            .unwrap()
+            .element(direction)
+            .channels
+            .clone())
+    }
+
+    fn set_channel_volumes(&mut self, direction: Direction, volumes: &[i64]) -> anyhow::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let element = state.element(direction);
+        anyhow::ensure!(
+            volumes.len() == element.channels.len(),
+            "{direction:?} element has {} channels, got {} volumes",
+            element.channels.len(),
+            volumes.len()
+        );
+        let (min, max) = element.range;
+        for ((_, volume), new) in element.channels.iter_mut().zip(volumes) {
+            *volume = (*new).clamp(min, max);
+        }
+        Ok(())
+    }
+
+    fn volume_db(&mut self, direction: Direction, raw: i64) -> anyhow::Result<Option<f64>> {
+        Ok(self.state.lock().unwrap().element(direction).volume_db(raw))
+    }
+
+    fn db_volume(&mut self, direction: Direction, db: f64) -> anyhow::Result<Option<i64>> {
+        Ok(self.state.lock().unwrap().element(direction).db_volume(db))
+    }
+}
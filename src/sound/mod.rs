@@ -1,4 +1,9 @@
+pub mod backend;
 pub mod sound_controller;
+pub mod volume_curve;
+
+pub mod mock;
+pub mod selection;
 
 #[cfg(target_os = "macos")]
 mod macos;
@@ -8,3 +13,9 @@ mod windows;
 
 #[cfg(target_os = "linux")]
 mod linux;
+
+#[cfg(target_os = "linux")]
+pub mod devices;
+
+#[cfg(all(target_os = "linux", feature = "pulseaudio"))]
+mod pulse;
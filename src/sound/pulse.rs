@@ -0,0 +1,409 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use anyhow::{anyhow, bail, Context as _};
+use libpulse_binding::{
+    callbacks::ListResult,
+    channelmap::{Map, Position},
+    context::{subscribe::InterestMaskSet, Context, FlagSet, State},
+    mainloop::standard::{IterateResult, Mainloop},
+    operation::{Operation, State as OperationState},
+    volume::{ChannelVolumes, Volume, VolumeDB},
+};
+
+use super::{
+    backend::{Channel, Direction, OnMicrophoneChangeCallback, SoundBackend},
+    sound_controller::MicrophoneStatus,
+};
+
+// Special names resolved by the server on every request, so the backend always
+// follows the current default devices, even when the user switches them
+const DEFAULT_SINK: &str = "@DEFAULT_SINK@";
+const DEFAULT_SOURCE: &str = "@DEFAULT_SOURCE@";
+
+/// Backend speaking the pulse protocol, works with PulseAudio and pipewire-pulse.
+pub struct PulseSoundController {}
+
+impl PulseSoundController {
+    pub fn new() -> anyhow::Result<Self> {
+        let mut connection = Connection::open()?;
+        let (sink, source) = connection.default_devices()?;
+        info!("PulseAudio default sink: {sink}, default source: {source}");
+        Ok(PulseSoundController {})
+    }
+
+    // server notifies about every source change and about default source switch,
+    // status is compared with the last one so only real transitions are reported
+    fn watch_server_events(on_change: OnMicrophoneChangeCallback) -> anyhow::Result<()> {
+        let mut connection = Connection::open()?;
+        let mut last_status = connection.microphone_status()?;
+
+        let changed = Rc::new(Cell::new(false));
+        {
+            let changed = changed.clone();
+            connection
+                .context
+                .set_subscribe_callback(Some(Box::new(move |_, _, _| changed.set(true))));
+        }
+        let success = Rc::new(Cell::new(false));
+        let operation = {
+            let success = success.clone();
+            connection.context.subscribe(
+                InterestMaskSet::SOURCE | InterestMaskSet::SERVER,
+                move |ok| success.set(ok),
+            )
+        };
+        connection.wait_for_success(operation, &success)?;
+
+        loop {
+            Connection::iterate(&mut connection.mainloop)?;
+            if !changed.replace(false) {
+                continue;
+            }
+
+            let status = connection.microphone_status()?;
+            if status != last_status {
+                info!("Microphone status changed to {:?}", status);
+                last_status = status;
+                on_change(status);
+            }
+        }
+    }
+
+    fn channel(position: Position) -> Channel {
+        match position {
+            Position::FrontLeft
+            | Position::RearLeft
+            | Position::FrontLeftOfCenter
+            | Position::SideLeft
+            | Position::TopFrontLeft
+            | Position::TopRearLeft => Channel::Left,
+            Position::FrontRight
+            | Position::RearRight
+            | Position::FrontRightOfCenter
+            | Position::SideRight
+            | Position::TopFrontRight
+            | Position::TopRearRight => Channel::Right,
+            _ => Channel::Center,
+        }
+    }
+}
+
+impl SoundBackend for PulseSoundController {
+    fn get_microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus> {
+        Connection::open()?.microphone_status()
+    }
+
+    fn mute_mic(&mut self) -> anyhow::Result<()> {
+        debug!("Muting mic");
+        Connection::open()?.set_source_mute(true)
+    }
+
+    fn unmute_mic(&mut self) -> anyhow::Result<()> {
+        debug!("Unmuting mic");
+        Connection::open()?.set_source_mute(false)
+    }
+
+    // volumes above 100% amplify in software, the knob doesn't go there
+    fn volume_range(&mut self, _direction: Direction) -> anyhow::Result<(i64, i64)> {
+        Ok((Volume::MUTED.0 as i64, Volume::NORMAL.0 as i64))
+    }
+
+    fn channel_volumes(&mut self, direction: Direction) -> anyhow::Result<Vec<(Channel, i64)>> {
+        let (channel_volumes, channel_map) = Connection::open()?.device_volume(direction)?;
+        let volumes: Vec<_> = channel_map
+            .get()
+            .iter()
+            .zip(channel_volumes.get())
+            .map(|(position, volume)| (Self::channel(*position), volume.0 as i64))
+            .collect();
+        debug!("Current {direction:?} volume: {volumes:?}");
+        Ok(volumes)
+    }
+
+    fn set_channel_volumes(&mut self, direction: Direction, volumes: &[i64]) -> anyhow::Result<()> {
+        let mut connection = Connection::open()?;
+        let (mut channel_volumes, _) = connection.device_volume(direction)?;
+        anyhow::ensure!(
+            channel_volumes.len() as usize == volumes.len(),
+            "{direction:?} device has {} channels, got {} volumes",
+            channel_volumes.len(),
+            volumes.len()
+        );
+
+        debug!("Setting {direction:?} volume to {volumes:?}");
+        for (channel_volume, volume) in channel_volumes.get_mut().iter_mut().zip(volumes) {
+            *channel_volume = Volume((*volume).clamp(0, Volume::MAX.0 as i64) as u32);
+        }
+        connection.set_device_volume(direction, &channel_volumes)
+    }
+
+    // pulse volumes are software ones, their dB scale is fixed (cubic)
+    fn volume_db(&mut self, _direction: Direction, raw: i64) -> anyhow::Result<Option<f64>> {
+        Ok(Some(
+            VolumeDB::from(Volume(raw.clamp(0, u32::MAX as i64) as u32)).0,
+        ))
+    }
+
+    fn db_volume(&mut self, _direction: Direction, db: f64) -> anyhow::Result<Option<i64>> {
+        Ok(Some(Volume::from(VolumeDB(db)).0 as i64))
+    }
+
+    fn subscribe(&mut self, on_change: OnMicrophoneChangeCallback) -> anyhow::Result<()> {
+        std::thread::Builder::new()
+            .name("pulse-events".to_string())
+            .spawn(move || {
+                if let Err(e) = Self::watch_server_events(on_change) {
+                    error!("PulseAudio events watcher stopped: {e:?}");
+                }
+            })?;
+        Ok(())
+    }
+}
+
+/// Blocking connection to the pulse server, opened per request as the
+/// mainloop and context are not `Send`
+struct Connection {
+    mainloop: Mainloop,
+    context: Context,
+}
+
+impl Connection {
+    fn open() -> anyhow::Result<Self> {
+        let mut mainloop = Mainloop::new().context("failed to create PulseAudio mainloop")?;
+        let mut context = Context::new(&mainloop, env!("CARGO_PKG_NAME"))
+            .context("failed to create PulseAudio context")?;
+        context
+            .connect(None, FlagSet::NOAUTOSPAWN, None)
+            .context("failed to connect to PulseAudio server")?;
+
+        loop {
+            Self::iterate(&mut mainloop)?;
+            match context.get_state() {
+                State::Ready => break,
+                State::Failed | State::Terminated => {
+                    bail!("PulseAudio connection failed: {:?}", context.get_state())
+                }
+                _ => {}
+            }
+        }
+        Ok(Connection { mainloop, context })
+    }
+
+    fn iterate(mainloop: &mut Mainloop) -> anyhow::Result<()> {
+        match mainloop.iterate(true) {
+            IterateResult::Success(_) => Ok(()),
+            IterateResult::Quit(_) => bail!("PulseAudio mainloop quit"),
+            IterateResult::Err(e) => Err(anyhow!("PulseAudio mainloop error: {e}")),
+        }
+    }
+
+    fn wait<F: ?Sized>(&mut self, operation: Operation<F>) -> anyhow::Result<()> {
+        while operation.get_state() == OperationState::Running {
+            Self::iterate(&mut self.mainloop)?;
+        }
+        Ok(())
+    }
+
+    fn wait_for_success(
+        &mut self,
+        operation: Operation<dyn FnMut(bool)>,
+        success: &Cell<bool>,
+    ) -> anyhow::Result<()> {
+        self.wait(operation)?;
+        if !success.get() {
+            bail!("PulseAudio request failed: {:?}", self.context.errno());
+        }
+        Ok(())
+    }
+
+    fn default_devices(&mut self) -> anyhow::Result<(String, String)> {
+        let result = Rc::new(RefCell::new(None));
+        let operation = {
+            let result = result.clone();
+            self.context.introspect().get_server_info(move |info| {
+                *result.borrow_mut() = Some((
+                    info.default_sink_name
+                        .as_deref()
+                        .unwrap_or_default()
+                        .to_string(),
+                    info.default_source_name
+                        .as_deref()
+                        .unwrap_or_default()
+                        .to_string(),
+                ));
+            })
+        };
+        self.wait(operation)?;
+        let devices = result.borrow_mut().take();
+        devices.context("PulseAudio server info not received")
+    }
+
+    /// Volume and channel positions of the default sink or source
+    fn device_volume(&mut self, direction: Direction) -> anyhow::Result<(ChannelVolumes, Map)> {
+        let result = Rc::new(RefCell::new(None));
+        let introspect = self.context.introspect();
+        match direction {
+            Direction::Playback => {
+                let result = result.clone();
+                let operation = introspect.get_sink_info_by_name(DEFAULT_SINK, move |info| {
+                    if let ListResult::Item(info) = info {
+                        trace!("Default sink: {:?}", info.name);
+                        *result.borrow_mut() = Some((info.volume, info.channel_map));
+                    }
+                });
+                self.wait(operation)?;
+            }
+            Direction::Capture => {
+                let result = result.clone();
+                let operation = introspect.get_source_info_by_name(DEFAULT_SOURCE, move |info| {
+                    if let ListResult::Item(info) = info {
+                        trace!("Default source: {:?}", info.name);
+                        *result.borrow_mut() = Some((info.volume, info.channel_map));
+                    }
+                });
+                self.wait(operation)?;
+            }
+        }
+        let volume = result.borrow_mut().take();
+        volume.with_context(|| format!("default PulseAudio {direction:?} device not found"))
+    }
+
+    fn set_device_volume(
+        &mut self,
+        direction: Direction,
+        volume: &ChannelVolumes,
+    ) -> anyhow::Result<()> {
+        match direction {
+            Direction::Playback => self.set_sink_volume(volume),
+            Direction::Capture => self.set_source_volume(volume),
+        }
+    }
+
+    fn source_state(&mut self) -> anyhow::Result<(ChannelVolumes, bool)> {
+        let result = Rc::new(RefCell::new(None));
+        let operation = {
+            let result = result.clone();
+            self.context
+                .introspect()
+                .get_source_info_by_name(DEFAULT_SOURCE, move |info| {
+                    if let ListResult::Item(info) = info {
+                        trace!("Default source: {:?}", info.name);
+                        *result.borrow_mut() = Some((info.volume, info.mute));
+                    }
+                })
+        };
+        self.wait(operation)?;
+        let state = result.borrow_mut().take();
+        state.context("default PulseAudio source not found")
+    }
+
+    fn microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus> {
+        let (volume, mute) = self.source_state()?;
+
+        let state = if mute || volume.max() == Volume::MUTED {
+            MicrophoneStatus::Muted
+        } else {
+            MicrophoneStatus::Unmuted
+        };
+
+        debug!("Microphone status: {:?}", state);
+        Ok(state)
+    }
+
+    fn set_sink_volume(&mut self, volume: &ChannelVolumes) -> anyhow::Result<()> {
+        let success = Rc::new(Cell::new(false));
+        let operation = {
+            let success = success.clone();
+            self.context.introspect().set_sink_volume_by_name(
+                DEFAULT_SINK,
+                volume,
+                Some(Box::new(move |ok| success.set(ok))),
+            )
+        };
+        self.wait_for_success(operation, &success)
+    }
+
+    fn set_source_volume(&mut self, volume: &ChannelVolumes) -> anyhow::Result<()> {
+        let success = Rc::new(Cell::new(false));
+        let operation = {
+            let success = success.clone();
+            self.context.introspect().set_source_volume_by_name(
+                DEFAULT_SOURCE,
+                volume,
+                Some(Box::new(move |ok| success.set(ok))),
+            )
+        };
+        self.wait_for_success(operation, &success)
+    }
+
+    fn set_source_mute(&mut self, mute: bool) -> anyhow::Result<()> {
+        let success = Rc::new(Cell::new(false));
+        let operation = {
+            let success = success.clone();
+            self.context.introspect().set_source_mute_by_name(
+                DEFAULT_SOURCE,
+                mute,
+                Some(Box::new(move |ok| success.set(ok))),
+            )
+        };
+        self.wait_for_success(operation, &success)
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.context.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::sound_controller::SoundController;
+
+    // needs a server with test devices as defaults, see "Testing pulse backend" in README,
+    // then `cargo test --features pulseaudio -- --ignored pulse`
+    fn controller() -> SoundController {
+        let backend = PulseSoundController::new().expect("no PulseAudio server");
+        SoundController::with_backend(Box::new(backend))
+    }
+
+    #[test]
+    #[ignore = "changes volume and mute of default PulseAudio sink and source"]
+    fn default_devices_follow_controller() {
+        let mut sc = controller();
+
+        for direction in [Direction::Playback, Direction::Capture] {
+            for percent in [40.0, 75.0] {
+                sc.set_volume_percent(direction, percent).unwrap();
+                let volume = sc.volume_percent(direction).unwrap();
+                assert!((volume - percent).abs() < 0.01, "{direction:?}: {volume}");
+            }
+        }
+
+        sc.unmute_mic().unwrap();
+        assert_eq!(
+            sc.get_microphone_status().unwrap(),
+            MicrophoneStatus::Unmuted
+        );
+        sc.toggle_microphone_mute().unwrap();
+        assert_eq!(sc.get_microphone_status().unwrap(), MicrophoneStatus::Muted);
+        sc.toggle_microphone_mute().unwrap();
+        assert_eq!(
+            sc.get_microphone_status().unwrap(),
+            MicrophoneStatus::Unmuted
+        );
+
+        // source turned all the way down is as good as muted
+        sc.set_volume_percent(Direction::Capture, 0.0).unwrap();
+        assert_eq!(sc.get_microphone_status().unwrap(), MicrophoneStatus::Muted);
+        sc.set_volume_percent(Direction::Capture, 75.0).unwrap();
+        assert_eq!(
+            sc.get_microphone_status().unwrap(),
+            MicrophoneStatus::Unmuted
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::backend::Direction;
+use crate::config::SoundConfig;
+
+const SELECTION_FILE_NAME: &str = "sound.toml";
+
+/// ALSA mixer device and its simple element
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SelectedElement {
+    pub device: String,
+    pub element: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct SelectionFile {
+    playback: Option<SelectedElement>,
+    capture: Option<SelectedElement>,
+}
+
+/// Playback and capture elements picked in the tray, persisted between runs. They take
+/// precedence over the ones from config until cleared. File is read on every access, like the
+/// one of `PairingStore`.
+#[derive(Clone, Debug)]
+pub struct SelectionStore {
+    path: Option<PathBuf>,
+}
+
+impl SelectionStore {
+    /// `$XDG_STATE_HOME/h-button-driver/sound.toml` on Linux
+    pub fn open() -> Self {
+        let path = dirs::state_dir()
+            .or_else(dirs::data_local_dir)
+            .map(|dir| dir.join(env!("CARGO_PKG_NAME")).join(SELECTION_FILE_NAME));
+        Self { path }
+    }
+
+    /// Store without a file, for tests not to pick up elements selected on this machine
+    #[cfg(test)]
+    pub fn empty() -> Self {
+        Self { path: None }
+    }
+
+    fn read(&self) -> anyhow::Result<SelectionFile> {
+        let Some(path) = self.path.as_ref().filter(|path| path.exists()) else {
+            return Ok(SelectionFile::default());
+        };
+        let content =
+            fs::read_to_string(path).with_context(|| format!("cannot read {}", path.display()))?;
+        toml::from_str(&content).with_context(|| format!("invalid {}", path.display()))
+    }
+
+    pub fn selected(&self, direction: Direction) -> anyhow::Result<Option<SelectedElement>> {
+        let file = self.read()?;
+        Ok(match direction {
+            Direction::Playback => file.playback,
+            Direction::Capture => file.capture,
+        })
+    }
+
+    /// Picks element of `direction`, `None` goes back to the one from config
+    pub fn select(
+        &self,
+        direction: Direction,
+        element: Option<SelectedElement>,
+    ) -> anyhow::Result<()> {
+        let mut file = self.read()?;
+        match direction {
+            Direction::Playback => file.playback = element,
+            Direction::Capture => file.capture = element,
+        }
+        let path = self
+            .path
+            .as_ref()
+            .context("no state directory to store selected sound devices in")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("cannot create {}", dir.display()))?;
+        }
+        let content = toml::to_string(&file)?;
+        fs::write(path, content).with_context(|| format!("cannot write {}", path.display()))
+    }
+
+    /// Replaces devices and elements of `config` with the selected ones, unreadable selection
+    /// is ignored
+    pub fn apply(&self, config: &mut SoundConfig) {
+        let file = match self.read() {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("Using sound devices from config: {e:#}");
+                return;
+            }
+        };
+        if let Some(selected) = file.playback {
+            config.playback_device = Some(selected.device);
+            config.playback_element = selected.element;
+        }
+        if let Some(selected) = file.capture {
+            config.capture_device = Some(selected.device);
+            config.capture_element = selected.element;
+        }
+    }
+}
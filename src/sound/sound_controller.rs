@@ -1,77 +1,248 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 
-#[cfg(target_os = "linux")]
-use super::linux::LinuxSoundController;
+use super::backend::{Channel, Direction, OnMicrophoneChangeCallback, SoundBackend};
+use crate::config::ConfigHandle;
 
-#[cfg(target_os = "windows")]
-use super::windows::WindowsSoundController;
-
-#[cfg(target_os = "macos")]
-use super::macos::MacOsSoundController;
-
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
 pub enum MicrophoneStatus {
     Muted,
     Unmuted,
 }
 
 pub struct SoundController {
-    #[cfg(target_os = "linux")]
-    sound_controller: LinuxSoundController,
-
-    #[cfg(target_os = "windows")]
-    sound_controller: WindowsSoundController,
-
-    #[cfg(target_os = "macos")]
-    sound_controller: MacOsSoundController,
+    backend: Box<dyn SoundBackend>,
 }
 
 impl SoundController {
-    pub fn new() -> Self {
-        #[cfg(target_os = "linux")]
-        {
-            let sound_controller = LinuxSoundController::new();
-            SoundController { sound_controller }
-        }
-        #[cfg(target_os = "macos")]
-        {
-            let sound_controller = MacOsSoundController::new();
-            SoundController { sound_controller }
+    pub fn new(config: ConfigHandle) -> anyhow::Result<Self> {
+        let kind = config.get().sound.backend;
+        info!("Using {:?} sound backend", kind);
+        Ok(Self::with_backend(kind.create(config)?))
+    }
+
+    pub fn with_backend(backend: Box<dyn SoundBackend>) -> Self {
+        SoundController { backend }
+    }
+
+    pub fn toggle_microphone_mute(&mut self) -> anyhow::Result<()> {
+        debug!("Toggling microphone mute");
+        self.backend.toggle_microphone_mute()
+    }
+
+    pub fn get_microphone_status(&mut self) -> anyhow::Result<MicrophoneStatus> {
+        self.backend.get_microphone_status()
+    }
+
+    /// Volume of the loudest channel, percent of the element's own range
+    pub fn volume_percent(&mut self, direction: Direction) -> anyhow::Result<f64> {
+        let (min, max) = self.volume_range(direction)?;
+        let loudest = Self::loudest(&self.backend.channel_volumes(direction)?, min);
+        Ok((loudest - min) as f64 / (max - min) as f64 * 100.0)
+    }
+
+    /// Sets the loudest channel to `percent` of the element's range, the other ones keep
+    /// their balance
+    pub fn set_volume_percent(&mut self, direction: Direction, percent: f64) -> anyhow::Result<()> {
+        debug!("Setting {direction:?} volume to {percent:.1}%");
+        let (min, max) = self.volume_range(direction)?;
+        let level = (max - min) as f64 * percent.clamp(0.0, 100.0) / 100.0;
+        self.set_loudest(direction, min + level.round() as i64)
+    }
+
+    /// Volume of the loudest channel in dB, `None` when the element has no dB scale
+    pub fn volume_db(&mut self, direction: Direction) -> anyhow::Result<Option<f64>> {
+        let (min, _) = self.volume_range(direction)?;
+        let loudest = Self::loudest(&self.backend.channel_volumes(direction)?, min);
+        self.backend.volume_db(direction, loudest)
+    }
+
+    /// Sets the loudest channel to the volume closest to `db`, the other ones keep their
+    /// balance
+    pub fn set_volume_db(&mut self, direction: Direction, db: f64) -> anyhow::Result<()> {
+        debug!("Setting {direction:?} volume to {db:.2} dB");
+        let raw = self
+            .backend
+            .db_volume(direction, db)?
+            .with_context(|| format!("{direction:?} volume has no dB scale"))?;
+        self.set_loudest(direction, raw)
+    }
+
+    /// -1 when only left channels play, 1 when only right ones do, 0 when both sides are
+    /// equally loud
+    pub fn balance(&mut self, direction: Direction) -> anyhow::Result<f64> {
+        let (min, _) = self.volume_range(direction)?;
+        Ok(Self::balance_of(
+            &self.backend.channel_volumes(direction)?,
+            min,
+        ))
+    }
+
+    /// Turns the quieter side down, the louder one keeps its volume. Elements without left
+    /// and right channels are not changed.
+    pub fn set_balance(&mut self, direction: Direction, balance: f64) -> anyhow::Result<()> {
+        debug!("Setting {direction:?} balance to {balance:.2}");
+        let (min, _) = self.volume_range(direction)?;
+        let channels = self.backend.channel_volumes(direction)?;
+        let loudest = Self::loudest(&channels, min);
+        self.set_channels(direction, min, &channels, loudest, balance.clamp(-1.0, 1.0))
+    }
+
+    fn volume_range(&mut self, direction: Direction) -> anyhow::Result<(i64, i64)> {
+        let (min, max) = self.backend.volume_range(direction)?;
+        if max <= min {
+            anyhow::bail!("{direction:?} volume range {min}..{max} is empty");
         }
-        #[cfg(target_os = "windows")]
-        {
-            let sound_controller = WindowsSoundController::new();
-            SoundController { sound_controller }
+        Ok((min, max))
+    }
+
+    fn set_loudest(&mut self, direction: Direction, raw: i64) -> anyhow::Result<()> {
+        let (min, max) = self.volume_range(direction)?;
+        let channels = self.backend.channel_volumes(direction)?;
+        let balance = Self::balance_of(&channels, min);
+        self.set_channels(direction, min, &channels, raw.clamp(min, max), balance)
+    }
+
+    // balance is ratio of the quieter side to the louder one, both counted from the range
+    // minimum; it's lost once every channel is at the minimum
+    fn set_channels(
+        &mut self,
+        direction: Direction,
+        min: i64,
+        channels: &[(Channel, i64)],
+        loudest: i64,
+        balance: f64,
+    ) -> anyhow::Result<()> {
+        let level = (loudest - min) as f64;
+        let volumes: Vec<i64> = channels
+            .iter()
+            .map(|(channel, _)| {
+                let side = match channel {
+                    Channel::Left => 1.0 - balance.max(0.0),
+                    Channel::Right => 1.0 + balance.min(0.0),
+                    Channel::Center => 1.0,
+                };
+                min + (level * side).round() as i64
+            })
+            .collect();
+        trace!("Setting {direction:?} channels to {volumes:?}");
+        self.backend.set_channel_volumes(direction, &volumes)
+    }
+
+    fn loudest(channels: &[(Channel, i64)], min: i64) -> i64 {
+        channels
+            .iter()
+            .map(|(_, volume)| *volume)
+            .max()
+            .unwrap_or(min)
+    }
+
+    fn balance_of(channels: &[(Channel, i64)], min: i64) -> f64 {
+        let side = |side: Channel| {
+            channels
+                .iter()
+                .filter(|(channel, _)| *channel == side)
+                .map(|(_, volume)| (volume - min) as f64)
+                .reduce(f64::max)
+        };
+        match (side(Channel::Left), side(Channel::Right)) {
+            (Some(left), Some(right)) if left > right => right / left - 1.0,
+            (Some(left), Some(right)) if right > left => 1.0 - left / right,
+            _ => 0.0,
         }
     }
 
-    pub fn toggle_microphone_mute(&mut self) {
-        println!("Toggling microphone mute");
-        self.sound_controller.toggle_microphone_mute();
+    /// `on_change` is called from backend's own thread on every mute transition
+    pub fn subscribe(&mut self, on_change: OnMicrophoneChangeCallback) -> anyhow::Result<()> {
+        self.backend.subscribe(on_change)
     }
 
-    pub fn get_microphone_status(&mut self) -> MicrophoneStatus {
-        self.sound_controller.get_microphone_status()
+    pub fn mute_mic(&mut self) -> anyhow::Result<()> {
+        debug!("Muting mic");
+        self.backend.mute_mic()
     }
 
-    pub fn set_volume(&mut self, volume: i64) {
-        println!("Setting volume to {}", volume);
-        self.sound_controller.set_volume(volume);
+    pub fn unmute_mic(&mut self) -> anyhow::Result<()> {
+        debug!("Unmuting mic");
+        self.backend.unmute_mic()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::mock::{MockElement, MockSoundBackend};
+
+    // like a laptop Master: 0..87, 0.75 dB steps
+    fn controller(channels: &[Channel]) -> (SoundController, MockSoundBackend) {
+        let sound = MockSoundBackend::new();
+        let element = MockElement::new((0, 87), channels, Some((-65.25, 0.0)));
+        sound.set_element(Direction::Playback, element);
+        (
+            SoundController::with_backend(Box::new(sound.clone())),
+            sound,
+        )
     }
 
-    pub fn get_current_volume(&mut self) -> i64 {
-        self.sound_controller.get_current_volume()
+    fn volumes(sound: &MockSoundBackend) -> Vec<i64> {
+        let element = sound.element(Direction::Playback);
+        element.channels.iter().map(|(_, volume)| *volume).collect()
     }
 
-    // todo
-    pub fn _mute_mic(&mut self) {
-        println!("Muting mic");
-        self.sound_controller.mute_mic();
+    #[test]
+    fn percent_is_relative_to_element_range() {
+        let (mut sc, sound) = controller(&[Channel::Left, Channel::Right]);
+
+        sc.set_volume_percent(Direction::Playback, 50.0).unwrap();
+        assert_eq!(volumes(&sound), vec![44, 44]);
+        let percent = sc.volume_percent(Direction::Playback).unwrap();
+        assert!((percent - 44.0 / 87.0 * 100.0).abs() < 1e-9);
+
+        sc.set_volume_percent(Direction::Playback, 150.0).unwrap();
+        assert_eq!(volumes(&sound), vec![87, 87]);
     }
 
-    // todo
-    pub fn _unmute_mic(&mut self) {
-        println!("Unmuting mic");
-        self.sound_controller.unmute_mic();
+    #[test]
+    fn db_uses_element_scale() {
+        let (mut sc, sound) = controller(&[Channel::Left, Channel::Right]);
+
+        sc.set_volume_db(Direction::Playback, -15.0).unwrap();
+        assert_eq!(volumes(&sound), vec![67, 67]);
+        assert_eq!(sc.volume_db(Direction::Playback).unwrap(), Some(-15.0));
+    }
+
+    #[test]
+    fn element_without_db_scale() {
+        let mut sc = SoundController::with_backend(Box::new(MockSoundBackend::new()));
+
+        assert_eq!(sc.volume_db(Direction::Playback).unwrap(), None);
+        assert!(sc.set_volume_db(Direction::Playback, -15.0).is_err());
+    }
+
+    #[test]
+    fn volume_change_keeps_balance() {
+        let (mut sc, sound) = controller(&[Channel::Left, Channel::Right, Channel::Center]);
+        sc.set_volume_percent(Direction::Playback, 100.0).unwrap();
+
+        sc.set_balance(Direction::Playback, 0.5).unwrap();
+        assert_eq!(volumes(&sound), vec![44, 87, 87]);
+        assert_eq!(sc.balance(Direction::Playback).unwrap(), 1.0 - 44.0 / 87.0);
+
+        sc.set_volume_percent(Direction::Playback, 50.0).unwrap();
+        assert_eq!(volumes(&sound), vec![22, 44, 44]);
+
+        sc.set_balance(Direction::Playback, -1.0).unwrap();
+        assert_eq!(volumes(&sound), vec![44, 0, 44]);
+        assert_eq!(sc.balance(Direction::Playback).unwrap(), -1.0);
+    }
+
+    #[test]
+    fn mono_element_has_no_balance() {
+        let (mut sc, sound) = controller(&[Channel::Center]);
+        sc.set_volume_percent(Direction::Playback, 100.0).unwrap();
+
+        sc.set_balance(Direction::Playback, 1.0).unwrap();
+        assert_eq!(volumes(&sound), vec![87]);
+        assert_eq!(sc.balance(Direction::Playback).unwrap(), 0.0);
     }
 }
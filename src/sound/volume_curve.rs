@@ -0,0 +1,339 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+use crate::config::EncoderConfig;
+
+// turns further apart than this are separate slow turns, not one fast sweep
+const TURN_GAP: Duration = Duration::from_millis(500);
+// notifications coming in one batch are not infinitely fast
+const MIN_TURN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// How knob position (0 to 1, one rotation sweeps all of it) maps onto raw mixer volume
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum VolumeCurve {
+    /// raw volume follows the knob, for mixers applying perceptual scale of their own
+    Linear,
+    /// raw volume is cube of the position, close to perceived loudness, fine steps when quiet
+    #[default]
+    Cubic,
+    /// position is attenuation from `-db_range` dB up to 0 dB, 0 position is silence
+    Db,
+}
+
+impl VolumeCurve {
+    /// Raw volume of knob `position`, from 0 to `volume_range`
+    pub fn raw(self, position: f64, volume_range: i64, db_range: f64) -> i64 {
+        let position = position.clamp(0.0, 1.0);
+        let level = match self {
+            VolumeCurve::Linear => position,
+            VolumeCurve::Cubic => position.powi(3),
+            VolumeCurve::Db if position == 0.0 => 0.0,
+            VolumeCurve::Db => 10f64.powf((position - 1.0) * db_range / 20.0),
+        };
+        ((level * volume_range as f64).round() as i64).clamp(0, volume_range)
+    }
+
+    /// Knob position of `raw` volume, inverse of `raw`
+    pub fn position(self, raw: i64, volume_range: i64, db_range: f64) -> f64 {
+        let level = (raw as f64 / volume_range as f64).clamp(0.0, 1.0);
+        match self {
+            VolumeCurve::Linear => level,
+            VolumeCurve::Cubic => level.cbrt(),
+            VolumeCurve::Db if level == 0.0 => 0.0,
+            VolumeCurve::Db => (1.0 + 20.0 * level.log10() / db_range).clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Volume knob of one button: remembers its speed and position between turns
+#[derive(Debug, Clone, Default)]
+pub struct VolumeKnob {
+    last_turn: Option<Instant>,
+    // position and the raw volume it was mapped to; turns too small to change raw volume
+    // still add up instead of being rounded away
+    position: Option<(f64, i64)>,
+    // fraction of a snapping step turned so far
+    pending_steps: f64,
+}
+
+impl VolumeKnob {
+    /// Volume after turning by `impulses` from `current` volume, `at` is when the turn was
+    /// reported. The result never goes against the turn direction and stays in
+    /// 0..=`volume_range`.
+    pub fn turn(
+        &mut self,
+        encoder: &EncoderConfig,
+        impulses: i32,
+        at: Instant,
+        current: i64,
+    ) -> i64 {
+        let acceleration = self.acceleration(encoder, impulses, at);
+        let position = match self.position {
+            Some((position, raw)) if raw == current => position,
+            // first turn, or volume was changed by other app
+            _ => {
+                self.pending_steps = 0.0;
+                encoder
+                    .curve
+                    .position(current, encoder.volume_range, encoder.db_range)
+            }
+        };
+        let delta = impulses as f64 * acceleration / encoder.impulses_per_rotation as f64;
+        let position = match encoder.steps_per_rotation() {
+            None => position + delta,
+            Some(steps) => {
+                // turning back starts a new step
+                if self.pending_steps * delta < 0.0 {
+                    self.pending_steps = 0.0;
+                }
+                let turned = delta * steps + self.pending_steps;
+                let whole = turned.trunc();
+                self.pending_steps = turned - whole;
+                ((position * steps).round() + whole) / steps
+            }
+        }
+        .clamp(0.0, 1.0);
+
+        let raw = encoder
+            .curve
+            .raw(position, encoder.volume_range, encoder.db_range);
+        // snapping to the nearest step must not go back
+        let raw = match impulses.signum() {
+            1 => raw.max(current),
+            -1 => raw.min(current),
+            _ => current,
+        }
+        .clamp(0, encoder.volume_range);
+        self.position = Some((position, raw));
+        raw
+    }
+
+    /// Records volume the mixer ended up at after `turn`, which may differ from the one asked
+    /// for when its steps are coarse. The knob keeps its position, so the next turn continues
+    /// from it instead of from the rounded volume.
+    pub fn settled(&mut self, raw: i64) {
+        if let Some((_, settled)) = &mut self.position {
+            *settled = raw;
+        }
+    }
+
+    /// Multiplier of the turn, from 1 for slow turns up to `encoder.acceleration`
+    fn acceleration(&mut self, encoder: &EncoderConfig, impulses: i32, at: Instant) -> f64 {
+        let Some(elapsed) = self
+            .last_turn
+            .replace(at)
+            .map(|last_turn| at.saturating_duration_since(last_turn))
+            .filter(|elapsed| *elapsed < TURN_GAP)
+        else {
+            return 1.0;
+        };
+        let rotations = impulses.unsigned_abs() as f64 / encoder.impulses_per_rotation as f64;
+        let speed = rotations / elapsed.max(MIN_TURN_INTERVAL).as_secs_f64();
+        let ramp = (speed / encoder.acceleration_speed).min(1.0);
+        1.0 + (encoder.acceleration - 1.0) * ramp
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn linear() -> EncoderConfig {
+        EncoderConfig {
+            curve: VolumeCurve::Linear,
+            acceleration: 1.0,
+            ..Default::default()
+        }
+    }
+
+    // turns far enough apart not to accelerate
+    fn slow_turns(encoder: &EncoderConfig, from: i64, turns: &[i32]) -> i64 {
+        let mut knob = VolumeKnob::default();
+        let start = Instant::now();
+        turns
+            .iter()
+            .enumerate()
+            .fold(from, |volume, (i, impulses)| {
+                knob.turn(encoder, *impulses, start + TURN_GAP * i as u32, volume)
+            })
+    }
+
+    #[test]
+    fn cubic_curve_has_fine_steps_when_quiet() {
+        let cubic = EncoderConfig {
+            acceleration: 1.0,
+            ..Default::default()
+        };
+        let quiet = cubic.volume_range / 100;
+
+        let linear_step = slow_turns(&linear(), quiet, &[1]) - quiet;
+        let cubic_step = slow_turns(&cubic, quiet, &[1]) - quiet;
+        assert!(cubic_step > 0);
+        assert!(
+            cubic_step < linear_step / 2,
+            "{cubic_step} vs {linear_step}"
+        );
+    }
+
+    #[test]
+    fn db_curve_spans_db_range() {
+        let encoder = EncoderConfig {
+            curve: VolumeCurve::Db,
+            ..Default::default()
+        };
+        let raw = |position| encoder.curve.raw(position, 65536, encoder.db_range);
+        assert_eq!(raw(0.0), 0);
+        assert_eq!(raw(1.0), 65536);
+        // half of 60 dB down
+        assert_eq!(raw(0.5), (65536.0 * 10f64.powf(-1.5)).round() as i64);
+    }
+
+    #[test]
+    fn fast_turn_goes_further() {
+        let encoder = EncoderConfig {
+            curve: VolumeCurve::Linear,
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let sweep = |interval: Duration| {
+            let mut knob = VolumeKnob::default();
+            (0..5).fold(0, |volume, i| {
+                knob.turn(&encoder, 12, start + interval * i, volume)
+            })
+        };
+
+        let slow = sweep(TURN_GAP);
+        let fast = sweep(Duration::from_millis(10));
+        assert_eq!(slow, linear().volume_range / 4);
+        assert!(fast > slow * 2, "{fast} vs {slow}");
+    }
+
+    #[test]
+    fn small_turns_add_up() {
+        let encoder = EncoderConfig {
+            acceleration: 1.0,
+            ..Default::default()
+        };
+        // each impulse alone is less than one raw step of the cubic curve at 0
+        assert_eq!(slow_turns(&encoder, 0, &[1; 240]), encoder.volume_range);
+    }
+
+    #[test]
+    fn detents_snap_to_steps() {
+        let encoder = EncoderConfig {
+            detent_impulses: 4,
+            steps_per_detent: 2,
+            ..linear()
+        };
+        // 60 detents, 120 steps per rotation
+        let step = |n: f64| (n / 120.0 * 65536.0).round() as i64;
+
+        assert_eq!(slow_turns(&encoder, 0, &[4]), step(2.0));
+        assert_eq!(slow_turns(&encoder, 0, &[1, 1]), step(1.0));
+        // off-grid volume set by other app snaps to the nearest step
+        assert_eq!(slow_turns(&encoder, step(10.3), &[2]), step(11.0));
+        assert_eq!(slow_turns(&encoder, step(10.3), &[-2]), step(9.0));
+    }
+
+    fn encoders() -> impl Strategy<Value = EncoderConfig> {
+        (
+            prop_oneof![
+                Just(VolumeCurve::Linear),
+                Just(VolumeCurve::Cubic),
+                Just(VolumeCurve::Db)
+            ],
+            1u32..1000,
+            1i64..100_000,
+            1.0..10.0f64,
+            prop_oneof![Just(0u32), 1u32..10],
+            1u32..5,
+        )
+            .prop_map(
+                |(curve, impulses_per_rotation, volume_range, acceleration, detent, steps)| {
+                    EncoderConfig {
+                        impulses_per_rotation,
+                        volume_range,
+                        curve,
+                        acceleration,
+                        detent_impulses: detent.min(impulses_per_rotation),
+                        steps_per_detent: steps,
+                        ..Default::default()
+                    }
+                },
+            )
+    }
+
+    // knob after some turns, with milliseconds between them
+    fn turned_knob(
+        encoder: &EncoderConfig,
+        turns: &[(i32, u64)],
+        volume: i64,
+    ) -> (VolumeKnob, Instant, i64) {
+        let mut knob = VolumeKnob::default();
+        let mut at = Instant::now();
+        let volume = turns.iter().fold(volume, |volume, (impulses, after)| {
+            at += Duration::from_millis(*after);
+            knob.turn(encoder, *impulses, at, volume)
+        });
+        (knob, at, volume)
+    }
+
+    proptest! {
+        #[test]
+        fn curve_is_monotonic(
+            encoder in encoders(),
+            a in 0.0..=1.0f64,
+            b in 0.0..=1.0f64,
+            raw in any::<i64>(),
+        ) {
+            let (low, high) = (a.min(b), a.max(b));
+            let raw_of = |p| encoder.curve.raw(p, encoder.volume_range, encoder.db_range);
+            prop_assert!(raw_of(low) <= raw_of(high));
+            prop_assert!((0..=encoder.volume_range).contains(&raw_of(low)));
+            let position = encoder.curve.position(raw, encoder.volume_range, encoder.db_range);
+            prop_assert!((0.0..=1.0).contains(&position));
+        }
+
+        #[test]
+        fn turn_stays_within_range(
+            encoder in encoders(),
+            turns in prop::collection::vec((-2000i32..2000, 0u64..1000), 0..20),
+            volume in any::<i64>(),
+        ) {
+            let mut knob = VolumeKnob::default();
+            let mut at = Instant::now();
+            for (impulses, after) in turns {
+                at += Duration::from_millis(after);
+                let volume = knob.turn(&encoder, impulses, at, volume);
+                prop_assert!((0..=encoder.volume_range).contains(&volume));
+            }
+        }
+
+        #[test]
+        fn turn_is_monotonic(
+            encoder in encoders(),
+            turns in prop::collection::vec((-100i32..100, 0u64..1000), 0..10),
+            volume in 0i64..100_000,
+            after in 0u64..1000,
+            a in -2000i32..2000,
+            b in -2000i32..2000,
+        ) {
+            let volume = volume.min(encoder.volume_range);
+            let (knob, at, volume) = turned_knob(&encoder, &turns, volume);
+            let at = at + Duration::from_millis(after);
+            let turn = |impulses| knob.clone().turn(&encoder, impulses, at, volume);
+
+            let (low, high) = (a.min(b), a.max(b));
+            prop_assert!(turn(low) <= turn(high), "{low} -> {}, {high} -> {}", turn(low), turn(high));
+            // never against the turn direction
+            prop_assert!(turn(high.max(0)) >= volume);
+            prop_assert!(turn(low.min(0)) <= volume);
+            prop_assert_eq!(turn(0), volume);
+        }
+    }
+}
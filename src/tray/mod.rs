@@ -1,25 +1,24 @@
+#[cfg(target_os = "linux")]
+mod sound_menu;
 pub mod tray_menu;
 use self::tray_menu::APP;
 
-use tauri::Icon;
+use btleplug::api::BDAddr;
+use tauri::{api::notification::Notification, Icon};
+
+use crate::ble::connection::ConnectionState;
 
 pub enum TrayIcon {
     Muted,
     Unmuted,
 }
 
-pub fn change_title_test1() {
-    let item = APP.get().unwrap().tray_handle().get_item("hide");
-    item.set_title("XDDDDDDDDD").unwrap();
-}
-
-pub fn change_title_test2() {
-    let item = APP.get().unwrap().tray_handle().get_item("hide");
-    item.set_title("XD").unwrap();
-}
-
 pub fn change_icon(icon: TrayIcon) {
-    let item = APP.get().unwrap().tray_handle();
+    // tray may be not initialized yet, when sound backend reports the first change
+    let Some(app) = APP.get() else {
+        return;
+    };
+    let item = app.tray_handle();
     match icon {
         TrayIcon::Muted => {
             println!("set muted icon");
@@ -35,3 +34,63 @@ pub fn change_icon(icon: TrayIcon) {
         }
     }
 }
+
+/// Shows warning about button sending undecodable messages, `None` clears it
+pub fn set_device_warning(warning: Option<&str>) {
+    let Some(app) = APP.get() else {
+        return;
+    };
+    let item = app.tray_handle().get_item("device_warning");
+    if let Err(e) = item.set_title(warning.unwrap_or("Device protocol: OK")) {
+        warn!("Failed to update tray device warning: {e:?}");
+    }
+}
+
+/// Shows battery levels of connected buttons in the menu and tooltip
+pub fn set_battery_summary(summary: &str) {
+    let Some(app) = APP.get() else {
+        return;
+    };
+    let tray = app.tray_handle();
+    if let Err(e) = tray.get_item("battery").set_title(summary) {
+        warn!("Failed to update tray battery level: {e:?}");
+    }
+    // not supported on every platform, menu item is enough there
+    if let Err(e) = tray.set_tooltip(summary) {
+        trace!("Failed to update tray tooltip: {e:?}");
+    }
+}
+
+pub fn notify_low_battery(address: BDAddr, level: u8) {
+    let Some(app) = APP.get() else {
+        return;
+    };
+    let result = Notification::new(&app.config().tauri.bundle.identifier)
+        .title("H-Button battery low")
+        .body(format!(
+            "Battery of {address} is at {level}%, charge it soon"
+        ))
+        .show();
+    if let Err(e) = result {
+        warn!("Failed to show low battery notification: {e:?}");
+    }
+}
+
+/// Closes the tray, which ends the driver
+pub fn tray_quit() {
+    match APP.get() {
+        Some(app) => app.exit(0),
+        // shutdown was requested before tray was set up
+        None => std::process::exit(0),
+    }
+}
+
+pub fn set_connection_state(state: &ConnectionState) {
+    let Some(app) = APP.get() else {
+        return;
+    };
+    let item = app.tray_handle().get_item("connection");
+    if let Err(e) = item.set_title(state.to_string()) {
+        warn!("Failed to update tray connection state: {e:?}");
+    }
+}
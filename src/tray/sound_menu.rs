@@ -0,0 +1,110 @@
+use tauri::{AppHandle, CustomMenuItem, SystemTrayMenu, SystemTraySubmenu};
+
+use crate::sound::{
+    backend::Direction,
+    devices,
+    selection::{SelectedElement, SelectionStore},
+};
+
+struct SoundItem {
+    id: String,
+    direction: Direction,
+    // `None` goes back to the element from config
+    element: Option<SelectedElement>,
+}
+
+/// "Output device" and "Input device" submenus, listing elements of every sound card. Cards
+/// plugged in later show up after restart, or can be picked with `h-button-driver devices`.
+#[derive(Default)]
+pub struct SoundMenu {
+    items: Vec<SoundItem>,
+}
+
+impl SoundMenu {
+    pub fn add_to(&mut self, mut menu: SystemTrayMenu) -> SystemTrayMenu {
+        let devices = match devices::list_devices() {
+            Ok(devices) => devices,
+            Err(e) => {
+                warn!("Sound devices not shown in tray: {e:#}");
+                return menu;
+            }
+        };
+        let store = SelectionStore::open();
+        for (direction, title) in [
+            (Direction::Playback, "Output device"),
+            (Direction::Capture, "Input device"),
+        ] {
+            let selected = store.selected(direction).unwrap_or_else(|e| {
+                warn!("{e:#}");
+                None
+            });
+            let mut submenu = SystemTrayMenu::new().add_item(self.item(
+                direction,
+                None,
+                "As in config",
+                &selected,
+            ));
+            for device in &devices {
+                for element in device.elements.iter().filter(|e| e.supports(direction)) {
+                    let title = format!("{} - {}", element.name, device.description);
+                    let element = SelectedElement {
+                        device: device.device.clone(),
+                        element: element.name.clone(),
+                    };
+                    submenu =
+                        submenu.add_item(self.item(direction, Some(element), &title, &selected));
+                }
+            }
+            menu = menu.add_submenu(SystemTraySubmenu::new(title, submenu));
+        }
+        menu
+    }
+
+    fn item(
+        &mut self,
+        direction: Direction,
+        element: Option<SelectedElement>,
+        title: &str,
+        selected: &Option<SelectedElement>,
+    ) -> CustomMenuItem {
+        // menu item ids must be unique, element names are not
+        let id = format!("sound_{}", self.items.len());
+        let mut item = CustomMenuItem::new(id.clone(), title);
+        if element == *selected {
+            item = item.selected();
+        }
+        self.items.push(SoundItem {
+            id,
+            direction,
+            element,
+        });
+        item
+    }
+
+    /// Picks the element of clicked item, false when the item isn't one of this menu
+    pub fn click(&self, app: &AppHandle, id: &str) -> bool {
+        let Some(clicked) = self.items.iter().find(|item| item.id == id) else {
+            return false;
+        };
+        let store = SelectionStore::open();
+        if let Err(e) = store.select(clicked.direction, clicked.element.clone()) {
+            warn!("Failed to pick {:?} element: {e:#}", clicked.direction);
+            return true;
+        }
+        info!(
+            "{:?} element picked in tray: {:?}",
+            clicked.direction, clicked.element
+        );
+        let tray = app.tray_handle();
+        for item in self
+            .items
+            .iter()
+            .filter(|item| item.direction == clicked.direction)
+        {
+            if let Err(e) = tray.get_item(&item.id).set_selected(item.id == clicked.id) {
+                warn!("Failed to update tray sound device: {e:?}");
+            }
+        }
+        true
+    }
+}
@@ -2,6 +2,21 @@ use tauri::SystemTrayMenuItem;
 use tauri::{AppHandle, CustomMenuItem, SystemTray, SystemTrayEvent, SystemTrayMenu};
 
 use once_cell::sync::OnceCell;
+use tokio::sync::mpsc::UnboundedSender;
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use btleplug::api::BDAddr;
+use tauri::api::dialog::{self, FileDialogBuilder};
+use tokio::sync::oneshot;
+
+#[cfg(target_os = "linux")]
+use super::sound_menu::SoundMenu;
+use crate::ble::{device_info::DeviceInfo, BleCommand};
+use crate::config::ConfigHandle;
+#[cfg(target_os = "linux")]
+use crate::sound::backend::SoundBackendKind;
 
 pub(crate) static APP: OnceCell<AppHandle> = OnceCell::new();
 
@@ -18,13 +33,84 @@ pub(crate) static APP: OnceCell<AppHandle> = OnceCell::new();
 //     .expect("Failed to open icon")
 // }
 
-pub fn tray_init() {
+// text of "About device" dialog
+fn about_devices(device_infos: &BTreeMap<BDAddr, DeviceInfo>) -> String {
+    if device_infos.is_empty() {
+        return "No button connected".to_string();
+    }
+    let about: Vec<String> = device_infos
+        .iter()
+        .map(|(address, device_info)| format!("Button {address}\n{device_info}"))
+        .collect();
+    about.join("\n\n")
+}
+
+// asks for firmware image and reports result of its update in a dialog
+fn pick_and_flash_firmware(ble_commands: UnboundedSender<BleCommand>) {
+    FileDialogBuilder::new()
+        .set_title("Select firmware image")
+        .add_filter("Firmware image", &["bin"])
+        .pick_file(move |image| {
+            let Some(image) = image else {
+                return;
+            };
+            let (result, result_rx) = oneshot::channel();
+            if ble_commands
+                .send(BleCommand::Flash { image, result })
+                .is_err()
+            {
+                warn!("Bluetooth thread is not running, firmware not updated");
+                return;
+            }
+            // update takes a while, dialog callback must not be blocked meanwhile
+            std::thread::spawn(move || {
+                let message = match result_rx.blocking_recv() {
+                    Ok(Ok(Some(version))) => format!("Button runs firmware {version}"),
+                    Ok(Ok(None)) => "Firmware updated".to_string(),
+                    Ok(Err(e)) => format!("Firmware update failed: {e:#}"),
+                    Err(_) => "Bluetooth thread stopped during firmware update".to_string(),
+                };
+                dialog::message(None::<&tauri::Window>, "Firmware update", message);
+            });
+        });
+}
+
+pub fn tray_init(
+    config: ConfigHandle,
+    ble_commands: UnboundedSender<BleCommand>,
+    device_infos: Arc<Mutex<BTreeMap<BDAddr, DeviceInfo>>>,
+) {
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let hide = CustomMenuItem::new("hide".to_string(), "Hide");
+    let forget = CustomMenuItem::new("forget".to_string(), "Forget button and re-pair");
+    let about_device = CustomMenuItem::new("about_device".to_string(), "About device");
+    let update_firmware = CustomMenuItem::new("update_firmware".to_string(), "Update firmware...");
+    let connection = CustomMenuItem::new("connection".to_string(), "Scanning").disabled();
+    let device_warning =
+        CustomMenuItem::new("device_warning".to_string(), "Device protocol: OK").disabled();
+    let battery = CustomMenuItem::new("battery".to_string(), "Battery: unknown").disabled();
     let tray_menu = SystemTrayMenu::new()
+        .add_item(connection)
+        .add_item(battery)
+        .add_item(device_warning)
+        .add_native_item(SystemTrayMenuItem::Separator)
         .add_item(quit)
         .add_native_item(SystemTrayMenuItem::Separator)
-        .add_item(hide);
+        .add_item(hide)
+        .add_item(about_device)
+        .add_item(update_firmware)
+        .add_item(forget);
+    // only ALSA backend has elements to pick, pulse follows the default sink and source
+    #[cfg(target_os = "linux")]
+    let mut sound_menu = SoundMenu::default();
+    #[cfg(target_os = "linux")]
+    let tray_menu = if config.get().sound.backend == SoundBackendKind::Alsa {
+        sound_menu.add_to(tray_menu.add_native_item(SystemTrayMenuItem::Separator))
+    } else {
+        tray_menu
+    };
+    #[cfg(not(target_os = "linux"))]
+    let _ = config;
     tauri::Builder::default()
         .setup(|app| {
             let app = app.handle().clone();
@@ -32,7 +118,7 @@ pub fn tray_init() {
             Ok(())
         })
         .system_tray(SystemTray::new().with_menu(tray_menu))
-        .on_system_tray_event(|app, event| match event {
+        .on_system_tray_event(move |app, event| match event {
             SystemTrayEvent::MenuItemClick { id, .. } => {
                 let item_handle = app.tray_handle().get_item(&id);
                 match id.as_str() {
@@ -43,8 +129,23 @@ pub fn tray_init() {
                         item_handle.set_title("Show").unwrap();
                     }
                     "quit" => {
-                        app.exit(0);
+                        // tray is closed once bluetooth turned led off and disconnected
+                        if ble_commands.send(BleCommand::Shutdown).is_err() {
+                            app.exit(0);
+                        }
+                    }
+                    "about_device" => {
+                        let about = about_devices(&device_infos.lock().unwrap());
+                        dialog::message(None::<&tauri::Window>, "About device", about);
+                    }
+                    "update_firmware" => pick_and_flash_firmware(ble_commands.clone()),
+                    "forget" => {
+                        if ble_commands.send(BleCommand::Forget).is_err() {
+                            warn!("Bluetooth thread is not running, button not forgotten");
+                        }
                     }
+                    #[cfg(target_os = "linux")]
+                    id if sound_menu.click(app, id) => {}
                     _ => {}
                 }
             }